@@ -0,0 +1,73 @@
+//! Send a command to the admin socket of a running `line_chat` server.
+//!
+//! Start the server with an admin socket:
+//!
+//!     cargo run --bin line_chat -- --admin-socket line_chat.sock
+//!
+//! Then, from the same directory:
+//!
+//!     cargo run --bin chatctl -- peers
+//!     cargo run --bin chatctl -- kick alice spamming
+//!     cargo run --bin chatctl -- notice restarting in 5 minutes
+//!
+//! The reply is printed on the standard output. `chatctl` exits with status 1
+//! if the command failed.
+
+#![deny(warnings)]
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::process;
+
+const USAGE: &str = "\
+usage: chatctl [--socket <file>] <command> [args]
+
+commands:
+    peers                  list the connected clients
+    kick <name> [reason]   disconnect a client
+    notice <text>          send a notice to every client
+    reload                 reread the settings that can change at runtime
+    stats                  show counters since the server started
+
+The socket defaults to line_chat.sock.";
+
+fn main() -> io::Result<()> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    let mut path = "line_chat.sock".to_string();
+    if args.first().map(String::as_str) == Some("--socket") {
+        if args.len() < 2 {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+        path = args.remove(1);
+        args.remove(0);
+    }
+
+    if args.is_empty() || args[0] == "-h" || args[0] == "--help" {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let mut socket = match UnixStream::connect(&path) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("error: failed to connect to {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    socket.write_all(format!("{}\n", args.join(" ")).as_bytes())?;
+    socket.shutdown(Shutdown::Write)?;
+
+    let mut reply = String::new();
+    socket.read_to_string(&mut reply)?;
+    print!("{}", reply);
+
+    if reply.starts_with("error: ") {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! Print lines for the credentials file of `line_chat`.
+//!
+//! To protect a name with a password, run:
+//!
+//!     cargo run --bin line_chat_passwd -- alice >> credentials
+//!
+//! and type the password. It is read from the first line of the standard
+//! input, so it can also be piped in.
+//!
+//! To create a token account for a bot, run:
+//!
+//!     cargo run --bin line_chat_passwd -- --token bot >> credentials
+//!
+//! The new token is printed on the standard error, only its hash is written
+//! to the credentials file. The bot logs in by sending `/token <token>` as
+//! its first line.
+
+#![deny(warnings)]
+
+use hello_async::chat::{hash_password, hash_token, new_token};
+
+use std::env;
+use std::io::{self, BufRead};
+use std::process;
+
+const USAGE: &str = "usage: line_chat_passwd [--token] <name>";
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (token, name) = match &args[..] {
+        [flag, name] if flag == "--token" => (true, name),
+        [name] if !name.starts_with('-') => (false, name),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let hash = if token {
+        let token = new_token();
+        eprintln!("token for {}: {}", name, token);
+        hash_token(&token)
+    } else {
+        eprintln!("password for {}:", name);
+        let mut password = String::new();
+        io::stdin().lock().read_line(&mut password)?;
+        let password = password.trim_end_matches(&['\r', '\n'][..]);
+        if password.is_empty() {
+            eprintln!("error: empty password");
+            process::exit(1);
+        }
+        hash_password(password)
+    };
+
+    println!("{} {}", name, hash);
+    Ok(())
+}
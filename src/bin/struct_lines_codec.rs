@@ -1 +1,195 @@
+//! A key-value server speaking a line protocol through `StructLinesCodec`.
+//!
+//! The lines used here are a tag followed by `key=value` fields:
+//!
+//!     SET color=blue size=10
+//!     GET color size
+//!     DEL color
+//!     PING
+//!
+//! Start the key-value server with
+//!
+//!     cargo run --bin struct_lines_codec
+//!
+//! and talk to it with
+//!
+//!     nc localhost 6143
+//!
+//! The server answers every request line with a `VALUE`, `OK`, `PONG` or
+//! `ERROR` line. A line that doesn't parse is answered with an `ERROR` line
+//! carrying its line number.
 
+use hello_async::codec::{Fields, FromLine, ParseError, ParseErrorKind, StructLinesCodec, ToLine};
+use hello_async::log::Context;
+use hello_async::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::codec::Framed;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+
+/// A request sent to the key-value server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    /// Store the given values.
+    Set(Fields),
+
+    /// Look up the given keys.
+    Get(Vec<String>),
+
+    /// Remove the given keys.
+    Del(Vec<String>),
+
+    Ping,
+}
+
+/// A response of the key-value server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Response {
+    /// The number of keys a `SET` or `DEL` changed.
+    Ok(usize),
+
+    /// The values found by a `GET`. Missing keys are left out.
+    Value(Fields),
+
+    Pong,
+
+    /// A request that could not be parsed.
+    Error(ParseError),
+}
+
+impl FromLine for Request {
+    fn from_line(line: &str) -> Result<Request, ParseErrorKind> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Err(ParseErrorKind::Empty);
+        }
+
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let tag = parts.next().unwrap_or("");
+        let rest = parts.next().unwrap_or("");
+        let keys = || -> Result<Vec<String>, ParseErrorKind> {
+            let keys: Vec<String> = rest.split_whitespace().map(String::from).collect();
+            if keys.is_empty() {
+                return Err(ParseErrorKind::Missing("key"));
+            }
+            Ok(keys)
+        };
+
+        match &tag.to_ascii_uppercase()[..] {
+            "SET" => {
+                let fields = Fields::parse(rest)?;
+                if fields.0.is_empty() {
+                    return Err(ParseErrorKind::Missing("field"));
+                }
+                Ok(Request::Set(fields))
+            }
+            "GET" => Ok(Request::Get(keys()?)),
+            "DEL" => Ok(Request::Del(keys()?)),
+            "PING" => Ok(Request::Ping),
+            _ => Err(ParseErrorKind::UnknownTag(tag.to_string())),
+        }
+    }
+}
+
+impl ToLine for Response {
+    fn to_line(&self, line: &mut String) {
+        match self {
+            Response::Ok(n) => line.push_str(&format!("OK {}", n)),
+            Response::Value(fields) => {
+                line.push_str("VALUE");
+                fields.write(line);
+            }
+            Response::Pong => line.push_str("PONG"),
+            Response::Error(err) => {
+                let mut fields = Fields::default();
+                fields.0.insert("line".to_string(), err.line.to_string());
+                fields.0.insert("reason".to_string(), err.kind.to_string());
+
+                line.push_str("ERROR");
+                fields.write(line);
+            }
+        }
+    }
+}
+
+/// Answer a single request.
+fn handle(
+    store: &Mutex<HashMap<String, String>>,
+    request: Result<Request, ParseError>,
+) -> Response {
+    let mut store = store.lock().unwrap();
+
+    match request {
+        Ok(Request::Set(fields)) => {
+            let n = fields.0.len();
+            store.extend(fields.0);
+            Response::Ok(n)
+        }
+        Ok(Request::Get(keys)) => {
+            let found = keys
+                .into_iter()
+                .filter_map(|key| store.get(&key).cloned().map(|value| (key, value)))
+                .collect();
+            Response::Value(Fields(found))
+        }
+        Ok(Request::Del(keys)) => {
+            let n = keys
+                .iter()
+                .filter(|key| store.remove(*key).is_some())
+                .count();
+            Response::Ok(n)
+        }
+        Ok(Request::Ping) => Response::Pong,
+        Err(err) => Response::Error(err),
+    }
+}
+
+fn main() {
+    let addr = "127.0.0.1:6143".parse().unwrap();
+    let listener = TcpListener::bind(&addr).expect("unable to bind TCP listener");
+
+    // All connections share the same store.
+    let store = Arc::new(Mutex::new(HashMap::new()));
+
+    let server = listener
+        .incoming()
+        .for_each(move |socket| {
+            let mut log = Context::new();
+            if let Ok(addr) = socket.peer_addr() {
+                log.set("addr", addr);
+            }
+            info!(context: log, "connection accepted");
+
+            // `Framed` drives the codec: requests come out of the stream half
+            // and responses go into the sink half.
+            let codec = StructLinesCodec::<Request, Response>::new().max_length(1024);
+            let (sink, stream) = Framed::new(socket, codec).split();
+
+            let store = store.clone();
+            let parse_log = log.clone();
+            let responses = stream.map(move |request| {
+                if let Err(err) = &request {
+                    warn!(context: parse_log, "parse error", error = err);
+                }
+                handle(&store, request)
+            });
+
+            let connection = sink.send_all(responses).then(move |result| {
+                match result {
+                    Ok(_) => info!(context: log, "connection closed"),
+                    Err(e) => warn!(context: log, "connection error", error = e),
+                }
+                Ok(())
+            });
+
+            tokio::spawn(connection);
+            Ok(())
+        })
+        .map_err(|err| {
+            error!("accept error", error = err);
+        });
+
+    info!("server running", addr = addr);
+    tokio::run(server);
+}
@@ -1,4 +1,5 @@
 use futures::{Future, Stream};
+use hello_async::{error, info, warn};
 use tokio::io::AsyncRead;
 use tokio::net::TcpListener;
 
@@ -19,7 +20,7 @@ fn main() {
     // combinator to specify what should happen each time a new
     // connection becomes available.
     let server = incoming
-        .map_err(|e| eprintln!("accept failed = {}", e))
+        .map_err(|e| error!("accept failed", error = e))
         .for_each(|socket| {
             // Each time we get a connection, this closure gets called.
             // We want to construct a Future that will read all the bytes
@@ -42,11 +43,11 @@ fn main() {
             let (reader, writer) = socket.split();
             let bytes_copied = tokio::io::copy(reader, writer);
             let handle_conn = bytes_copied
-                .map(|amt| {
-                    println!("wrote {:?} bytes", amt);
+                .map(|(amt, _, _)| {
+                    info!("connection closed", written = amt);
                 })
                 .map_err(|e| {
-                    eprintln!("I/O error {:?}", e);
+                    warn!("I/O error", error = e);
                 });
 
             // handle_conn here is still a Future, so it hasn't actually
@@ -15,31 +15,129 @@
 //!
 //!     cargo run --bin hello_world
 //!
+//! The client can also connect over TLS, for example to `tokio_echo` started
+//! with `--tls`. Servers using a self-signed certificate are trusted by
+//! passing that certificate with `--ca`:
+//!
+//!     cargo run --bin tokio_client -- --tls --ca cert.pem 127.0.0.1:9876
+//!
+//! The server's certificate must be valid for `localhost`, or for the name
+//! given with `--domain`.
+//!
 
+use futures::future::Either;
+use hello_async::{error, info, tls, warn};
+use std::env;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
 use tokio::io;
 use tokio::net::TcpStream;
 use tokio::prelude::*;
 
+const USAGE: &str = "usage: tokio_client [--tls] [--ca <file>] [--domain <name>] [<addr>]";
+
+/// Command-line arguments.
+struct Args {
+    addr: SocketAddr,
+    tls: bool,
+    ca: Option<PathBuf>,
+    domain: String,
+}
+
+impl Args {
+    fn parse() -> Result<Args, String> {
+        let mut args = Args {
+            addr: "127.0.0.1:6142".parse().unwrap(),
+            tls: false,
+            ca: None,
+            domain: "localhost".to_string(),
+        };
+
+        let mut argv = env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            match &arg[..] {
+                "--tls" => args.tls = true,
+                "--ca" => {
+                    args.ca = Some(PathBuf::from(
+                        argv.next().ok_or("missing value for `--ca`")?,
+                    ))
+                }
+                "--domain" => args.domain = argv.next().ok_or("missing value for `--domain`")?,
+                addr => {
+                    args.addr = addr
+                        .parse()
+                        .map_err(|e| format!("invalid address `{}`: {}", addr, e))?
+                }
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+/// Write "hello world\n" to the stream.
+fn hello<S: AsyncWrite>(stream: S) -> impl Future<Item = (), Error = io::Error> {
+    io::write_all(stream, "hello world\n")
+        .and_then(|(stream, _)| io::shutdown(stream))
+        .then(|result| {
+            match result {
+                Ok(_) => info!("wrote to stream"),
+                Err(e) => warn!("write failed", error = e),
+            }
+            Ok(())
+        })
+}
+
 fn main() {
-    let addr = "127.0.0.1:6142".parse::<SocketAddr>().unwrap();
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let connector = if args.tls {
+        match tls::connector(args.ca.as_deref()) {
+            Ok(connector) => Some(connector),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let domain = args.domain;
+    let addr = args.addr;
     let client = TcpStream::connect(&addr)
-        .and_then(|stream| {
-            println!("created stream");
-            io::write_all(stream, "hello world\n").then(|result| {
-                println!("wrote to stream; success={:?}", result.is_ok());
-                Ok(())
-            })
+        .and_then(move |stream| {
+            info!("connected", addr = addr);
+            match connector {
+                // The TLS handshake checks the server's certificate before
+                // anything is written.
+                Some(connector) => Either::A(
+                    connector
+                        .connect(&domain, stream)
+                        .map_err(io::Error::other)
+                        .and_then(|stream| {
+                            info!("TLS handshake done");
+                            hello(stream)
+                        }),
+                ),
+                None => Either::B(hello(stream)),
+            }
         })
         .map_err(|err| {
             // All tasks must have an `Error` type of `()`. This forces error
             // handling and helps avoid silencing failures.
             //
-            // In our example, we are only going to log the error to STDOUT.
-            println!("connection error = {:?}", err);
+            // In our example, we are only going to log the error.
+            error!("connection error", error = err);
         });
 
-    println!("About to create the stream and write to it...");
+    info!("connecting", addr = addr);
     tokio::run(client);
-    println!("Stream has been created and written to.");
 }
@@ -1,4 +1,5 @@
 use futures::Future;
+use hello_async::{error, info};
 use tokio::io;
 use tokio::net::TcpStream;
 
@@ -7,8 +8,8 @@ fn main() {
 
     let future = TcpStream::connect(&addr)
         .and_then(|socket| io::write_all(socket, b"hello world"))
-        .map(|_| println!("wirte complete"))
-        .map_err(|e| println!("failed - {:?}", e));
+        .map(|_| info!("write complete"))
+        .map_err(|e| error!("write failed", error = e));
 
     tokio::run(future);
 }
@@ -1,28 +1,5 @@
-#[macro_use]
-extern crate futures;
-
 use futures::{Async, Future, Poll};
-use std::fmt;
-
-//
-// Explicit way
-//
-struct Display<T>(T);
-
-impl<T> Future for Display<T>
-where
-    T: Future,
-    T::Item: fmt::Display,
-{
-    type Item = ();
-    type Error = T::Error;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let val = try_ready!(self.0.poll());
-        println!("{}", val);
-        Ok(Async::Ready(()))
-    }
-}
+use hello_async::combinators::Display;
 
 struct HelloWorld;
 
@@ -36,6 +13,7 @@ impl Future for HelloWorld {
 }
 
 fn main() {
+    // Explicit way
     let future = Display(HelloWorld);
     tokio::run(future);
 
@@ -1,5 +1,7 @@
 //! Start the echo server
 //!
+//!     cargo run --bin tokio_echo
+//!
 //! Use netcat to send tcp packets to server
 //!
 //!     nc localhost 9876
@@ -7,48 +9,92 @@
 //!
 //! Use `Ctrl+C` to close the connection
 //!
+//! To accept TLS connections instead of plaintext ones, give the server a PEM
+//! certificate and private key:
+//!
+//!     cargo run --bin tokio_echo -- --tls cert.pem key.pem
+//!     openssl s_client -connect localhost:9876
+//!
 
+use hello_async::log::Context;
+use hello_async::{error, info, tls, warn};
+use std::env;
+use std::process;
 use tokio::io;
 use tokio::net::TcpListener;
 use tokio::prelude::*;
 
+/// Copy everything read from `socket` back to it.
+fn echo<S>(socket: S, log: Context) -> impl Future<Item = (), Error = ()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    // Split the socket into readable and writable parts
+    let (reader, writer) = socket.split();
+    // Copy bytes from the reader into the writer
+    let amount = io::copy(reader, writer);
+
+    amount.then(move |result| {
+        match result {
+            Ok((amount, _, _)) => info!(context: log, "connection closed", written = amount),
+            Err(e) => warn!(context: log, "connection error", error = e),
+        }
+
+        Ok(())
+    })
+}
+
 fn main() {
+    let tls = match tls::acceptor_from_args(env::args().skip(1)) {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!("usage: tokio_echo [--tls <cert> <key>]");
+            process::exit(2);
+        }
+    };
+
     // Bind the server's socket
     let addr = "127.0.0.1:9876".parse().unwrap();
     let listener = TcpListener::bind(&addr).expect("unable to bind TCP listener");
+    info!("server running", addr = addr, tls = tls.is_some());
 
     // Convert the `TcpListener` to a stream of incoming connections
     //  with the `incoming` method. We then define how to process each element in
     //  the stream with the `for_each` combinator
     let server = listener
         .incoming()
-        .for_each(|socket| {
-            // Split the socket into readable and writable parts
-            let (reader, writer) = socket.split();
-            // Copy bytes from the reader into the writer
-            let amount = io::copy(reader, writer);
-
-            let msg = amount.then(|result| {
-                match result {
-                    Ok(amount, _, _) => println!("wrote {} bytes", amount),
-                    Err(e) => println!("error: {}", e),
-                }
-
-                Ok(())
-            });
-
+        .for_each(move |socket| {
             // Spawn the task that handles the client connection socket on to the
             // tokio runtime. This means each client connection will be handled
             // concurrently
-            tokio::spawn(msg);
+            let mut log = Context::new();
+            if let Ok(addr) = socket.peer_addr() {
+                log.set("addr", addr);
+            }
+            info!(context: log, "connection accepted");
+
+            match tls {
+                // The TLS handshake has to complete before anything is echoed.
+                Some(ref tls) => {
+                    let tls_log = log.clone();
+                    let connection = tls
+                        .accept(socket)
+                        .map_err(move |e| warn!(context: tls_log, "TLS error", error = e))
+                        .and_then(|socket| echo(socket, log));
+                    tokio::spawn(connection);
+                }
+                None => {
+                    tokio::spawn(echo(socket, log));
+                }
+            }
             Ok(())
         })
         .map_err(|err| {
-            // Handle error by printing it to STDOUT
+            // Handle error by logging it
+            error!("accept error", error = err);
         });
 
-    println!("server running on localhost:9876");
-
     // Start the server
     //
     // This does a few things
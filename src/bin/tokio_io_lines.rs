@@ -2,6 +2,7 @@
 //!
 //!     cat file | nc -l 12345
 
+use hello_async::{error, info};
 use tokio::net::tcp::TcpStream;
 use tokio::prelude::*;
 
@@ -17,7 +18,7 @@ fn main() {
         // Luckily, BufReader from the standard library gives us that!
         let stream = std::io::BufReader::new(stream);
         tokio::io::lines(stream).for_each(|line| {
-            println!("server sent us the line: {}", line);
+            info!("server sent us a line", line = line);
             // This closure is called for each line we receive,
             // and returns a Future that represents the work we
             // want to do before accepting the next line.
@@ -27,5 +28,9 @@ fn main() {
         })
     });
 
-    tokio::run(lines_fut.map(|_| ()).map_err(|e| eprintln!("{:?}", e)))
+    tokio::run(
+        lines_fut
+            .map(|_| ())
+            .map_err(|e| error!("connection error", error = e)),
+    )
 }
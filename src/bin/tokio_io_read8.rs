@@ -2,6 +2,7 @@
 //!
 //!     cat file | nc -l 12345
 
+use hello_async::{error, info};
 use tokio::net::tcp::TcpStream;
 use tokio::prelude::*;
 
@@ -27,8 +28,12 @@ fn main() {
             // Notice that we get both the buffer and the stream back
             // here, so that we can now continue using the stream to
             // send a reply for example.
-            println!("got eight bytes: {:x?}", buf);
+            info!("got eight bytes", bytes = format!("{:x?}", buf));
         });
 
-    tokio::run(read_8_fut.map(|_| ()).map_err(|e| eprintln!("{:?}", e)))
+    tokio::run(
+        read_8_fut
+            .map(|_| ())
+            .map_err(|e| error!("connection error", error = e)),
+    )
 }
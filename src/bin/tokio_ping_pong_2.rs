@@ -43,69 +43,25 @@
 //!
 
 use futures::future::lazy;
-use futures::{future, Future, Sink, Stream};
-use rand::Rng;
-use std::io;
+use futures::{Future, Sink, Stream};
+use hello_async::io::Transport;
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::{Receiver, Sender};
-use tokio::timer::Delay;
-
-const TRANSPORT_MAX_DELAY: Duration = Duration::from_secs(0);
-const TRANSPORT_MIN_DELAY: Duration = Duration::from_secs(10);
 
 type Message = (usize, oneshot::Sender<Duration>);
 
-struct Transport {
-    min_delay: Duration,
-    max_delay: Duration,
-}
-
-impl Transport {
-    fn new(min_delay: Duration, max_delay: Duration) -> Transport {
-        Transport {
-            min_delay,
-            max_delay,
-        }
-    }
-
-    fn default() -> Transport {
-        Transport::new(TRANSPORT_MAX_DELAY, TRANSPORT_MIN_DELAY)
-    }
-
-    fn send_ping(&self, thread_id: &usize) {
-        println!("[{}] entering ping", thread_id);
-    }
-
-    fn recv_pong(&self, thread_id: &usize) -> impl Future<Item = (), Error = io::Error> {
-        print!("[{}] entering recv_pong", thread_id);
-
-        let mut rng = rand::thread_rng();
-        let wait_millis: u64 =
-            rng.gen_range(self.min_delay.as_millis(), self.max_delay.as_millis()) as u64;
-        let delay = Duration::from_millis(wait_millis);
-
-        println!(" delay {:?}", delay);
-
-        let when = Instant::now() + delay;
-
-        Delay::new(when)
-            .and_then(|_| Ok(()))
-            .map_err(|e| panic!("delay errored; err={:?}", e))
-    }
-}
-
 fn coordinator_task(rx: mpsc::Receiver<Message>) -> impl Future<Item = (), Error = ()> {
     let transport = Transport::default();
 
     rx.map_err(|_| ()).for_each(move |(thread_id, r_tx)| {
         let start = Instant::now();
 
-        transport.send_ping(&thread_id);
+        transport.send_ping(thread_id);
 
         let fut = transport
-            .recv_pong(&thread_id)
+            .recv_pong(thread_id)
             .map_err(|_| ())
             .and_then(move |_| {
                 let rtt = start.elapsed();
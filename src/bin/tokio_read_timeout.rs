@@ -1,4 +1,5 @@
 use futures::{Future, Stream};
+use hello_async::{debug, error, info, warn};
 use std::time::Duration;
 use tokio::io;
 use tokio::net::{TcpListener, TcpStream};
@@ -7,12 +8,12 @@ use tokio::prelude::FutureExt;
 fn read_four_bytes(
     socket: TcpStream,
 ) -> Box<dyn Future<Item = (TcpStream, Vec<u8>), Error = ()> + Send> {
-    println!("enter read four bytes");
+    debug!("reading four bytes");
 
     let buf = vec![0; 4];
     let fut = io::read_exact(socket, buf)
         .timeout(Duration::from_secs(1))
-        .map_err(|_| println!("failed to read 4 bytes by timeout"));
+        .map_err(|e| warn!("failed to read 4 bytes", error = e));
     ;
 
     Box::new(fut)
@@ -24,13 +25,13 @@ fn main() {
 
     let server = listener
         .incoming()
-        .map_err(|e| eprintln!("error accept = {:?}", e))
+        .map_err(|e| error!("accept error", error = e))
         .for_each(|socket| {
             let read_fut = read_four_bytes(socket).and_then(|(_, v)| {
-                println!("v = {:?}", v);
+                info!("read four bytes", bytes = format!("{:?}", v));
                 Ok(())
             }).or_else(|_| {
-                eprintln!("timeout error");
+                // The error was logged by `read_four_bytes`.
                 Ok(())
             });
 
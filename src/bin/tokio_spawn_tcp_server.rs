@@ -1,33 +1,83 @@
+//! A server that writes "hello world" to every client, then closes the
+//! connection.
+//!
+//!     cargo run --bin tokio_spawn_tcp_server
+//!     nc localhost 9878
+//!
+//! Over TLS, with a PEM certificate and private key:
+//!
+//!     cargo run --bin tokio_spawn_tcp_server -- --tls cert.pem key.pem
+//!     openssl s_client -connect localhost:9878
+//!
+
 use futures::{Future, Stream};
+use hello_async::log::Context;
+use hello_async::{error, info, tls, warn};
+use std::env;
+use std::process;
 use tokio::io;
 use tokio::net::TcpListener;
 
 fn main() {
+    let tls = match tls::acceptor_from_args(env::args().skip(1)) {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!("usage: tokio_spawn_tcp_server [--tls <cert> <key>]");
+            process::exit(2);
+        }
+    };
+
     let addr = "127.0.0.1:9878".parse().unwrap();
     let listener = TcpListener::bind(&addr).unwrap();
+    info!("server running", addr = addr, tls = tls.is_some());
 
     tokio::run({
         listener
             .incoming()
-            .for_each(|socket| {
+            .for_each(move |socket| {
                 // An inbound socket has been received.
                 //
                 // Spawn a new task to process the socket
-                tokio::spawn({
+                let mut log = Context::new();
+                if let Ok(addr) = socket.peer_addr() {
+                    log.set("addr", addr);
+                }
+                info!(context: log, "connection accepted");
+
+                match tls {
                     // In this example, "hello world" will be written to the
                     // socket followed by the socket being closed.
-                    io::write_all(socket, "hello world")
-                        // Drop the socket
-                        .map(|_| ())
-                        // Write any error to STDOUT
-                        .map_err(|e| println!("socker error = {:?}", e))
-                });
+                    None => tokio::spawn({
+                        io::write_all(socket, "hello world")
+                            // Drop the socket
+                            .map(|_| ())
+                            // Log any error
+                            .map_err(move |e| warn!(context: log, "socket error", error = e))
+                    }),
+                    // Same, once the TLS handshake is done. The TLS session is
+                    // shut down before the socket is dropped, so the client
+                    // knows it got everything.
+                    Some(ref tls) => tokio::spawn({
+                        let tls_log = log.clone();
+                        tls.accept(socket)
+                            .map_err(move |e| warn!(context: tls_log, "TLS error", error = e))
+                            .and_then(move |socket| {
+                                io::write_all(socket, "hello world")
+                                    .and_then(|(socket, _)| io::shutdown(socket))
+                                    .map(|_| ())
+                                    .map_err(
+                                        move |e| warn!(context: log, "socket error", error = e),
+                                    )
+                            })
+                    }),
+                };
 
                 // Receive the next inbound socket
                 Ok(())
             })
             .map_err(|e| {
-                println!("socket error = {}", e);
+                error!("accept error", error = e);
             })
     });
 }
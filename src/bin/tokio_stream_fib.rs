@@ -1,9 +1,10 @@
 #[macro_use]
 extern crate futures;
 
-use futures::{stream, Async, Future, Poll, Stream};
+use futures::{stream, Async, Poll, Stream};
+use hello_async::combinators::Display10;
+use std::time;
 use std::time::Duration;
-use std::{fmt, time};
 use tokio::timer;
 use tokio::timer::Interval;
 
@@ -46,42 +47,6 @@ impl Stream for Fibonacci {
     }
 }
 
-pub struct Display10<T> {
-    stream: T,
-    curr: usize,
-}
-
-impl<T> Display10<T> {
-    fn new(stream: T) -> Display10<T> {
-        Display10 { stream, curr: 0 }
-    }
-}
-
-impl<T> Future for Display10<T>
-where
-    T: Stream,
-    T::Item: fmt::Display,
-{
-    type Item = ();
-    type Error = T::Error;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        while self.curr < 10 {
-            let value = match try_ready!(self.stream.poll()) {
-                Some(value) => value,
-                // There were less than 10 values to display, terminate the
-                // future.
-                None => break,
-            };
-
-            println!("value #{} = {}", self.curr, value);
-            self.curr += 1;
-        }
-
-        Ok(Async::Ready(()))
-    }
-}
-
 fn fib() -> impl Stream<Item = u64, Error = ()> {
     stream::unfold((1, 1), |(curr, next)| {
         let yielded = curr;
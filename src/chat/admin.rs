@@ -0,0 +1,262 @@
+//! The admin socket, for managing a running server.
+//!
+//! The socket is a Unix domain socket, only accessible to the user running
+//! the server. Each connection carries a single command line, which is
+//! answered with one or more lines of text before the server closes the
+//! connection. `chatctl` sends commands from the command line. The commands
+//! are:
+//!
+//! ```text
+//! peers                  list the connected clients with their address,
+//!                        protocol, queued messages and bytes read and written
+//! kick <name> [reason]   disconnect a client
+//! notice <text>          send a notice to every client
+//! reload                 reread the settings that can change at runtime
+//! stats                  show counters since the server started
+//! help                   list the commands
+//! ```
+//!
+//! The reply to a command that failed starts with `error: `.
+
+use futures::future::{self, Either};
+use tokio::io;
+use tokio::net::UnixListener;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use super::auth::Auth;
+use super::config::Config;
+use super::event::Event;
+use super::moderation::{format_duration, Bans};
+use super::server::keep_accepting;
+use super::state::Shared;
+use crate::codec::Lines;
+use crate::{info, warn};
+
+/// Help of the admin commands.
+const HELP: &str = "\
+peers                  list the connected clients
+kick <name> [reason]   disconnect a client
+notice <text>          send a notice to every client
+reload                 reread the settings that can change at runtime
+stats                  show counters since the server started";
+
+/// Accept admin commands on the Unix socket at `path`, on `runtime`.
+///
+/// A file left at `path` by a previous run is replaced.
+pub(crate) fn start(
+    path: &Path,
+    state: Arc<Mutex<Shared>>,
+    config: Arc<Config>,
+    runtime: &mut Runtime,
+) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        result => result?,
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!("accepting admin commands", path = path.display());
+
+    let errors = state.lock().unwrap().metrics.accept_errors.clone();
+    let server = keep_accepting(listener.incoming(), path.display().to_string(), errors).for_each(
+        move |socket| {
+            let state = state.clone();
+            let config = config.clone();
+
+            let session = Lines::new(socket)
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(move |(line, mut lines)| {
+                    let line = match line {
+                        Some(line) => line,
+                        None => return Either::A(future::ok(())),
+                    };
+
+                    let line = String::from_utf8_lossy(&line);
+                    info!("admin command", command = line);
+                    let reply = match handle(&state, &config, &line) {
+                        Ok(reply) => reply,
+                        Err(e) => format!("error: {}", e),
+                    };
+                    lines.buffer(reply.as_bytes());
+                    lines.buffer(b"\n");
+
+                    Either::B(future::poll_fn(move || lines.poll_flush()))
+                })
+                .map_err(|e| warn!("admin connection error", error = e));
+
+            tokio::spawn(session);
+            Ok(())
+        },
+    );
+    runtime.spawn(server);
+
+    Ok(())
+}
+
+/// Run an admin command, returning its reply.
+fn handle(state: &Mutex<Shared>, config: &Config, line: &str) -> Result<String, String> {
+    let mut parts = line.trim().splitn(2, ' ');
+    let command = parts.next().unwrap_or("");
+    let args = parts.next().unwrap_or("").trim();
+
+    match command {
+        "peers" => Ok(peers(&state.lock().unwrap())),
+        "kick" => kick(&state.lock().unwrap(), args),
+        "notice" => notice(&state.lock().unwrap(), args),
+        "reload" => reload(state, config),
+        "stats" => Ok(stats(&state.lock().unwrap())),
+        "help" => Ok(HELP.to_string()),
+        _ => Err(format!("unknown command `{}`, try help", command)),
+    }
+}
+
+/// `peers`
+fn peers(state: &Shared) -> String {
+    if state.peers.is_empty() {
+        return "no clients".to_string();
+    }
+
+    let mut peers: Vec<_> = state.peers.iter().collect();
+    peers.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+
+    let lines: Vec<String> = peers
+        .into_iter()
+        .map(|(addr, client)| {
+            format!(
+                "{} {} {} queued={} in={} out={} idle={}",
+                addr,
+                client.name,
+                client.protocol.name(),
+                client.tx.len(),
+                client.traffic.read.load(Ordering::Relaxed),
+                client.traffic.written.load(Ordering::Relaxed),
+                format_duration(client.active.elapsed()),
+            )
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// `kick <name> [reason]`
+fn kick(state: &Shared, args: &str) -> Result<String, String> {
+    let mut parts = args.splitn(2, ' ');
+    let name = parts.next().unwrap_or("");
+    let reason = parts.next().unwrap_or("").trim();
+    if name.is_empty() {
+        return Err("usage: kick <name> [reason]".to_string());
+    }
+
+    let (addr, client) = state
+        .names
+        .get(&name.to_lowercase())
+        .and_then(|addr| state.peers.get_key_value(addr))
+        .ok_or_else(|| format!("no one is called {}", name))?;
+    let (addr, name) = (*addr, &client.name);
+
+    let notice = if reason.is_empty() {
+        "you were kicked by the server administrator".to_string()
+    } else {
+        format!("you were kicked by the server administrator: {}", reason)
+    };
+    state.disconnect(addr, &notice);
+
+    info!("the administrator kicked a client", target = name);
+    Ok(format!("kicked {}", name))
+}
+
+/// `notice <text>`
+fn notice(state: &Shared, text: &str) -> Result<String, String> {
+    if text.is_empty() {
+        return Err("usage: notice <text>".to_string());
+    }
+
+    let event = Arc::new(Event::Notice {
+        text: format!("notice from the server administrator: {}", text),
+    });
+    for client in state.peers.values() {
+        client.tx.send(event.clone());
+    }
+    Ok(format!("sent to {} client(s)", state.peers.len()))
+}
+
+/// `reload`
+///
+/// The settings are built again from the original command line. Only the
+/// announced events, the accounts and the ban file are replaced; other
+/// settings need a restart.
+fn reload(state: &Mutex<Shared>, config: &Config) -> Result<String, String> {
+    let config = Config::from_args(config.args.clone())?
+        .ok_or_else(|| "the server was started with --help".to_string())?;
+
+    // Reading the files happens without holding the lock.
+    let auth = match config.auth {
+        Some(ref auth) => Some(Arc::new(Auth::load(auth).map_err(|e| e.to_string())?)),
+        None => None,
+    };
+    let bans = match config.bans {
+        Some(ref path) => Some(Bans::load(Some(path)).map_err(|e| e.to_string())?),
+        None => None,
+    };
+
+    let mut state = state.lock().unwrap();
+    state.announce = config.announce;
+    let accounts = auth.as_ref().map_or(0, |auth| auth.len());
+    state.auth = auth;
+
+    // Without a ban file, the bans placed since the start are kept.
+    let banned = match bans {
+        Some(bans) => {
+            state.bans = bans;
+            format!("{} ban(s)", state.bans.len())
+        }
+        None => "bans kept".to_string(),
+    };
+
+    info!("settings reloaded");
+    Ok(format!(
+        "reloaded announcements, {} account(s) and {}; other settings need a restart",
+        accounts, banned
+    ))
+}
+
+/// `stats`
+fn stats(state: &Shared) -> String {
+    let metrics = &state.metrics;
+
+    let mut lines = vec![
+        format!("uptime {}", format_duration(metrics.started.elapsed())),
+        format!(
+            "clients {} ({} since start)",
+            state.peers.len(),
+            metrics.clients.get()
+        ),
+        format!(
+            "connections accepted={} rejected={} errors={}",
+            metrics.accepted.get(),
+            metrics.rejected.get(),
+            metrics.accept_errors.get()
+        ),
+        format!("rooms {}", state.rooms.len()),
+        format!("messages {}", metrics.messages.get()),
+        format!(
+            "bytes in={} out={}",
+            metrics.read.get(),
+            metrics.written.get()
+        ),
+        format!("dropped {}", metrics.dropped.get()),
+        format!("bans {}", state.bans.len()),
+    ];
+    if let Some(ref federation) = state.federation {
+        lines.push(format!("links {}", federation.neighbours().join(" ")));
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,501 @@
+//! Accounts protecting client names with a password or a token.
+
+use hmac::Hmac;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of PBKDF2 rounds used for new password hashes.
+///
+/// The number of rounds is stored with every hash, so it can be raised
+/// without invalidating existing ones.
+pub const PASSWORD_ROUNDS: u32 = 100_000;
+
+/// Length of the random salt of a password hash, in bytes.
+const SALT_LENGTH: usize = 16;
+
+/// Length of a generated token, in bytes before encoding.
+const TOKEN_LENGTH: usize = 24;
+
+/// Authentication settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// The credentials file, with one `<name> <hash>` line per account, as
+    /// printed by `line_chat_passwd`. Empty lines and lines starting with `#`
+    /// are ignored.
+    pub credentials: PathBuf,
+
+    /// Number of failed attempts a client address may make within
+    /// `failure_window` before its attempts are refused.
+    pub max_failures: usize,
+
+    /// Period over which failed attempts are counted, in seconds.
+    pub failure_window: u64,
+
+    /// Accounts whose clients are operators as soon as they log in.
+    pub operators: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            credentials: PathBuf::new(),
+            max_failures: 5,
+            failure_window: 60,
+            operators: Vec::new(),
+        }
+    }
+}
+
+/// The accounts loaded from the credentials file, along with the recent
+/// failed attempts of every client address.
+pub(crate) struct Auth {
+    /// Accounts keyed by lowercased name.
+    accounts: HashMap<String, Account>,
+
+    /// Names of the token accounts, keyed by the hash of their token.
+    tokens: HashMap<String, String>,
+
+    /// Lowercased names of the operator accounts.
+    operators: HashSet<String>,
+
+    max_failures: usize,
+    failure_window: Duration,
+
+    /// Times of the failed attempts made from each address within
+    /// `failure_window`, oldest first.
+    failures: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+}
+
+/// An entry of the credentials file.
+struct Account {
+    /// The name, as written in the file.
+    name: String,
+
+    secret: Secret,
+}
+
+/// How an account proves who it is.
+enum Secret {
+    /// A password, hashed with PBKDF2-HMAC-SHA256.
+    Password {
+        rounds: u32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+
+    /// A token, for bots, hashed with SHA-256. Tokens are random, so they
+    /// need neither a salt nor many rounds.
+    Token { hash: String },
+}
+
+/// Why an attempt to log in failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AuthError {
+    /// The password or token is wrong.
+    Denied,
+
+    /// The address made too many failed attempts recently. Nothing was
+    /// checked.
+    Blocked,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Denied => write!(fmt, "wrong password or token"),
+            AuthError::Blocked => write!(fmt, "too many failed attempts, try again later"),
+        }
+    }
+}
+
+impl Auth {
+    /// Load the credentials file.
+    pub(crate) fn load(config: &AuthConfig) -> io::Result<Auth> {
+        let path = &config.credentials;
+        let text = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to read {}: {}", path.display(), e),
+            )
+        })?;
+
+        let mut auth = Auth {
+            accounts: HashMap::new(),
+            tokens: HashMap::new(),
+            operators: HashSet::new(),
+            max_failures: config.max_failures,
+            failure_window: Duration::from_secs(config.failure_window),
+            failures: Mutex::new(HashMap::new()),
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} line {}: {}", path.display(), i + 1, reason),
+                )
+            };
+
+            let mut fields = line.split_whitespace();
+            let (name, hash) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(hash), None) => (name, hash),
+                _ => return Err(invalid("expected `<name> <hash>`")),
+            };
+            let secret = Secret::parse(hash).ok_or_else(|| invalid("invalid hash"))?;
+
+            if let Secret::Token { ref hash } = secret {
+                auth.tokens.insert(hash.clone(), name.to_string());
+            }
+
+            let account = Account {
+                name: name.to_string(),
+                secret,
+            };
+            if auth.accounts.insert(name.to_lowercase(), account).is_some() {
+                return Err(invalid(&format!("duplicate account {}", name)));
+            }
+        }
+
+        for name in &config.operators {
+            if !auth.is_registered(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("operator {} has no account in {}", name, path.display()),
+                ));
+            }
+            auth.operators.insert(name.to_lowercase());
+        }
+
+        Ok(auth)
+    }
+
+    /// Number of accounts.
+    pub(crate) fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    /// Whether `name` belongs to an account, regardless of case.
+    pub(crate) fn is_registered(&self, name: &str) -> bool {
+        self.accounts.contains_key(&name.to_lowercase())
+    }
+
+    /// Whether clients logged in to `account` are operators.
+    pub(crate) fn is_operator(&self, account: &str) -> bool {
+        self.operators.contains(&account.to_lowercase())
+    }
+
+    /// Check the password, or token, given for the account `name`.
+    ///
+    /// Returns the name of the account as written in the credentials file.
+    ///
+    /// Checking a password takes a while on purpose, so this blocks.
+    pub(crate) fn verify(&self, ip: IpAddr, name: &str, secret: &str) -> Result<String, AuthError> {
+        let attempt = self.attempt(ip)?;
+
+        let account = self
+            .accounts
+            .get(&name.to_lowercase())
+            .filter(|account| account.secret.matches(secret));
+
+        match account {
+            Some(account) => {
+                self.succeed(ip, attempt);
+                Ok(account.name.clone())
+            }
+            None => Err(AuthError::Denied),
+        }
+    }
+
+    /// Find the token account `token` belongs to.
+    pub(crate) fn verify_token(&self, ip: IpAddr, token: &str) -> Result<String, AuthError> {
+        let attempt = self.attempt(ip)?;
+
+        match self.tokens.get(&hash_token(token)) {
+            Some(name) => {
+                self.succeed(ip, attempt);
+                Ok(name.clone())
+            }
+            None => Err(AuthError::Denied),
+        }
+    }
+
+    /// Start an attempt to log in from `ip`, refusing it if the address
+    /// failed too often.
+    ///
+    /// The attempt counts as a failure until `succeed` is called, so that
+    /// connections checking passwords in parallel from the same address
+    /// can't make more attempts than allowed between them. Failures older
+    /// than `failure_window` are forgotten for every address on the way, so
+    /// addresses that stopped trying don't pile up.
+    ///
+    /// Returns the time the attempt was recorded at.
+    fn attempt(&self, ip: IpAddr) -> Result<Instant, AuthError> {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+
+        failures.retain(|_, recent| {
+            recent.retain(|time| now.duration_since(*time) < self.failure_window);
+            !recent.is_empty()
+        });
+
+        let recent = failures.entry(ip).or_default();
+        if recent.len() >= self.max_failures {
+            return Err(AuthError::Blocked);
+        }
+        recent.push(now);
+        Ok(now)
+    }
+
+    /// Forget the attempt started at `time`, which succeeded.
+    fn succeed(&self, ip: IpAddr, time: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+
+        if let Some(recent) = failures.get_mut(&ip) {
+            if let Some(i) = recent.iter().position(|t| *t == time) {
+                recent.remove(i);
+            }
+            if recent.is_empty() {
+                failures.remove(&ip);
+            }
+        }
+    }
+}
+
+impl Secret {
+    /// Parse a hash written by `hash_password` or `hash_token`.
+    fn parse(hash: &str) -> Option<Secret> {
+        let mut parts = hash.split('$');
+
+        match parts.next()? {
+            "pbkdf2-sha256" => {
+                let rounds = parts.next()?.parse().ok()?;
+                let salt = base64::decode(parts.next()?).ok()?;
+                let hash = base64::decode(parts.next()?).ok()?;
+                if parts.next().is_some() || hash.is_empty() {
+                    return None;
+                }
+                Some(Secret::Password { rounds, salt, hash })
+            }
+            "sha256" => {
+                let digest = base64::decode(parts.next()?).ok()?;
+                if parts.next().is_some() || digest.len() != 32 {
+                    return None;
+                }
+                // Kept as written, to be compared with `hash_token`.
+                Some(Secret::Token {
+                    hash: hash.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `secret` is the password or token.
+    fn matches(&self, secret: &str) -> bool {
+        match self {
+            Secret::Password { rounds, salt, hash } => {
+                let mut derived = vec![0; hash.len()];
+                pbkdf2::pbkdf2::<Hmac<Sha256>>(secret.as_bytes(), salt, *rounds, &mut derived);
+                constant_time_eq(&derived, hash)
+            }
+            Secret::Token { hash } => {
+                constant_time_eq(hash_token(secret).as_bytes(), hash.as_bytes())
+            }
+        }
+    }
+}
+
+/// Hash a password with a new random salt, for the credentials file.
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0; SALT_LENGTH];
+    rand::thread_rng().fill(&mut salt);
+
+    let mut hash = [0; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), &salt, PASSWORD_ROUNDS, &mut hash);
+
+    format!(
+        "pbkdf2-sha256${}${}${}",
+        PASSWORD_ROUNDS,
+        base64::encode(&salt),
+        base64::encode(&hash)
+    )
+}
+
+/// Generate a random token for a bot.
+pub fn new_token() -> String {
+    let mut token = [0; TOKEN_LENGTH];
+    rand::thread_rng().fill(&mut token);
+    base64::encode_config(&token, base64::URL_SAFE_NO_PAD)
+}
+
+/// Hash a token, for the credentials file.
+pub fn hash_token(token: &str) -> String {
+    format!(
+        "sha256${}",
+        base64::encode(&Sha256::digest(token.as_bytes()))
+    )
+}
+
+/// Compare secrets in a time that doesn't depend on where they differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hash `password` like `hash_password`, with fewer rounds so the tests
+    /// run quickly.
+    fn quick_hash(password: &str) -> String {
+        let salt = b"0123456789abcdef";
+        let mut hash = [0; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, 1000, &mut hash);
+        format!(
+            "pbkdf2-sha256$1000${}${}",
+            base64::encode(salt),
+            base64::encode(&hash)
+        )
+    }
+
+    /// Write `credentials` to a new file and load it.
+    fn load(test: &str, credentials: &str, operators: &[&str]) -> io::Result<Auth> {
+        let dir = std::env::temp_dir().join(format!("auth-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("credentials");
+        fs::write(&path, credentials).unwrap();
+
+        Auth::load(&AuthConfig {
+            credentials: path,
+            max_failures: 3,
+            operators: operators.iter().map(|name| name.to_string()).collect(),
+            ..AuthConfig::default()
+        })
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn verifies_passwords_and_tokens() {
+        let credentials = format!(
+            "# accounts\n\nAlice {}\nbot {}\n",
+            quick_hash("secret"),
+            hash_token("t0ken")
+        );
+        let auth = load("verify", &credentials, &["alice"]).unwrap();
+        let ip = ip("192.0.2.1");
+
+        assert_eq!(auth.len(), 2);
+        assert!(auth.is_registered("ALICE"));
+        assert!(auth.is_operator("Alice"));
+        assert!(!auth.is_operator("bot"));
+
+        assert_eq!(auth.verify(ip, "alice", "secret"), Ok("Alice".to_string()));
+        assert_eq!(auth.verify(ip, "alice", "Secret"), Err(AuthError::Denied));
+        assert_eq!(auth.verify(ip, "bob", "secret"), Err(AuthError::Denied));
+        assert_eq!(auth.verify(ip, "bot", "t0ken"), Ok("bot".to_string()));
+        assert_eq!(auth.verify_token(ip, "t0ken"), Ok("bot".to_string()));
+        assert_eq!(auth.verify_token(ip, "secret"), Err(AuthError::Denied));
+    }
+
+    #[test]
+    fn verifies_generated_hashes() {
+        let token = new_token();
+        let credentials = format!(
+            "alice {}\nbot {}\n",
+            hash_password("secret"),
+            hash_token(&token)
+        );
+        let auth = load("generated", &credentials, &[]).unwrap();
+        let ip = ip("192.0.2.1");
+
+        assert_eq!(auth.verify(ip, "alice", "secret"), Ok("alice".to_string()));
+        assert_eq!(auth.verify_token(ip, &token), Ok("bot".to_string()));
+    }
+
+    #[test]
+    fn blocks_after_too_many_failures() {
+        let credentials = format!("alice {}\n", quick_hash("secret"));
+        let auth = load("block", &credentials, &[]).unwrap();
+        let blocked = ip("192.0.2.1");
+        let other = ip("2001:db8::1");
+
+        // Successful attempts don't count.
+        for _ in 0..3 {
+            assert!(auth.verify(blocked, "alice", "secret").is_ok());
+        }
+        for _ in 0..3 {
+            assert_eq!(
+                auth.verify(blocked, "alice", "wrong"),
+                Err(AuthError::Denied)
+            );
+        }
+
+        // Not even the right password is checked now.
+        assert_eq!(
+            auth.verify(blocked, "alice", "secret"),
+            Err(AuthError::Blocked)
+        );
+        assert_eq!(auth.verify_token(blocked, "token"), Err(AuthError::Blocked));
+        assert!(auth.verify(other, "alice", "secret").is_ok());
+
+        // Until the failures leave the window.
+        for time in auth.failures.lock().unwrap().get_mut(&blocked).unwrap() {
+            *time -= Duration::from_secs(1);
+        }
+        let auth = Auth {
+            failure_window: Duration::from_secs(1),
+            ..auth
+        };
+        assert!(auth.verify(blocked, "alice", "secret").is_ok());
+        assert!(auth.failures.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_credentials() {
+        let invalid = [
+            "alice".to_string(),
+            format!("alice {} extra", quick_hash("secret")),
+            "alice md5$abc".to_string(),
+            "alice sha256$c2hvcnQ=".to_string(),
+            "alice pbkdf2-sha256$many$c2FsdA==$aGFzaA==".to_string(),
+            format!("alice {}\nALICE {}", quick_hash("a"), quick_hash("b")),
+        ];
+        for (i, credentials) in invalid.iter().enumerate() {
+            let err = load(&format!("invalid-{}", i), credentials, &[])
+                .err()
+                .unwrap_or_else(|| panic!("accepted {:?}", credentials));
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+        }
+
+        let credentials = format!("alice {}\n", quick_hash("secret"));
+        let err = load("operator", &credentials, &["bob"]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"Secret"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
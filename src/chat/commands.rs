@@ -0,0 +1,593 @@
+//! Commands that clients run by sending `/<name>`.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::event::Event;
+use super::moderation::{format_duration, parse_duration, Restriction};
+use super::peer::{validate_name, Peer};
+use super::state::{Shared, HISTORY_REPLAY, HISTORY_SIZE};
+use crate::info;
+
+/// Maximum length of a room name, in characters.
+const MAX_ROOM_NAME: usize = 32;
+
+/// What a `Peer` should do after running a command.
+pub(crate) enum Flow {
+    /// Keep processing lines from the client.
+    Continue,
+
+    /// Close the connection.
+    Quit,
+}
+
+/// Signature of a command handler.
+///
+/// A handler gets the peer that issued the command, the (already locked)
+/// shared state and the rest of the line after the command name. An `Err`
+/// is sent back to the peer as an error line.
+pub(crate) type Handler = fn(&mut Peer, &mut Shared, &str) -> Result<Flow, String>;
+
+/// A command that can be run by typing `/<name>`.
+pub(crate) struct Command {
+    /// Arguments of the command, as shown by `/help`.
+    usage: &'static str,
+
+    /// One line description of the command, as shown by `/help`.
+    help: &'static str,
+
+    handler: Handler,
+}
+
+/// Registry of the commands understood by the server.
+///
+/// New behaviour is added to the chat by registering a handler here instead
+/// of editing `Peer::poll`.
+pub(crate) struct Commands {
+    /// Commands keyed by name. A `BTreeMap` keeps `/help` sorted.
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl Command {
+    /// Format the `/help` line of the command registered as `name`.
+    fn describe(&self, name: &str) -> String {
+        if self.usage.is_empty() {
+            format!("/{} - {}", name, self.help)
+        } else {
+            format!("/{} {} - {}", name, self.usage, self.help)
+        }
+    }
+}
+
+impl Commands {
+    /// Create an empty registry.
+    pub(crate) fn new() -> Self {
+        Commands {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Create a registry holding the commands that ship with the server.
+    pub(crate) fn builtin() -> Self {
+        let mut commands = Commands::new();
+        commands.register("join", "<room>", "join a room", cmd_join);
+        commands.register("leave", "[room]", "leave a room", cmd_leave);
+        commands.register("rooms", "", "list all rooms", cmd_rooms);
+        commands.register("nick", "<name>", "change your name", cmd_nick);
+        commands.register(
+            "who",
+            "[room]",
+            "list the members of your rooms and their idle times",
+            cmd_who,
+        );
+        commands.register("msg", "<name> <text>", "send a private message", cmd_msg);
+        commands.register("me", "<action>", "send an action message", cmd_me);
+        commands.register(
+            "history",
+            "<n>",
+            "replay the last messages of your rooms",
+            cmd_history,
+        );
+        commands.register(
+            "kick",
+            "<name> [reason]",
+            "disconnect a client (operators)",
+            cmd_kick,
+        );
+        commands.register(
+            "ban",
+            "<name|ip> [duration]",
+            "ban a client's address (operators)",
+            cmd_ban,
+        );
+        commands.register("unban", "<ip>", "lift a ban (operators)", cmd_unban);
+        commands.register(
+            "mute",
+            "<name> [duration]",
+            "stop a client from sending messages (operators)",
+            cmd_mute,
+        );
+        commands.register("unmute", "<name>", "lift a mute (operators)", cmd_unmute);
+        commands.register(
+            "op",
+            "<name>",
+            "make a client an operator (operators)",
+            cmd_op,
+        );
+        commands.register("pong", "", "answer a keepalive ping", cmd_pong);
+        commands.register("quit", "", "leave the chat", cmd_quit);
+        commands.register("help", "[command]", "show this help", cmd_help);
+        commands
+    }
+
+    /// Register a command, replacing any command with the same name.
+    pub(crate) fn register(
+        &mut self,
+        name: &'static str,
+        usage: &'static str,
+        help: &'static str,
+        handler: Handler,
+    ) {
+        let command = Command {
+            usage,
+            help,
+            handler,
+        };
+        self.commands.insert(name, command);
+    }
+
+    /// Run the command in `line`, which is everything after the leading `/`.
+    pub(crate) fn dispatch(&self, peer: &mut Peer, line: &str) -> Result<Flow, String> {
+        let mut parts = line.trim().splitn(2, ' ');
+        let name = parts.next().unwrap_or("");
+        let args = parts.next().unwrap_or("").trim();
+
+        let command = match self.commands.get(name) {
+            Some(command) => command,
+            None => return Err(format!("unknown command /{}, try /help", name)),
+        };
+
+        // Handlers run with the shared state locked, so they see a consistent
+        // view of the rooms and peers.
+        let state = peer.state.clone();
+        let mut state = state.lock().unwrap();
+        (command.handler)(peer, &mut state, args)
+    }
+}
+
+/// Check that `name` can be used as a room name.
+///
+/// Rooms double as IRC channels, whose names can't contain `,`, which
+/// separates channels in `JOIN` and `PART`, or `:`.
+pub(crate) fn validate_room(room: &str) -> Result<(), String> {
+    if room.chars().count() > MAX_ROOM_NAME
+        || room.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(format!(
+            "invalid room name, use at most {} characters without spaces",
+            MAX_ROOM_NAME
+        ));
+    }
+
+    if room.contains([',', ':']) {
+        return Err("invalid room name, it can't contain , or :".to_string());
+    }
+
+    Ok(())
+}
+
+/// `/join <room>`
+fn cmd_join(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    if args.is_empty() {
+        return Err("usage: /join <room>".to_string());
+    }
+
+    validate_room(args)?;
+
+    if !peer.rooms.insert(args.to_string()) {
+        return Err(format!("you are already in {}", args));
+    }
+
+    state.join(args, peer.addr);
+    peer.reply(&format!("you joined {}", args));
+    peer.replay(&state.history(args, HISTORY_REPLAY));
+    Ok(Flow::Continue)
+}
+
+/// `/leave [room]`
+fn cmd_leave(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    let room = if !args.is_empty() {
+        args.to_string()
+    } else if peer.rooms.len() == 1 {
+        peer.rooms.iter().next().unwrap().clone()
+    } else if peer.rooms.is_empty() {
+        return Err("you are not in any room".to_string());
+    } else {
+        return Err("usage: /leave <room>".to_string());
+    };
+
+    if !peer.rooms.remove(&room) {
+        return Err(format!("you are not in {}", room));
+    }
+
+    state.leave(&room, peer.addr);
+    peer.reply(&format!("you left {}", room));
+    Ok(Flow::Continue)
+}
+
+/// `/rooms`
+fn cmd_rooms(peer: &mut Peer, state: &mut Shared, _args: &str) -> Result<Flow, String> {
+    let mut rooms: Vec<(&String, usize)> = state
+        .rooms
+        .iter()
+        .map(|(name, room)| (name, room.members.len()))
+        .collect();
+    rooms.sort();
+
+    if rooms.is_empty() {
+        peer.reply("there are no rooms, use /join <room> to create one");
+    }
+
+    for (room, members) in rooms {
+        // Mark the rooms this peer is a member of.
+        let marker = if peer.rooms.contains(room) {
+            " (joined)"
+        } else {
+            ""
+        };
+        peer.reply(&format!("{} - {} member(s){}", room, members, marker));
+    }
+
+    Ok(Flow::Continue)
+}
+
+/// `/nick <name>`
+fn cmd_nick(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    if args.is_empty() {
+        return Err("usage: /nick <name>".to_string());
+    }
+
+    let name = validate_name(args.as_bytes())?;
+    state.rename(peer.addr, &name)?;
+
+    peer.reply(&format!("you are now known as {}", name));
+    peer.renamed(name);
+    Ok(Flow::Continue)
+}
+
+/// `/who [room]`
+fn cmd_who(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    let rooms: Vec<String> = if args.is_empty() {
+        peer.rooms.iter().cloned().collect()
+    } else {
+        vec![args.to_string()]
+    };
+
+    if rooms.is_empty() {
+        return Err("you are not in any room".to_string());
+    }
+
+    for room in rooms {
+        let members = &state
+            .rooms
+            .get(&room)
+            .ok_or_else(|| format!("no such room: {}", room))?
+            .members;
+
+        let mut clients: Vec<(String, Instant)> = members
+            .iter()
+            .filter_map(|addr| state.peers.get(addr))
+            .map(|client| (client.name.clone(), client.active))
+            .collect();
+
+        // Clients of linked servers in the same room are listed as well.
+        if let Some(ref federation) = state.federation {
+            clients.extend(federation.members(&room));
+        }
+        clients.sort();
+
+        // Each member is shown with the time since its last message.
+        let names: Vec<String> = clients
+            .iter()
+            .map(|(name, active)| {
+                let idle = format_duration(active.elapsed());
+                format!("{} (idle {})", name, idle)
+            })
+            .collect();
+
+        peer.reply(&format!("{}: {}", room, names.join(", ")));
+    }
+
+    Ok(Flow::Continue)
+}
+
+/// `/msg <name> <text>`
+fn cmd_msg(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    let mut parts = args.splitn(2, ' ');
+    let name = parts.next().unwrap_or("");
+    let text = parts.next().unwrap_or("").trim();
+
+    if name.is_empty() || text.is_empty() {
+        return Err("usage: /msg <name> <text>".to_string());
+    }
+
+    state.check_muted(peer.addr)?;
+
+    // Clients of linked servers are addressed as `name@server`.
+    if name.contains('@') {
+        let to = state.relay_private(peer.addr, name, text, false)?;
+        peer.reply(&format!("to {}: {}", to, text));
+        return Ok(Flow::Continue);
+    }
+
+    let addr = state.find(name)?;
+    state.touch(peer.addr);
+    state.private(addr, &peer.name, text, false);
+
+    peer.reply(&format!("to {}: {}", state.peers[&addr].name, text));
+    Ok(Flow::Continue)
+}
+
+/// `/me <action>`
+fn cmd_me(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    if args.is_empty() {
+        return Err("usage: /me <action>".to_string());
+    }
+
+    peer.broadcast(state, args, true)?;
+    Ok(Flow::Continue)
+}
+
+/// `/history <n>`
+fn cmd_history(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    let n: usize = match args.parse() {
+        Ok(n) if n > 0 => n,
+        _ => return Err("usage: /history <n>".to_string()),
+    };
+
+    if peer.rooms.is_empty() {
+        return Err("you are not in any room".to_string());
+    }
+
+    // Only `HISTORY_SIZE` lines are kept, so larger requests can't return more.
+    let n = n.min(HISTORY_SIZE);
+
+    for room in peer.rooms.clone() {
+        peer.replay(&state.history(&room, n));
+    }
+
+    Ok(Flow::Continue)
+}
+
+/// Check that the peer may run the moderation command `command`.
+fn require_operator(peer: &Peer, state: &Shared, command: &str) -> Result<(), String> {
+    if !state.is_operator(peer.addr) {
+        return Err(format!("only operators can use /{}", command));
+    }
+    Ok(())
+}
+
+/// Find the client a moderation command is aimed at.
+///
+/// Unlike `/msg`, the name must match exactly, so that a typo can't hit
+/// someone else.
+fn target(peer: &Peer, state: &Shared, name: &str) -> Result<SocketAddr, String> {
+    let addr = *state
+        .names
+        .get(&name.to_lowercase())
+        .ok_or_else(|| format!("no one is called {}", name))?;
+
+    if addr == peer.addr {
+        return Err("you can't do that to yourself".to_string());
+    }
+    Ok(addr)
+}
+
+/// Parse the optional duration of a ban or a mute.
+fn restriction(duration: Option<&str>) -> Result<Restriction, String> {
+    let duration = match duration {
+        Some(duration) => Some(parse_duration(duration)?),
+        None => None,
+    };
+    Ok(Restriction::new(duration))
+}
+
+/// `/kick <name> [reason]`
+fn cmd_kick(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    require_operator(peer, state, "kick")?;
+
+    let mut parts = args.splitn(2, ' ');
+    let name = parts.next().unwrap_or("");
+    let reason = parts.next().unwrap_or("").trim();
+    if name.is_empty() {
+        return Err("usage: /kick <name> [reason]".to_string());
+    }
+
+    let addr = target(peer, state, name)?;
+    let name = state.peers[&addr].name.clone();
+
+    let notice = if reason.is_empty() {
+        format!("you were kicked by {}", peer.name)
+    } else {
+        format!("you were kicked by {}: {}", peer.name, reason)
+    };
+    state.disconnect(addr, &notice);
+
+    info!(context: peer.log, "kicked", target = name);
+    peer.reply(&format!("kicked {}", name));
+    Ok(Flow::Continue)
+}
+
+/// `/ban <name|ip> [duration]`
+fn cmd_ban(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    require_operator(peer, state, "ban")?;
+
+    let mut parts = args.split_whitespace();
+    let (who, duration) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(who), duration, None) => (who, duration),
+        _ => return Err("usage: /ban <name|ip> [duration]".to_string()),
+    };
+
+    let ip: IpAddr = match who.parse() {
+        Ok(ip) => ip,
+        Err(_) => target(peer, state, who)?.ip(),
+    };
+    if ip == peer.addr.ip() {
+        return Err("you can't ban your own address".to_string());
+    }
+
+    let ban = restriction(duration)?;
+    let saved = state.bans.insert(ip, ban, &peer.name);
+
+    // Clients already connected from the address are disconnected as well.
+    let notice = format!("you are banned {}", ban.remaining());
+    let banned: Vec<SocketAddr> = state
+        .peers
+        .keys()
+        .filter(|addr| addr.ip() == ip)
+        .cloned()
+        .collect();
+    for addr in banned {
+        state.disconnect(addr, &notice);
+    }
+
+    info!(
+        context: peer.log,
+        "banned",
+        target = ip,
+        duration = ban.remaining()
+    );
+    peer.reply(&format!("banned {} {}", ip, ban.remaining()));
+    saved.map_err(|e| format!("the ban list couldn't be saved: {}", e))?;
+    Ok(Flow::Continue)
+}
+
+/// `/unban <ip>`
+fn cmd_unban(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    require_operator(peer, state, "unban")?;
+
+    let ip: IpAddr = args.parse().map_err(|_| "usage: /unban <ip>".to_string())?;
+
+    let removed = state
+        .bans
+        .remove(ip)
+        .map_err(|e| format!("the ban list couldn't be saved: {}", e))?;
+    if !removed {
+        return Err(format!("{} isn't banned", ip));
+    }
+
+    info!(context: peer.log, "lifted a ban", target = ip);
+    peer.reply(&format!("lifted the ban on {}", ip));
+    Ok(Flow::Continue)
+}
+
+/// `/mute <name> [duration]`
+fn cmd_mute(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    require_operator(peer, state, "mute")?;
+
+    let mut parts = args.split_whitespace();
+    let (name, duration) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(name), duration, None) => (name, duration),
+        _ => return Err("usage: /mute <name> [duration]".to_string()),
+    };
+
+    let addr = target(peer, state, name)?;
+    let mute = restriction(duration)?;
+
+    let client = state.peers.get_mut(&addr).unwrap();
+    client.muted = Some(mute);
+    client.tx.send(Arc::new(Event::Notice {
+        text: format!("you were muted by {} {}", peer.name, mute.remaining()),
+    }));
+
+    info!(
+        context: peer.log,
+        "muted",
+        target = client.name,
+        duration = mute.remaining()
+    );
+    peer.reply(&format!("muted {} {}", client.name, mute.remaining()));
+    Ok(Flow::Continue)
+}
+
+/// `/unmute <name>`
+fn cmd_unmute(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    require_operator(peer, state, "unmute")?;
+
+    if args.is_empty() {
+        return Err("usage: /unmute <name>".to_string());
+    }
+
+    let addr = target(peer, state, args)?;
+    let client = state.peers.get_mut(&addr).unwrap();
+    if client.muted.take().is_none() {
+        return Err(format!("{} isn't muted", client.name));
+    }
+    client.tx.send(Arc::new(Event::Notice {
+        text: format!("{} lifted your mute", peer.name),
+    }));
+
+    info!(context: peer.log, "lifted a mute", target = client.name);
+    peer.reply(&format!("lifted the mute on {}", client.name));
+    Ok(Flow::Continue)
+}
+
+/// `/op <name>`
+fn cmd_op(peer: &mut Peer, state: &mut Shared, args: &str) -> Result<Flow, String> {
+    require_operator(peer, state, "op")?;
+
+    if args.is_empty() {
+        return Err("usage: /op <name>".to_string());
+    }
+
+    let addr = target(peer, state, args)?;
+    let client = state.peers.get_mut(&addr).unwrap();
+    if client.operator {
+        return Err(format!("{} is already an operator", client.name));
+    }
+    client.operator = true;
+    client.tx.send(Arc::new(Event::Notice {
+        text: format!("{} made you an operator", peer.name),
+    }));
+
+    info!(context: peer.log, "made an operator", target = client.name);
+    peer.reply(&format!("{} is now an operator", client.name));
+    Ok(Flow::Continue)
+}
+
+/// `/pong`
+///
+/// Any line resets the idle timer, so there is nothing left to do.
+fn cmd_pong(_peer: &mut Peer, _state: &mut Shared, _args: &str) -> Result<Flow, String> {
+    Ok(Flow::Continue)
+}
+
+/// `/quit`
+fn cmd_quit(peer: &mut Peer, _state: &mut Shared, _args: &str) -> Result<Flow, String> {
+    peer.reply("bye");
+    Ok(Flow::Quit)
+}
+
+/// `/help [command]`
+fn cmd_help(peer: &mut Peer, _state: &mut Shared, args: &str) -> Result<Flow, String> {
+    let commands = peer.commands.clone();
+    let name = args.trim_start_matches('/');
+
+    if !name.is_empty() {
+        let command = commands
+            .commands
+            .get(name)
+            .ok_or_else(|| format!("unknown command /{}", name))?;
+        peer.reply(&command.describe(name));
+        return Ok(Flow::Continue);
+    }
+
+    for (name, command) in &commands.commands {
+        peer.reply(&command.describe(name));
+    }
+    peer.reply("start a message with // to send a line beginning with /");
+
+    Ok(Flow::Continue)
+}
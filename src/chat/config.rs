@@ -0,0 +1,681 @@
+//! Settings of the chat server.
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::auth::AuthConfig;
+use super::event::Announcement;
+use super::federation::{FederationConfig, LinkTlsConfig};
+use super::flood::FloodConfig;
+use super::peer::validate_name;
+use super::queue::QueueConfig;
+use super::transcript::{TranscriptConfig, DEFAULT_TRANSCRIPT_KEEP, DEFAULT_TRANSCRIPT_MAX};
+use crate::codec::{LongLines, DEFAULT_MAX_LINE_LENGTH, DEFAULT_READ_RESERVE};
+use crate::log::{self, LogConfig};
+
+/// Server settings.
+///
+/// The settings are read from an optional TOML file, then overridden by
+/// command-line flags. Settings missing from both keep their default value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to accept clients on.
+    pub listen: Vec<SocketAddr>,
+
+    /// Addresses to accept IRC clients on.
+    pub irc_listen: Vec<SocketAddr>,
+
+    /// Addresses to accept WebSocket clients on.
+    pub websocket_listen: Vec<SocketAddr>,
+
+    /// Maximum number of connections, including clients that haven't sent
+    /// their name yet.
+    pub max_clients: usize,
+
+    /// Maximum length of a line sent by a client, in bytes, not counting the
+    /// line ending.
+    pub max_line_length: usize,
+
+    /// What to do with lines longer than `max_line_length`.
+    pub long_lines: LongLines,
+
+    /// Maximum number of queued lines a `Peer` stages on each tick before
+    /// yielding to other tasks.
+    pub lines_per_tick: usize,
+
+    /// Space reserved in a connection's read buffer before each read, in
+    /// bytes.
+    pub read_reserve: usize,
+
+    /// Amount of data a `Peer` stages in its write buffer before it stops
+    /// taking lines off its queue, in bytes.
+    pub write_buffer_limit: usize,
+
+    /// Seconds of silence after which a client is pinged. `0` keeps idle
+    /// clients forever.
+    pub keepalive: u64,
+
+    /// Seconds a pinged client has to send a line before it is disconnected.
+    pub idle_timeout: u64,
+
+    /// Seconds a new client has to complete the handshake, from the TLS
+    /// handshake to sending an acceptable name. `0` waits forever.
+    pub handshake_timeout: u64,
+
+    /// Presence events announced to the members of a room.
+    pub announce: Vec<Announcement>,
+
+    /// File the bans placed by operators are saved to. Without it, bans are
+    /// lost when the server stops.
+    pub bans: Option<PathBuf>,
+
+    /// Unix domain socket to accept admin commands on, for `chatctl`.
+    pub admin_socket: Option<PathBuf>,
+
+    /// Addresses to serve the metrics on, in the Prometheus text format.
+    pub metrics_listen: Vec<SocketAddr>,
+
+    /// Verbosity and format of the logs. `line_chat` applies them with
+    /// `log::init` before logging anything, so a `reload` doesn't change
+    /// them.
+    pub log: LogConfig,
+
+    pub queue: QueueConfig,
+
+    /// Rate limit of the lines sent by each client.
+    pub flood: FloodConfig,
+
+    /// The transcript is only written if this is set.
+    pub transcript: Option<TranscriptConfig>,
+
+    /// Listeners encrypting their connections, if any.
+    pub tls: Option<TlsConfig>,
+
+    /// Names are only protected by accounts if this is set.
+    pub auth: Option<AuthConfig>,
+
+    /// Links to other servers, if any.
+    pub federation: Option<FederationConfig>,
+
+    /// The command-line arguments the settings were built from, without the
+    /// program name. The admin `reload` command builds the settings again
+    /// from them, rereading the `--config` file.
+    #[serde(skip)]
+    pub args: Vec<String>,
+}
+
+/// Listeners that encrypt their connections with TLS.
+///
+/// Clients speak the same protocols as on the plaintext listeners. To serve
+/// TLS clients only, set `listen` to an empty list.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The certificate chain, PEM encoded, starting with the server's own
+    /// certificate.
+    pub cert: PathBuf,
+
+    /// The private key of the certificate, PEM encoded in PKCS #8.
+    pub key: PathBuf,
+
+    /// Addresses to accept clients on.
+    pub listen: Vec<SocketAddr>,
+
+    /// Addresses to accept IRC clients on.
+    pub irc_listen: Vec<SocketAddr>,
+
+    /// Addresses to accept WebSocket clients on.
+    pub websocket_listen: Vec<SocketAddr>,
+}
+
+/// Command-line help of the server.
+pub const USAGE: &str = "\
+usage: line_chat [options]
+
+options:
+    --config <file>             read settings from a TOML file
+    --listen <addr>             accept clients on <addr>, may be repeated
+    --irc-listen <addr>         accept IRC clients on <addr>, may be repeated
+    --websocket-listen <addr>   accept WebSocket clients on <addr>, may be
+                                repeated
+    --max-clients <n>           maximum number of connections
+    --max-line-length <bytes>   maximum length of a line sent by a client
+    --long-lines <policy>       disconnect or truncate
+    --lines-per-tick <n>        lines staged for a client before yielding
+    --read-reserve <bytes>      space reserved in read buffers before a read
+    --write-buffer-limit <bytes>
+                                data staged for a client before queueing
+    --announce <events>         comma separated presence events to announce:
+                                join, leave, nick and timeout, or none
+    --keepalive <secs>          ping clients after <secs> of silence, 0 = never
+    --idle-timeout <secs>       disconnect pinged clients that stay silent for
+                                <secs>
+    --handshake-timeout <secs>  disconnect clients that haven't sent their name
+                                after <secs>, 0 = never
+    --queue-capacity <n>        lines queued for a client
+    --overflow <policy>         drop-oldest, drop-newest or disconnect
+    --flood-burst <n>           lines a client may send at once
+    --flood-rate <n>            lines a client may send per second after its
+                                burst
+    --transcript <file>         write a transcript to <file>
+    --transcript-max-size <bytes>
+                                rotate the transcript at this size, 0 = never
+    --transcript-keep <n>       number of rotated transcripts to keep
+    --credentials <file>        protect the names listed in <file> with
+                                their password or token
+    --operator <name>           make clients logged in to account <name>
+                                operators, may be repeated
+    --bans <file>               save the bans placed by operators to <file>
+    --admin-socket <file>       accept admin commands on the Unix socket <file>
+    --metrics-listen <addr>     serve Prometheus metrics on <addr>, may be
+                                repeated
+    --log-level <level>         error, warn, info, debug, trace or off
+    --log-format <format>       text or json
+    --server-name <name>        name of this server on the linked servers
+    --link-listen <addr>        accept links from other servers on <addr>, may
+                                be repeated
+    --link <host:port>          link to another server, may be repeated
+    --link-cert <file>          PEM certificate chain of the link listeners,
+                                encrypts the links
+    --link-key <file>           PEM private key of the link listeners
+    --link-ca <file>            PEM certificate trusted to sign those of the
+                                linked servers, encrypts the links
+    --tls-cert <file>           PEM certificate chain of the TLS listeners
+    --tls-key <file>            PEM private key of the TLS listeners
+    --tls-listen <addr>         accept clients over TLS on <addr>, may be
+                                repeated
+    --tls-irc-listen <addr>     accept IRC clients over TLS on <addr>, may be
+                                repeated
+    --tls-websocket-listen <addr>
+                                accept WebSocket clients over TLS on <addr>,
+                                may be repeated
+    -h, --help                  print this help
+";
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec!["0.0.0.0:6142".parse().unwrap()],
+            irc_listen: Vec::new(),
+            websocket_listen: Vec::new(),
+            max_clients: 1024,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            long_lines: LongLines::Disconnect,
+            lines_per_tick: 10,
+            read_reserve: DEFAULT_READ_RESERVE,
+            write_buffer_limit: 64 * 1024,
+            keepalive: 0,
+            idle_timeout: 60,
+            handshake_timeout: 0,
+            announce: Announcement::ALL.to_vec(),
+            bans: None,
+            admin_socket: None,
+            metrics_listen: Vec::new(),
+            log: LogConfig::default(),
+            queue: QueueConfig::default(),
+            flood: FloodConfig::default(),
+            transcript: None,
+            tls: None,
+            auth: None,
+            federation: None,
+            args: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Build the settings from the command-line arguments, without the
+    /// program name.
+    ///
+    /// A `--config` file is read first wherever it appears, so the other
+    /// flags always take precedence over it. Returns `None` if `--help` was
+    /// given.
+    pub fn from_args<I>(args: I) -> Result<Option<Config>, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut flags = Vec::new();
+        let mut path = None;
+
+        let mut iter = args.iter().cloned();
+        while let Some(flag) = iter.next() {
+            if flag == "-h" || flag == "--help" {
+                return Ok(None);
+            }
+            let value = iter
+                .next()
+                .ok_or_else(|| format!("missing value for `{}`", flag))?;
+            if flag == "--config" {
+                path = Some(value);
+            } else {
+                flags.push((flag, value));
+            }
+        }
+
+        let mut config = match path {
+            Some(path) => Config::load(Path::new(&path))?,
+            None => Config::default(),
+        };
+
+        // The `--*listen` flags replace the configured addresses rather than
+        // adding to them.
+        let mut listen = Vec::new();
+        let mut irc_listen = Vec::new();
+        let mut websocket_listen = Vec::new();
+        let mut tls_listen = Vec::new();
+        let mut tls_irc_listen = Vec::new();
+        let mut tls_websocket_listen = Vec::new();
+        let mut link_listen = Vec::new();
+        let mut metrics_listen = Vec::new();
+        let mut links = Vec::new();
+        // The rotation flags apply to the transcript wherever `--transcript`
+        // appears, so they are only applied after the loop.
+        let mut transcript_max_size = None;
+        let mut transcript_keep = None;
+        for (flag, value) in flags {
+            match &flag[..] {
+                "--listen" => listen.push(parse_flag(&flag, &value)?),
+                "--irc-listen" => irc_listen.push(parse_flag(&flag, &value)?),
+                "--websocket-listen" => websocket_listen.push(parse_flag(&flag, &value)?),
+                "--tls-listen" => tls_listen.push(parse_flag(&flag, &value)?),
+                "--tls-irc-listen" => tls_irc_listen.push(parse_flag(&flag, &value)?),
+                "--tls-websocket-listen" => tls_websocket_listen.push(parse_flag(&flag, &value)?),
+                "--credentials" => {
+                    config
+                        .auth
+                        .get_or_insert_with(AuthConfig::default)
+                        .credentials = PathBuf::from(value)
+                }
+                "--operator" => config
+                    .auth
+                    .get_or_insert_with(AuthConfig::default)
+                    .operators
+                    .push(value),
+                "--bans" => config.bans = Some(PathBuf::from(value)),
+                "--admin-socket" => config.admin_socket = Some(PathBuf::from(value)),
+                "--metrics-listen" => metrics_listen.push(parse_flag(&flag, &value)?),
+                "--log-level" => {
+                    log::parse_level(&value)
+                        .map_err(|e| format!("invalid value `{}` for `{}`: {}", value, flag, e))?;
+                    config.log.level = Some(value)
+                }
+                "--log-format" => config.log.format = Some(parse_flag(&flag, &value)?),
+                "--server-name" => {
+                    config
+                        .federation
+                        .get_or_insert_with(FederationConfig::default)
+                        .name = value
+                }
+                "--link-listen" => link_listen.push(parse_flag(&flag, &value)?),
+                "--link" => links.push(value),
+                "--link-cert" | "--link-key" | "--link-ca" => {
+                    let tls = config
+                        .federation
+                        .get_or_insert_with(FederationConfig::default)
+                        .tls
+                        .get_or_insert_with(LinkTlsConfig::default);
+                    let path = PathBuf::from(value);
+                    match &flag[..] {
+                        "--link-cert" => tls.cert = path,
+                        "--link-key" => tls.key = path,
+                        _ => tls.ca = Some(path),
+                    }
+                }
+                "--tls-cert" => {
+                    config.tls.get_or_insert_with(TlsConfig::default).cert = PathBuf::from(value)
+                }
+                "--tls-key" => {
+                    config.tls.get_or_insert_with(TlsConfig::default).key = PathBuf::from(value)
+                }
+                "--max-clients" => config.max_clients = parse_flag(&flag, &value)?,
+                "--max-line-length" => config.max_line_length = parse_flag(&flag, &value)?,
+                "--long-lines" => config.long_lines = parse_flag(&flag, &value)?,
+                "--lines-per-tick" => config.lines_per_tick = parse_flag(&flag, &value)?,
+                "--read-reserve" => config.read_reserve = parse_flag(&flag, &value)?,
+                "--write-buffer-limit" => config.write_buffer_limit = parse_flag(&flag, &value)?,
+                "--announce" => {
+                    config.announce = match &value[..] {
+                        "none" => Vec::new(),
+                        _ => value
+                            .split(',')
+                            .map(|event| parse_flag(&flag, event.trim()))
+                            .collect::<Result<_, _>>()?,
+                    }
+                }
+                "--keepalive" => config.keepalive = parse_flag(&flag, &value)?,
+                "--idle-timeout" => config.idle_timeout = parse_flag(&flag, &value)?,
+                "--handshake-timeout" => config.handshake_timeout = parse_flag(&flag, &value)?,
+                "--queue-capacity" => config.queue.capacity = parse_flag(&flag, &value)?,
+                "--overflow" => config.queue.policy = parse_flag(&flag, &value)?,
+                "--flood-burst" => config.flood.burst = parse_flag(&flag, &value)?,
+                "--flood-rate" => config.flood.rate = parse_flag(&flag, &value)?,
+                // Only the path is replaced: a configured rotation is kept.
+                "--transcript" => match config.transcript.as_mut() {
+                    Some(transcript) => transcript.path = PathBuf::from(value),
+                    None => {
+                        config.transcript = Some(TranscriptConfig {
+                            path: PathBuf::from(value),
+                            max_size: DEFAULT_TRANSCRIPT_MAX,
+                            keep: DEFAULT_TRANSCRIPT_KEEP,
+                        })
+                    }
+                },
+                "--transcript-max-size" => transcript_max_size = Some(parse_flag(&flag, &value)?),
+                "--transcript-keep" => transcript_keep = Some(parse_flag(&flag, &value)?),
+                _ => return Err(format!("unknown option `{}`", flag)),
+            }
+        }
+        if transcript_max_size.is_some() || transcript_keep.is_some() {
+            let transcript = config.transcript.as_mut().ok_or_else(|| {
+                "`--transcript-max-size` and `--transcript-keep` require a transcript to be \
+                 configured"
+                    .to_string()
+            })?;
+            if let Some(max_size) = transcript_max_size {
+                transcript.max_size = max_size;
+            }
+            if let Some(keep) = transcript_keep {
+                transcript.keep = keep;
+            }
+        }
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+        if !irc_listen.is_empty() {
+            config.irc_listen = irc_listen;
+        }
+        if !websocket_listen.is_empty() {
+            config.websocket_listen = websocket_listen;
+        }
+        if !metrics_listen.is_empty() {
+            config.metrics_listen = metrics_listen;
+        }
+        if !tls_listen.is_empty() {
+            config.tls.get_or_insert_with(TlsConfig::default).listen = tls_listen;
+        }
+        if !tls_irc_listen.is_empty() {
+            config.tls.get_or_insert_with(TlsConfig::default).irc_listen = tls_irc_listen;
+        }
+        if !tls_websocket_listen.is_empty() {
+            config
+                .tls
+                .get_or_insert_with(TlsConfig::default)
+                .websocket_listen = tls_websocket_listen;
+        }
+        if !link_listen.is_empty() {
+            config
+                .federation
+                .get_or_insert_with(FederationConfig::default)
+                .listen = link_listen;
+        }
+        if !links.is_empty() {
+            config
+                .federation
+                .get_or_insert_with(FederationConfig::default)
+                .links = links;
+        }
+
+        config.validate()?;
+        config.args = args;
+        Ok(Some(config))
+    }
+
+    /// Read the settings from a TOML file.
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("invalid config {}: {}", path.display(), e))
+    }
+
+    /// Check that the settings leave the server something to work with.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut addresses = self.listen.len() + self.irc_listen.len() + self.websocket_listen.len();
+
+        if let Some(ref tls) = self.tls {
+            if tls.cert.as_os_str().is_empty() || tls.key.as_os_str().is_empty() {
+                return Err("TLS listeners require both a certificate and a key".to_string());
+            }
+            addresses += tls.listen.len() + tls.irc_listen.len() + tls.websocket_listen.len();
+        }
+
+        if addresses == 0 {
+            return Err("no listen address configured".to_string());
+        }
+
+        if let Some(ref auth) = self.auth {
+            if auth.credentials.as_os_str().is_empty() {
+                return Err("authentication requires a credentials file".to_string());
+            }
+            if auth.max_failures == 0 {
+                return Err("`auth.max_failures` must be greater than 0".to_string());
+            }
+        }
+
+        if let Some(ref federation) = self.federation {
+            validate_name(federation.name.as_bytes())
+                .map_err(|e| format!("invalid `federation.name`: {}", e))?;
+            if federation.listen.is_empty() && federation.links.is_empty() {
+                return Err("federation requires links or a link listen address".to_string());
+            }
+            if federation.max_backoff == 0 {
+                return Err("`federation.max_backoff` must be greater than 0".to_string());
+            }
+            if let Some(ref tls) = federation.tls {
+                if !federation.listen.is_empty()
+                    && (tls.cert.as_os_str().is_empty() || tls.key.as_os_str().is_empty())
+                {
+                    return Err(
+                        "TLS link listeners require both a certificate and a key".to_string()
+                    );
+                }
+            }
+        }
+
+        if let Some(ref level) = self.log.level {
+            log::parse_level(level).map_err(|e| format!("invalid `log.level`: {}", e))?;
+        }
+
+        let limits = [
+            ("max_clients", self.max_clients),
+            ("max_line_length", self.max_line_length),
+            ("lines_per_tick", self.lines_per_tick),
+            ("read_reserve", self.read_reserve),
+            ("write_buffer_limit", self.write_buffer_limit),
+            ("queue.capacity", self.queue.capacity),
+            ("flood.burst", self.flood.burst),
+        ];
+        for &(name, value) in limits.iter() {
+            if value == 0 {
+                return Err(format!("`{}` must be greater than 0", name));
+            }
+        }
+
+        if self.keepalive > 0 && self.idle_timeout == 0 {
+            return Err("`idle_timeout` must be greater than 0".to_string());
+        }
+
+        // Also rejects NaN.
+        if !(self.flood.rate > 0.0 && self.flood.rate.is_finite()) {
+            return Err("`flood.rate` must be greater than 0".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the value of a command-line flag.
+fn parse_flag<T>(flag: &str, value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("invalid value `{}` for `{}`: {}", value, flag, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::log::Format;
+
+    fn from_args(args: &[&str]) -> Result<Option<Config>, String> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// Write `text` to a new config file, returning its path.
+    fn config_file(test: &str, text: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("config-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("line_chat.toml");
+        fs::write(&path, text).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = from_args(&[]).unwrap().unwrap();
+        assert_eq!(config.listen, ["0.0.0.0:6142".parse().unwrap()]);
+        assert!(config.transcript.is_none());
+        assert!(config.args.is_empty());
+
+        assert!(from_args(&["--max-clients", "1", "--help"])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn applies_transcript_flags_in_any_order() {
+        let orders: [&[&str]; 3] = [
+            &[
+                "--transcript",
+                "chat.log",
+                "--transcript-max-size",
+                "100",
+                "--transcript-keep",
+                "2",
+            ],
+            &[
+                "--transcript-max-size",
+                "100",
+                "--transcript-keep",
+                "2",
+                "--transcript",
+                "chat.log",
+            ],
+            &[
+                "--transcript-keep",
+                "2",
+                "--transcript",
+                "chat.log",
+                "--transcript-max-size",
+                "100",
+            ],
+        ];
+        for args in orders.iter() {
+            let config = from_args(args).unwrap().unwrap();
+            let transcript = config.transcript.unwrap();
+            assert_eq!(transcript.path, Path::new("chat.log"));
+            assert_eq!(transcript.max_size, 100);
+            assert_eq!(transcript.keep, 2);
+        }
+
+        let config = from_args(&["--transcript", "chat.log"]).unwrap().unwrap();
+        let transcript = config.transcript.unwrap();
+        assert_eq!(transcript.max_size, DEFAULT_TRANSCRIPT_MAX);
+        assert_eq!(transcript.keep, DEFAULT_TRANSCRIPT_KEEP);
+
+        let err = from_args(&["--transcript-keep", "2"]).err().unwrap();
+        assert!(err.contains("require a transcript"), "{}", err);
+    }
+
+    #[test]
+    fn flags_override_the_config_file_wherever_it_appears() {
+        let path = config_file(
+            "override",
+            "listen = [\"127.0.0.1:1\"]\nmax_clients = 5\n\n\
+             [transcript]\npath = \"old.log\"\nmax_size = 10\nkeep = 1\n",
+        );
+
+        let config = from_args(&[
+            "--max-clients",
+            "7",
+            "--transcript",
+            "new.log",
+            "--config",
+            &path,
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.listen, ["127.0.0.1:1".parse().unwrap()]);
+        assert_eq!(config.max_clients, 7);
+        // Only the path is replaced.
+        let transcript = config.transcript.unwrap();
+        assert_eq!(transcript.path, Path::new("new.log"));
+        assert_eq!(transcript.max_size, 10);
+        assert_eq!(transcript.keep, 1);
+
+        let config = from_args(&["--config", &path, "--transcript-keep", "4"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.transcript.unwrap().keep, 4);
+    }
+
+    #[test]
+    fn listen_flags_replace_the_defaults() {
+        let config = from_args(&["--irc-listen", "127.0.0.1:6667", "--listen", "127.0.0.1:1"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.listen, ["127.0.0.1:1".parse().unwrap()]);
+        assert_eq!(config.irc_listen, ["127.0.0.1:6667".parse().unwrap()]);
+
+        let config = from_args(&["--listen", "127.0.0.1:1", "--listen", "127.0.0.1:2"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.listen.len(), 2);
+    }
+
+    #[test]
+    fn log_flags() {
+        let config = from_args(&["--log-level", "debug", "--log-format", "json"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            config.log.level.as_ref().map(|level| &level[..]),
+            Some("debug")
+        );
+        assert_eq!(config.log.format, Some(Format::Json));
+
+        let err = from_args(&["--log-level", "loud"]).err().unwrap();
+        assert!(err.contains("`--log-level`"), "{}", err);
+        assert!(from_args(&["--log-format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        let invalid: [&[&str]; 6] = [
+            &["--listen"],
+            &["--verbose", "yes"],
+            &["--max-clients", "many"],
+            &["--max-clients", "0"],
+            &["--flood-rate", "NaN"],
+            &["--credentials", "/nonexistent", "--credentials"],
+        ];
+        for args in invalid.iter() {
+            assert!(from_args(args).is_err(), "accepted {:?}", args);
+        }
+
+        let err = from_args(&["--config", "/nonexistent/line_chat.toml"])
+            .err()
+            .unwrap();
+        assert!(err.contains("failed to read"), "{}", err);
+    }
+}
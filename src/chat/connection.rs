@@ -0,0 +1,76 @@
+//! The sockets clients are connected through.
+
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio_tls::TlsStream;
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+
+use crate::codec::WebSocket;
+
+/// A connection to a client or a linked server, wrapped by `Lines` whatever
+/// its transport.
+pub(crate) enum Connection {
+    /// A plain TCP connection.
+    Tcp(TcpStream),
+
+    /// A TLS connection.
+    Tls(TlsStream<TcpStream>),
+
+    /// A WebSocket connection, with one text message per line, over a plain
+    /// or TLS connection.
+    WebSocket(Box<WebSocket<Connection>>),
+}
+
+impl Connection {
+    /// Address of the other end.
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Connection::Tcp(socket) => socket.peer_addr(),
+            Connection::Tls(socket) => socket.get_ref().get_ref().peer_addr(),
+            Connection::WebSocket(socket) => socket.get_ref().peer_addr(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(socket) => socket.read(buf),
+            Connection::Tls(socket) => socket.read(buf),
+            Connection::WebSocket(socket) => socket.read(buf),
+        }
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(socket) => socket.write(buf),
+            Connection::Tls(socket) => socket.write(buf),
+            Connection::WebSocket(socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(socket) => socket.flush(),
+            Connection::Tls(socket) => socket.flush(),
+            Connection::WebSocket(socket) => socket.flush(),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            Connection::Tcp(socket) => AsyncWrite::shutdown(socket),
+            Connection::Tls(socket) => socket.shutdown(),
+            Connection::WebSocket(socket) => socket.shutdown(),
+        }
+    }
+}
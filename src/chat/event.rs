@@ -0,0 +1,106 @@
+//! What peers tell each other.
+
+use serde::{Deserialize, Serialize};
+
+use std::str::FromStr;
+
+/// Something that happened in the chat, queued for the peers that should see
+/// it.
+///
+/// Events are kept structured until they reach the recipient, which renders
+/// them in the protocol of its own connection. This is what lets telnet and
+/// IRC clients share the same rooms. Each event is wrapped in an `Arc`, so
+/// that it is shared between the queues of all its recipients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Event {
+    /// A message to a room.
+    Message {
+        room: String,
+        from: String,
+        text: String,
+
+        /// Set for `/me` actions.
+        action: bool,
+    },
+
+    /// A message to a single client.
+    Private {
+        from: String,
+        text: String,
+        action: bool,
+    },
+
+    /// A client entered a room the recipient is in.
+    Joined { room: String, name: String },
+
+    /// A client left a room the recipient is in.
+    Parted { room: String, name: String },
+
+    /// A client sharing a room with the recipient disconnected.
+    Quit { name: String, departure: Departure },
+
+    /// A client sharing a room with the recipient changed its name.
+    Renamed { old: String, new: String },
+
+    /// A notice from the server, such as being muted by an operator.
+    Notice { text: String },
+
+    /// The client is disconnected by the server, for example when an
+    /// operator kicks it. This is the last event it receives.
+    Disconnect { reason: String },
+}
+
+/// Why a client disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Departure {
+    /// The client quit, closed its connection or was disconnected by an
+    /// operator.
+    Quit,
+
+    /// The client didn't answer a keepalive ping.
+    Timeout,
+}
+
+/// Kinds of presence events that can be announced to the members of a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Announcement {
+    /// A client entered a room, including the room it starts in.
+    Join,
+
+    /// A client left a room, or the chat.
+    Leave,
+
+    /// A client changed its name.
+    Nick,
+
+    /// A client was disconnected for not answering a keepalive ping.
+    Timeout,
+}
+
+impl Announcement {
+    /// Every kind of announcement.
+    pub const ALL: [Announcement; 4] = [
+        Announcement::Join,
+        Announcement::Leave,
+        Announcement::Nick,
+        Announcement::Timeout,
+    ];
+}
+
+impl FromStr for Announcement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Announcement, String> {
+        match s {
+            "join" => Ok(Announcement::Join),
+            "leave" => Ok(Announcement::Leave),
+            "nick" => Ok(Announcement::Nick),
+            "timeout" => Ok(Announcement::Timeout),
+            _ => Err(format!(
+                "unknown announcement `{}`, expected join, leave, nick or timeout",
+                s
+            )),
+        }
+    }
+}
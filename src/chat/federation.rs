@@ -0,0 +1,976 @@
+//! Links between servers, so that their clients share the same rooms.
+//!
+//! Linked servers exchange JSON lines over TCP, encrypted with TLS if `tls`
+//! is set. The connecting side starts with a `hello` line giving its name
+//! and the shared secret; the other side checks it and answers with its own
+//! `hello`, without the secret. Then each side relays what its own clients
+//! do: messages, private messages, joins, leaves, quits and renames, plus a
+//! `roster` of its clients now and then. Every relayed line carries the name
+//! of the server it started from and a random id. A server forwards the lines
+//! it receives to its other links, and remembers the ids it has seen so that
+//! a line coming back around a loop of links is dropped.
+//!
+//! Clients of other servers appear as `name@server`.
+
+use futures::future::{self, Either, Loop};
+use serde::{Deserialize, Serialize};
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio::timer::{Delay, Interval};
+use tokio_tls::{TlsAcceptor, TlsConnector};
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::auth::constant_time_eq;
+use super::commands::validate_room;
+use super::connection::Connection;
+use super::event::{Announcement, Departure, Event};
+use super::peer::validate_name;
+use super::queue::{channel, Overflow, QueueConfig, Rx, Tx};
+use super::server::keep_accepting;
+use super::state::Shared;
+use crate::codec::Lines;
+use crate::log::Context;
+use crate::tls;
+use crate::{debug, error, info, warn};
+
+/// How often a server sends the roster of its clients to the others.
+const ROSTER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the roster of a server is kept without being refreshed. A link
+/// that stays silent this long is closed as well.
+const ROSTER_EXPIRY: Duration = Duration::from_secs(3 * 30);
+
+/// Number of relayed line ids remembered to break loops.
+const SEEN_SIZE: usize = 16 * 1024;
+
+/// Number of lines waiting to be sent on a link. A link that falls this far
+/// behind is closed, and reconnected.
+const LINK_QUEUE: usize = 4096;
+
+/// Longest line accepted from a linked server. Rosters can be long.
+const LINK_MAX_LINE: usize = 1024 * 1024;
+
+/// Maximum number of lines a link reads or writes before yielding to other
+/// tasks.
+const LINK_LINES_PER_TICK: usize = 64;
+
+/// Data a link stages in its write buffer before leaving lines on its queue.
+const LINK_WRITE_BUFFER: usize = 256 * 1024;
+
+/// How long to wait before the first attempt to reconnect a link.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Links between this server and others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationConfig {
+    /// Name of this server, unique among the linked servers. The other
+    /// servers show its clients as `name@server`.
+    pub name: String,
+
+    /// Addresses to accept links from other servers on.
+    pub listen: Vec<SocketAddr>,
+
+    /// Servers to link to, as `host:port`. Only one side of a link should
+    /// list the other.
+    pub links: Vec<String>,
+
+    /// Secret that linked servers must share. Without one, any server that
+    /// can reach `listen` may link. Only the connecting side sends it, in
+    /// the clear unless the links use TLS. It is left out when the settings
+    /// are printed.
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+
+    /// Longest wait between two attempts to reconnect a link, in seconds.
+    pub max_backoff: u64,
+
+    /// Links are encrypted, in both directions, if this is set. The servers
+    /// linked together must agree on it.
+    pub tls: Option<LinkTlsConfig>,
+}
+
+/// Encryption of the links between servers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkTlsConfig {
+    /// The certificate chain presented on `listen`, PEM encoded, starting
+    /// with the server's own certificate.
+    pub cert: PathBuf,
+
+    /// The private key of the certificate, PEM encoded in PKCS #8.
+    pub key: PathBuf,
+
+    /// A PEM encoded certificate trusted to sign those of the servers in
+    /// `links`, besides the system's. Their certificates must match the host
+    /// they are listed with.
+    pub ca: Option<PathBuf>,
+}
+
+/// The federation side of the shared state.
+pub(crate) struct Federation {
+    /// Name of this server.
+    pub(crate) name: String,
+
+    secret: Option<String>,
+
+    /// Established links, by id.
+    links: HashMap<usize, Neighbour>,
+
+    /// Id of the next link.
+    next_link: usize,
+
+    /// Ids of the lines relayed recently, oldest first.
+    seen: HashSet<u64>,
+    seen_order: VecDeque<u64>,
+
+    /// Clients of the other servers, by server name.
+    pub(crate) remote: HashMap<String, Roster>,
+
+    /// Longest message text accepted from other servers, the longest line
+    /// a local client may send.
+    max_text: usize,
+}
+
+/// A server linked directly to this one.
+struct Neighbour {
+    /// Name of the server at the other end.
+    server: String,
+
+    /// Lines waiting to be sent to it.
+    tx: Tx<Arc<String>>,
+}
+
+/// The clients of another server.
+pub(crate) struct Roster {
+    /// The link the server's lines arrive through.
+    via: usize,
+
+    /// When the roster was last received in full.
+    updated: Instant,
+
+    /// Rooms and activity of each client, by name.
+    pub(crate) users: HashMap<String, RemoteUser>,
+}
+
+/// A client of another server.
+pub(crate) struct RemoteUser {
+    pub(crate) rooms: BTreeSet<String>,
+
+    /// When the client last sent a message, as far as this server knows.
+    pub(crate) active: Instant,
+}
+
+/// A line exchanged between servers.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Frame {
+    /// The first line sent by both sides: by the connecting side with the
+    /// secret, then by the other side, without it, once the first was
+    /// accepted.
+    Hello {
+        server: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret: Option<String>,
+    },
+
+    /// Something a client of `origin` did, forwarded by every server that
+    /// hasn't seen `id` yet.
+    Relay { id: u64, origin: String, body: Body },
+}
+
+/// What a relayed line is about. Names are those of clients of the origin
+/// server.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Body {
+    Message {
+        room: String,
+        from: String,
+        text: String,
+        action: bool,
+    },
+
+    /// A private message to `to`, a client of any other server.
+    Private {
+        from: String,
+        to: String,
+        text: String,
+        action: bool,
+    },
+
+    Join {
+        room: String,
+        name: String,
+    },
+
+    Leave {
+        room: String,
+        name: String,
+    },
+
+    Quit {
+        name: String,
+        timeout: bool,
+    },
+
+    Nick {
+        old: String,
+        new: String,
+    },
+
+    /// Every client of the origin server, replacing what was known of them.
+    Roster {
+        users: Vec<Member>,
+    },
+}
+
+/// A client listed in a roster.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Member {
+    name: String,
+    rooms: Vec<String>,
+
+    /// Seconds since the client last sent a message.
+    idle: u64,
+}
+
+impl Body {
+    /// Check the names, rooms and texts of a relayed line against the rules
+    /// local clients follow, texts being at most `max_text` bytes long.
+    /// Other servers aren't trusted to have done it.
+    fn validate(&self, max_text: usize) -> Result<(), String> {
+        match self {
+            Body::Message {
+                room, from, text, ..
+            } => {
+                check_room(room)?;
+                check_name(from)?;
+                check_text(text, max_text)
+            }
+            Body::Private { from, to, text, .. } => {
+                check_name(from)?;
+                check_text(text, max_text)?;
+                let (to, server) = to
+                    .rsplit_once('@')
+                    .ok_or_else(|| format!("invalid recipient {:?}", to))?;
+                check_name(to)?;
+                check_name(server)
+            }
+            Body::Join { room, name } | Body::Leave { room, name } => {
+                check_room(room)?;
+                check_name(name)
+            }
+            Body::Quit { name, .. } => check_name(name),
+            Body::Nick { old, new } => {
+                check_name(old)?;
+                check_name(new)
+            }
+            Body::Roster { users } => users.iter().try_for_each(|member| {
+                check_name(&member.name)?;
+                member.rooms.iter().try_for_each(|room| check_room(room))
+            }),
+        }
+    }
+}
+
+/// Check a name given by another server, which must be valid as it is.
+fn check_name(name: &str) -> Result<(), String> {
+    match validate_name(name.as_bytes()) {
+        Ok(ref valid) if valid == name => Ok(()),
+        Ok(_) => Err(format!("invalid name {:?}", name)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check the length of a message relayed by another server.
+fn check_text(text: &str, max_text: usize) -> Result<(), String> {
+    if text.len() > max_text {
+        return Err(format!("message longer than {} bytes", max_text));
+    }
+    Ok(())
+}
+
+/// Check a room name given by another server.
+fn check_room(room: &str) -> Result<(), String> {
+    if room.is_empty() {
+        return Err("empty room name".to_string());
+    }
+    validate_room(room)
+}
+
+impl Default for FederationConfig {
+    fn default() -> FederationConfig {
+        FederationConfig {
+            name: String::new(),
+            listen: Vec::new(),
+            links: Vec::new(),
+            secret: None,
+            max_backoff: 60,
+            tls: None,
+        }
+    }
+}
+
+impl Federation {
+    /// Links for the server named in `config`, whose clients may send lines
+    /// of up to `max_line_length` bytes.
+    pub(crate) fn new(config: &FederationConfig, max_line_length: usize) -> Federation {
+        Federation {
+            name: config.name.clone(),
+            secret: config.secret.clone(),
+            links: HashMap::new(),
+            next_link: 0,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            remote: HashMap::new(),
+            max_text: max_line_length,
+        }
+    }
+
+    /// Remember the id of a relayed line. Returns `false` if it was already
+    /// seen.
+    fn see(&mut self, id: u64) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.seen_order.push_back(id);
+        if self.seen_order.len() > SEEN_SIZE {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Send something a local client did to every linked server.
+    pub(crate) fn relay(&mut self, body: Body) {
+        if self.links.is_empty() {
+            return;
+        }
+
+        let id = rand::random();
+        self.see(id);
+        let line = encode(&Frame::Relay {
+            id,
+            origin: self.name.clone(),
+            body,
+        });
+        for link in self.links.values() {
+            link.tx.send(line.clone());
+        }
+    }
+
+    /// Relay a private message to `to`, written `name@server`.
+    pub(crate) fn private(
+        &mut self,
+        from: &str,
+        to: &str,
+        text: &str,
+        action: bool,
+    ) -> Result<String, String> {
+        let unknown = || format!("no one is called {}", to);
+
+        let (name, server) = to.rsplit_once('@').ok_or_else(unknown)?;
+        let roster = self.remote.get(server).ok_or_else(unknown)?;
+
+        // Names are unique regardless of case, on every server.
+        let name = roster
+            .users
+            .keys()
+            .find(|user| user.eq_ignore_ascii_case(name))
+            .ok_or_else(unknown)?
+            .clone();
+
+        let tagged = format!("{}@{}", name, server);
+        self.relay(Body::Private {
+            from: from.to_string(),
+            to: tagged.clone(),
+            text: text.to_string(),
+            action,
+        });
+        Ok(tagged)
+    }
+
+    /// Add a link to `server`, returning its id.
+    fn register(&mut self, server: &str, tx: Tx<Arc<String>>) -> Result<usize, String> {
+        if server == self.name {
+            return Err("the other server has the same name as this one".to_string());
+        }
+        if self.links.values().any(|link| link.server == server) {
+            return Err(format!("already linked to {}", server));
+        }
+
+        let id = self.next_link;
+        self.next_link += 1;
+        self.links.insert(
+            id,
+            Neighbour {
+                server: server.to_string(),
+                tx,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Remove a closed link, and forget the clients of the servers that were
+    /// reached through it.
+    fn unregister(&mut self, id: usize) {
+        self.links.remove(&id);
+        self.remote.retain(|_, roster| roster.via != id);
+    }
+
+    /// Names of the servers linked directly to this one, sorted.
+    pub(crate) fn neighbours(&self) -> Vec<&str> {
+        let mut servers: Vec<&str> = self.links.values().map(|link| &link.server[..]).collect();
+        servers.sort_unstable();
+        servers
+    }
+
+    /// The clients of other servers in `room`, with when they last sent a
+    /// message, for `/who`.
+    pub(crate) fn members(&self, room: &str) -> Vec<(String, Instant)> {
+        let mut members = Vec::new();
+        for (server, roster) in &self.remote {
+            for (name, user) in &roster.users {
+                if user.rooms.contains(room) {
+                    members.push((format!("{}@{}", name, server), user.active));
+                }
+            }
+        }
+        members
+    }
+}
+
+/// Serialize a line for a link.
+fn encode(frame: &Frame) -> Arc<String> {
+    let mut line = serde_json::to_string(frame).expect("frames always serialize");
+    line.push('\n');
+    Arc::new(line)
+}
+
+/// The roster of the local clients.
+fn roster(state: &Shared) -> Body {
+    let mut users: HashMap<_, _> = state
+        .peers
+        .iter()
+        .map(|(addr, client)| {
+            let member = Member {
+                name: client.name.clone(),
+                rooms: Vec::new(),
+                idle: client.active.elapsed().as_secs(),
+            };
+            (*addr, member)
+        })
+        .collect();
+
+    for (name, room) in &state.rooms {
+        for addr in &room.members {
+            if let Some(member) = users.get_mut(addr) {
+                member.rooms.push(name.clone());
+            }
+        }
+    }
+
+    Body::Roster {
+        users: users.into_values().collect(),
+    }
+}
+
+/// Handle a line received on the link `link`.
+fn receive(state: &mut Shared, link: usize, line: &[u8]) -> Result<(), String> {
+    let (id, origin, body) = match serde_json::from_slice(line) {
+        Ok(Frame::Relay { id, origin, body }) => (id, origin, body),
+        Ok(Frame::Hello { .. }) => return Err("unexpected hello".to_string()),
+        Err(e) => return Err(format!("invalid line: {}", e)),
+    };
+
+    let federation = match state.federation {
+        Some(ref mut federation) => federation,
+        None => return Ok(()),
+    };
+
+    if !federation.see(id) || origin == federation.name {
+        return Ok(());
+    }
+
+    check_name(&origin)
+        .and_then(|_| body.validate(federation.max_text))
+        .map_err(|e| format!("invalid line from {}: {}", origin, e))?;
+
+    // Pass the line on, as received, to the other links before acting on
+    // it.
+    let mut forward = String::from_utf8_lossy(line).into_owned();
+    forward.push('\n');
+    let forward = Arc::new(forward);
+    for (other, neighbour) in &federation.links {
+        if *other != link {
+            neighbour.tx.send(forward.clone());
+        }
+    }
+
+    apply(state, link, &origin, body);
+    Ok(())
+}
+
+/// Act on something a client of `origin` did.
+fn apply(state: &mut Shared, link: usize, origin: &str, body: Body) {
+    let tag = |name: &str| format!("{}@{}", name, origin);
+
+    let federation = state.federation.as_mut().expect("federation is enabled");
+    let roster = federation
+        .remote
+        .entry(origin.to_string())
+        .or_insert_with(|| Roster {
+            via: link,
+            updated: Instant::now(),
+            users: HashMap::new(),
+        });
+
+    match body {
+        Body::Message {
+            room,
+            from,
+            text,
+            action,
+        } => {
+            if let Some(user) = roster.users.get_mut(&from) {
+                user.active = Instant::now();
+            }
+            state.deliver(&room, &tag(&from), &text, action);
+        }
+        Body::Private {
+            from,
+            to,
+            text,
+            action,
+        } => {
+            // Only the recipient's server delivers the message.
+            let local = match to.rsplit_once('@') {
+                Some((name, server)) if server == federation.name => name,
+                _ => return,
+            };
+            if let Some(&addr) = state.names.get(&local.to_lowercase()) {
+                state.private(addr, &tag(&from), &text, action);
+            }
+        }
+        Body::Join { room, name } => {
+            roster
+                .users
+                .entry(name.clone())
+                .or_insert_with(|| RemoteUser {
+                    rooms: BTreeSet::new(),
+                    active: Instant::now(),
+                })
+                .rooms
+                .insert(room.clone());
+
+            let rooms = Some(room.clone()).into_iter().collect();
+            let event = Event::Joined {
+                room,
+                name: tag(&name),
+            };
+            state.notify_rooms(&rooms, Announcement::Join, event);
+        }
+        Body::Leave { room, name } => {
+            if let Some(user) = roster.users.get_mut(&name) {
+                user.rooms.remove(&room);
+            }
+
+            let rooms = Some(room.clone()).into_iter().collect();
+            let event = Event::Parted {
+                room,
+                name: tag(&name),
+            };
+            state.notify_rooms(&rooms, Announcement::Leave, event);
+        }
+        Body::Quit { name, timeout } => {
+            let rooms = match roster.users.remove(&name) {
+                Some(user) => user.rooms,
+                None => return,
+            };
+
+            let (departure, announcement) = if timeout {
+                (Departure::Timeout, Announcement::Timeout)
+            } else {
+                (Departure::Quit, Announcement::Leave)
+            };
+            let event = Event::Quit {
+                name: tag(&name),
+                departure,
+            };
+            state.notify_rooms(&rooms, announcement, event);
+        }
+        Body::Nick { old, new } => {
+            let user = match roster.users.remove(&old) {
+                Some(user) => user,
+                None => return,
+            };
+            let rooms = user.rooms.clone();
+            roster.users.insert(new.clone(), user);
+
+            let event = Event::Renamed {
+                old: tag(&old),
+                new: tag(&new),
+            };
+            state.notify_rooms(&rooms, Announcement::Nick, event);
+        }
+        Body::Roster { users } => {
+            let now = Instant::now();
+            roster.via = link;
+            roster.updated = now;
+            roster.users = users
+                .into_iter()
+                .map(|member| {
+                    let user = RemoteUser {
+                        rooms: member.rooms.into_iter().collect(),
+                        active: now
+                            .checked_sub(Duration::from_secs(member.idle))
+                            .unwrap_or(now),
+                    };
+                    (member.name, user)
+                })
+                .collect();
+        }
+    }
+}
+
+/// A connection to another server, in either direction.
+///
+/// Resolves once the connection is closed, to whether the other server's
+/// `hello` was accepted.
+struct Link {
+    lines: Lines<Connection>,
+
+    /// Fields of the link's events in the logs: the other server's address,
+    /// and its name once it has sent its `hello`.
+    log: Context,
+
+    state: Arc<Mutex<Shared>>,
+
+    /// Whether this server connected to the other, and so sent its `hello`
+    /// first.
+    outbound: bool,
+
+    /// The id of the link and the lines to send on it, once the other
+    /// server's `hello` was accepted.
+    link: Option<(usize, Rx<Arc<String>>)>,
+
+    /// Fires once the other server has been silent for `ROSTER_EXPIRY`.
+    silence: Delay,
+}
+
+impl Link {
+    /// Start a link on a new connection. A server that connected to the other
+    /// sends its `hello` right away; one that accepted the connection waits
+    /// for the other's.
+    fn new(connection: Connection, state: Arc<Mutex<Shared>>, outbound: bool) -> io::Result<Link> {
+        let addr = connection.peer_addr()?;
+        let mut lines = Lines::new(connection).max_line_length(LINK_MAX_LINE);
+
+        if outbound {
+            let hello = match state.lock().unwrap().federation {
+                Some(ref federation) => Frame::Hello {
+                    server: federation.name.clone(),
+                    secret: federation.secret.clone(),
+                },
+                None => return Err(io::Error::other("federation is disabled")),
+            };
+            lines.buffer(encode(&hello).as_bytes());
+        }
+
+        Ok(Link {
+            lines,
+            log: Context::new().with("link", addr),
+            state,
+            outbound,
+            link: None,
+            silence: Delay::new(Instant::now() + ROSTER_EXPIRY),
+        })
+    }
+
+    /// Check the other server's `hello`, answer it if it came first, and
+    /// register the link.
+    fn hello(&mut self, line: &[u8]) -> Result<(usize, Rx<Arc<String>>), String> {
+        let (server, secret) = match serde_json::from_slice(line) {
+            Ok(Frame::Hello { server, secret }) => (server, secret),
+            Ok(_) => return Err("expected hello".to_string()),
+            Err(e) => return Err(format!("invalid hello: {}", e)),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let body = roster(&state);
+        let federation = state.federation.as_mut().expect("federation is enabled");
+
+        // The server that accepted the connection never sends the secret:
+        // it is only given to a server that proved it knows it.
+        if !self.outbound {
+            if let Some(ref expected) = federation.secret {
+                let secret = secret.unwrap_or_default();
+                if !constant_time_eq(secret.as_bytes(), expected.as_bytes()) {
+                    return Err(format!("{} sent the wrong secret", server));
+                }
+            }
+        }
+
+        let (tx, rx) = channel(QueueConfig {
+            capacity: LINK_QUEUE,
+            policy: Overflow::Disconnect,
+        });
+        let id = federation.register(&server, tx)?;
+
+        if !self.outbound {
+            let hello = encode(&Frame::Hello {
+                server: federation.name.clone(),
+                secret: None,
+            });
+            self.lines.buffer(hello.as_bytes());
+        }
+
+        // Introduce our clients to the new link only; the others already
+        // know them.
+        let roster_id = rand::random();
+        federation.see(roster_id);
+        let roster = encode(&Frame::Relay {
+            id: roster_id,
+            origin: federation.name.clone(),
+            body,
+        });
+        self.lines.buffer(roster.as_bytes());
+
+        self.log.set("server", &server);
+        info!(context: self.log, "linked");
+        Ok((id, rx))
+    }
+}
+
+impl Future for Link {
+    type Item = bool;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<bool, io::Error> {
+        if self.silence.poll().map_err(io::Error::other)?.is_ready() {
+            warn!(context: self.log, "link timed out");
+            return Ok(Async::Ready(self.link.is_some()));
+        }
+
+        if self.link.is_none() {
+            self.lines.poll_flush()?;
+
+            let line = match try_ready!(self.lines.poll()) {
+                Some(line) => line,
+                None => return Ok(Async::Ready(false)),
+            };
+            match self.hello(&line) {
+                Ok(link) => self.link = Some(link),
+                Err(e) => {
+                    warn!(context: self.log, "link refused", error = e);
+                    return Ok(Async::Ready(false));
+                }
+            }
+            self.silence.reset(Instant::now() + ROSTER_EXPIRY);
+        }
+
+        let (id, rx) = self.link.as_mut().unwrap();
+        let id = *id;
+
+        // Stage the lines queued for the other server. The queue fails once
+        // it overflowed, closing the link.
+        for i in 0..LINK_LINES_PER_TICK {
+            if self.lines.buffered_len() >= LINK_WRITE_BUFFER {
+                break;
+            }
+            match rx.poll()? {
+                Async::Ready(Some(line)) => self.lines.buffer(line.as_bytes()),
+                Async::Ready(None) | Async::NotReady => break,
+            }
+            if i + 1 == LINK_LINES_PER_TICK {
+                task::current().notify();
+            }
+        }
+        self.lines.poll_flush()?;
+
+        for i in 0..LINK_LINES_PER_TICK {
+            let line = match self.lines.poll()? {
+                Async::Ready(Some(line)) => line,
+                Async::Ready(None) => return Ok(Async::Ready(true)),
+                Async::NotReady => break,
+            };
+            self.silence.reset(Instant::now() + ROSTER_EXPIRY);
+
+            let mut state = self.state.lock().unwrap();
+            if let Err(e) = receive(&mut state, id, &line) {
+                warn!(context: self.log, "closing link", error = e);
+                return Ok(Async::Ready(true));
+            }
+
+            if i + 1 == LINK_LINES_PER_TICK {
+                task::current().notify();
+            }
+        }
+
+        // Poll the timer again after it was reset, so that the task is woken
+        // up at the new deadline.
+        self.silence.poll().map_err(io::Error::other)?;
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        if let Some((id, _)) = self.link {
+            let mut state = self.state.lock().unwrap();
+            if let Some(ref mut federation) = state.federation {
+                federation.unregister(id);
+            }
+            info!(context: self.log, "link closed");
+        }
+    }
+}
+
+/// Keep a link to the server at `addr` up, reconnecting with exponential
+/// backoff. The link is encrypted if `tls` is given, and the other server's
+/// certificate must then match the host of `addr`.
+fn connect(
+    addr: String,
+    max_backoff: Duration,
+    tls: Option<TlsConnector>,
+    state: Arc<Mutex<Shared>>,
+) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn(INITIAL_BACKOFF, move |backoff| {
+        let state = state.clone();
+        let addr = addr.clone();
+        let tls = tls.clone();
+        let host = addr
+            .rsplit_once(':')
+            .map_or(&addr[..], |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+
+        // Resolving blocks, but only once per attempt.
+        let socket = addr.to_socket_addrs().and_then(|mut addrs| {
+            addrs
+                .next()
+                .ok_or_else(|| io::Error::other("no address found"))
+        });
+
+        future::result(socket)
+            .and_then(|socket| TcpStream::connect(&socket))
+            .and_then(move |socket| match tls {
+                Some(tls) => Either::A(
+                    tls.connect(&host, socket)
+                        .map(Connection::Tls)
+                        .map_err(io::Error::other),
+                ),
+                None => Either::B(future::ok(Connection::Tcp(socket))),
+            })
+            .and_then(move |connection| Link::new(connection, state, true))
+            .flatten()
+            .then(move |result| {
+                let linked = match result {
+                    Ok(linked) => linked,
+                    Err(e) => {
+                        warn!("link failed", link = addr, error = e);
+                        false
+                    }
+                };
+
+                // A link that was up starts over with a short wait.
+                let wait = if linked { INITIAL_BACKOFF } else { backoff };
+                debug!("reconnecting", link = addr, wait = wait.as_secs());
+
+                Delay::new(Instant::now() + wait).then(move |_| {
+                    let next = (wait * 2).min(max_backoff);
+                    Ok(Loop::<(), _>::Continue(next))
+                })
+            })
+    })
+}
+
+/// Listen for and connect the links of `config`, on `runtime`.
+pub(crate) fn start(
+    config: &FederationConfig,
+    state: Arc<Mutex<Shared>>,
+    runtime: &mut Runtime,
+) -> io::Result<()> {
+    let acceptor: Option<TlsAcceptor> = match config.tls {
+        Some(ref tls) if !config.listen.is_empty() => Some(tls::acceptor(&tls.cert, &tls.key)?),
+        _ => None,
+    };
+    let connector = match config.tls {
+        Some(ref tls) => Some(tls::connector(tls.ca.as_deref())?),
+        None => None,
+    };
+
+    for addr in &config.listen {
+        let listener = TcpListener::bind(addr)?;
+        info!(
+            "accepting server links",
+            addr = addr,
+            tls = acceptor.is_some()
+        );
+
+        let errors = state.lock().unwrap().metrics.accept_errors.clone();
+        let state = state.clone();
+        let acceptor = acceptor.clone();
+        let server =
+            keep_accepting(listener.incoming(), addr.to_string(), errors).for_each(move |socket| {
+                // The TLS handshake comes first, if the links are encrypted.
+                let connection = match acceptor {
+                    Some(ref tls) => Either::A(
+                        tls.accept(socket)
+                            .map(Connection::Tls)
+                            .map_err(io::Error::other),
+                    ),
+                    None => Either::B(future::ok(Connection::Tcp(socket))),
+                };
+
+                let state = state.clone();
+                let link = connection
+                    .and_then(move |connection| Link::new(connection, state, false))
+                    .flatten()
+                    .map(|_| ())
+                    .map_err(|e| warn!("link error", error = e));
+                tokio::spawn(link);
+                Ok(())
+            });
+        runtime.spawn(server);
+    }
+
+    let max_backoff = Duration::from_secs(config.max_backoff);
+    for addr in &config.links {
+        runtime.spawn(connect(
+            addr.clone(),
+            max_backoff,
+            connector.clone(),
+            state.clone(),
+        ));
+    }
+
+    // Refresh what the other servers know of our clients, and forget the
+    // servers that stopped sending theirs.
+    let refresh = Interval::new(Instant::now() + ROSTER_INTERVAL, ROSTER_INTERVAL)
+        .map_err(|err| error!("timer error", error = err))
+        .for_each(move |_| {
+            let mut state = state.lock().unwrap();
+            let body = roster(&state);
+            if let Some(ref mut federation) = state.federation {
+                federation
+                    .remote
+                    .retain(|_, roster| roster.updated.elapsed() < ROSTER_EXPIRY);
+                federation.relay(body);
+            }
+            Ok(())
+        });
+    runtime.spawn(refresh);
+
+    Ok(())
+}
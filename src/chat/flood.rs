@@ -0,0 +1,199 @@
+//! Flood protection.
+
+use serde::{Deserialize, Serialize};
+
+use std::time::{Duration, Instant};
+
+/// How long a client has to stay within its rate for its past offences to be
+/// forgotten.
+const FLOOD_MEMORY: Duration = Duration::from_secs(10 * 60);
+
+/// Rate at which a client may send lines, and what happens when it doesn't
+/// keep to it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FloodConfig {
+    /// Number of lines a client may send at once.
+    pub burst: usize,
+
+    /// Number of lines a client may send per second, once its burst is used
+    /// up.
+    pub rate: f64,
+
+    /// Number of warnings a flooding client gets before being muted.
+    pub warnings: usize,
+
+    /// How long a flooding client is muted for, in seconds. A client that
+    /// floods again after that is disconnected.
+    pub mute: u64,
+}
+
+/// What to do with a line sent by a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// Handle the line.
+    Allow,
+
+    /// Discard the line.
+    Drop,
+
+    /// Discard the line and warn the client.
+    Warn,
+
+    /// Discard the line and mute the client.
+    Mute,
+
+    /// Disconnect the client.
+    Disconnect,
+}
+
+/// Token bucket of a peer.
+///
+/// The bucket holds up to `burst` tokens and gains `rate` tokens a second.
+/// Every line the client sends takes a token, messages and commands alike.
+/// A line arriving at an empty bucket is dropped, and the first line dropped
+/// after an accepted one is an offence. Offences are answered with warnings,
+/// then a mute, then disconnection.
+pub(crate) struct Limiter {
+    config: FloodConfig,
+    tokens: f64,
+
+    /// When `tokens` was last refilled.
+    refilled: Instant,
+
+    /// Number of offences, forgotten after `FLOOD_MEMORY` without one.
+    offences: usize,
+
+    /// When the last offence happened.
+    offended: Instant,
+
+    /// Set while lines are being dropped, so a single flood only counts as
+    /// one offence.
+    flooding: bool,
+}
+
+impl Limiter {
+    /// Create a full bucket.
+    pub(crate) fn new(config: FloodConfig) -> Limiter {
+        let now = Instant::now();
+        Limiter {
+            config,
+            tokens: config.burst as f64,
+            refilled: now,
+            offences: 0,
+            offended: now,
+            flooding: false,
+        }
+    }
+
+    /// Take a token for a line received now.
+    pub(crate) fn check(&mut self) -> Verdict {
+        let now = Instant::now();
+
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.config.rate).min(self.config.burst as f64);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.flooding = false;
+            return Verdict::Allow;
+        }
+
+        if self.flooding {
+            return Verdict::Drop;
+        }
+        self.flooding = true;
+
+        if now.duration_since(self.offended) >= FLOOD_MEMORY {
+            self.offences = 0;
+        }
+        self.offences += 1;
+        self.offended = now;
+
+        if self.offences <= self.config.warnings {
+            Verdict::Warn
+        } else if self.offences == self.config.warnings + 1 {
+            Verdict::Mute
+        } else {
+            Verdict::Disconnect
+        }
+    }
+}
+
+impl Default for FloodConfig {
+    fn default() -> FloodConfig {
+        FloodConfig {
+            burst: 10,
+            rate: 2.0,
+            warnings: 2,
+            mute: 60,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(burst: usize, warnings: usize) -> Limiter {
+        Limiter::new(FloodConfig {
+            burst,
+            rate: 2.0,
+            warnings,
+            mute: 60,
+        })
+    }
+
+    #[test]
+    fn allows_a_burst() {
+        let mut limiter = limiter(3, 1);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(), Verdict::Warn);
+        assert_eq!(limiter.check(), Verdict::Drop);
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let mut limiter = limiter(3, 1);
+        for _ in 0..3 {
+            limiter.check();
+        }
+
+        // One second at 2 lines a second.
+        limiter.refilled -= Duration::from_secs(1);
+        assert_eq!(limiter.check(), Verdict::Allow);
+        assert_eq!(limiter.check(), Verdict::Allow);
+        assert_eq!(limiter.check(), Verdict::Warn);
+
+        // No more than the burst, however long the client waited.
+        limiter.config.rate = 1000.0;
+        limiter.refilled -= Duration::from_secs(1);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(), Verdict::Mute);
+    }
+
+    #[test]
+    fn escalates_offences() {
+        let mut limiter = limiter(1, 2);
+        let mut verdicts = Vec::new();
+        for _ in 0..4 {
+            limiter.tokens = 0.0;
+            limiter.flooding = false;
+            verdicts.push(limiter.check());
+        }
+        assert_eq!(
+            verdicts,
+            [
+                Verdict::Warn,
+                Verdict::Warn,
+                Verdict::Mute,
+                Verdict::Disconnect
+            ]
+        );
+    }
+}
@@ -0,0 +1,516 @@
+//! IRC compatibility mode.
+//!
+//! Clients connecting to one of the `irc_listen` addresses speak the core of
+//! RFC 1459 / 2812 instead of the telnet protocol: `NICK`, `USER`, `JOIN`,
+//! `PART`, `PRIVMSG`, `NOTICE`, `PING`, `PONG` and `QUIT`, answered with the
+//! usual numeric replies. They share the same `Shared` state as telnet
+//! clients, so both see each other's messages. Room `lobby` is channel
+//! `#lobby`.
+
+use std::collections::BTreeSet;
+
+use super::auth::{Auth, AuthError};
+use super::commands::{validate_room, Flow};
+use super::connection::Connection;
+use super::event::{Departure, Event};
+use super::peer::{validate_name, Login, Peer};
+use super::state::{Shared, HISTORY_REPLAY};
+use crate::codec::Lines;
+
+/// Name the server uses as the prefix of its own messages.
+pub(crate) const SERVER_NAME: &str = "line_chat";
+
+/// A message sent by an IRC client.
+///
+/// Clients aren't supposed to send a prefix, so it is parsed and ignored.
+#[derive(Debug)]
+pub(crate) struct Message {
+    /// The command, uppercased.
+    pub(crate) command: String,
+
+    /// The parameters, with the trailing parameter (after ` :`) last.
+    pub(crate) params: Vec<String>,
+}
+
+/// Progress of a client through the `NICK` / `USER` registration.
+#[derive(Debug, Default)]
+pub(crate) struct Registration {
+    nick: Option<String>,
+    user: bool,
+
+    /// The password sent with `PASS`, checked once `NICK` and `USER` have
+    /// been received.
+    pass: Option<String>,
+}
+
+/// What the handshake should do after a line sent during registration.
+pub(crate) enum Step {
+    /// Wait for more lines.
+    Continue,
+
+    /// Both `NICK` and `USER` have been received, try to register the client
+    /// under this nickname.
+    Register(String),
+
+    /// The client sent `QUIT`.
+    Quit,
+}
+
+impl Message {
+    /// Parse a line, without its line ending. Returns `None` for an empty
+    /// line.
+    pub(crate) fn parse(line: &str) -> Option<Message> {
+        let mut rest = line.trim_start();
+
+        // Skip the prefix.
+        if rest.starts_with(':') {
+            rest = rest.split_once(' ')?.1.trim_start();
+        }
+
+        let (head, trailing) = match rest.find(" :") {
+            Some(i) => (&rest[..i], Some(&rest[i + 2..])),
+            None => (rest, None),
+        };
+
+        let mut words = head.split(' ').filter(|word| !word.is_empty());
+        let command = words.next()?.to_ascii_uppercase();
+        let mut params: Vec<String> = words.map(String::from).collect();
+        if let Some(trailing) = trailing {
+            params.push(trailing.to_string());
+        }
+
+        Some(Message { command, params })
+    }
+}
+
+impl Registration {
+    /// Handle a line sent before the client is registered.
+    pub(crate) fn handle(&mut self, lines: &mut Lines<Connection>, line: &[u8]) -> Step {
+        let line = String::from_utf8_lossy(line);
+        let message = match Message::parse(&line) {
+            Some(message) => message,
+            None => return Step::Continue,
+        };
+        let target = self.nick.as_deref().unwrap_or("*");
+
+        match &message.command[..] {
+            "NICK" => match message.params.first() {
+                Some(nick) => match validate_name(nick.as_bytes()) {
+                    Ok(nick) => self.nick = Some(nick),
+                    Err(_) => numeric(
+                        lines,
+                        "432",
+                        target,
+                        &format!("{} :Erroneous nickname", nick),
+                    ),
+                },
+                None => numeric(lines, "431", target, ":No nickname given"),
+            },
+            "USER" => {
+                if message.params.len() < 4 {
+                    numeric(lines, "461", target, "USER :Not enough parameters");
+                } else {
+                    self.user = true;
+                }
+            }
+            "PING" => pong(lines, &message),
+            "QUIT" => {
+                lines.buffer(b"ERROR :Closing link\r\n");
+                return Step::Quit;
+            }
+            // Capability negotiation isn't supported. Answering `CAP LS` with
+            // an empty list lets clients that start with it carry on.
+            "CAP" => {
+                if message.params.first().map(String::as_str) == Some("LS") {
+                    lines.buffer(format!(":{} CAP * LS :\r\n", SERVER_NAME).as_bytes());
+                }
+            }
+            "PASS" => match message.params.first() {
+                Some(pass) => self.pass = Some(pass.clone()),
+                None => numeric(lines, "461", target, "PASS :Not enough parameters"),
+            },
+            "PONG" => {}
+            _ => numeric(lines, "451", target, ":You have not registered"),
+        }
+
+        match self.nick {
+            Some(ref nick) if self.user => Step::Register(nick.clone()),
+            _ => Step::Continue,
+        }
+    }
+
+    /// Log in the nickname given to `Register`, asking for its password to
+    /// be checked if it belongs to an account.
+    pub(crate) fn login(&mut self, nick: String, auth: Option<&Auth>) -> Login {
+        match auth {
+            Some(auth) if auth.is_registered(&nick) => Login::Verify {
+                name: nick,
+                password: self.pass.take().unwrap_or_default(),
+            },
+            _ => Login::Accepted {
+                name: nick,
+                account: None,
+            },
+        }
+    }
+
+    /// Answer a client whose password has been checked.
+    ///
+    /// A wrong password is answered like a nickname in use, the client may
+    /// try again.
+    pub(crate) fn verified(
+        &mut self,
+        lines: &mut Lines<Connection>,
+        result: Result<(String, String), AuthError>,
+    ) -> Login {
+        match result {
+            Ok((nick, account)) => Login::Accepted {
+                name: nick,
+                account: Some(account),
+            },
+            Err(AuthError::Denied) => {
+                if let Some(nick) = self.nick.take() {
+                    numeric(lines, "464", &nick, ":Password incorrect");
+                }
+                Login::Continue
+            }
+            Err(AuthError::Blocked) => {
+                lines.buffer(b"ERROR :Too many failed attempts, try again later\r\n");
+                Login::Refused
+            }
+        }
+    }
+
+    /// The nickname given to `Register` is taken. The client has to send
+    /// another `NICK`.
+    pub(crate) fn taken(&mut self, lines: &mut Lines<Connection>) {
+        if let Some(nick) = self.nick.take() {
+            numeric(
+                lines,
+                "433",
+                "*",
+                &format!("{} :Nickname is already in use", nick),
+            );
+        }
+    }
+}
+
+/// Greet a client that has just been registered.
+pub(crate) fn welcome(lines: &mut Lines<Connection>, nick: &str) {
+    numeric(
+        lines,
+        "001",
+        nick,
+        &format!(":Welcome to the {} IRC network {}", SERVER_NAME, nick),
+    );
+    numeric(
+        lines,
+        "002",
+        nick,
+        &format!(":Your host is {}, running line_chat", SERVER_NAME),
+    );
+    numeric(lines, "003", nick, ":This server has no creation date");
+    numeric(
+        lines,
+        "004",
+        nick,
+        &format!("{} line_chat o o", SERVER_NAME),
+    );
+    numeric(lines, "422", nick, ":MOTD File is missing");
+}
+
+/// Handle a line sent by a registered client.
+pub(crate) fn handle(peer: &mut Peer, line: &[u8]) -> Flow {
+    let line = String::from_utf8_lossy(line);
+    let message = match Message::parse(&line) {
+        Some(message) => message,
+        None => return Flow::Continue,
+    };
+
+    let state = peer.state.clone();
+    let mut state = state.lock().unwrap();
+
+    match &message.command[..] {
+        "PING" => pong(&mut peer.lines, &message),
+        "NICK" => nick(peer, &mut state, &message),
+        "JOIN" => join(peer, &mut state, &message),
+        "PART" => part(peer, &mut state, &message),
+        "PRIVMSG" => privmsg(peer, &mut state, &message, false),
+        "NOTICE" => privmsg(peer, &mut state, &message, true),
+        "QUIT" => {
+            peer.lines.buffer(b"ERROR :Closing link\r\n");
+            return Flow::Quit;
+        }
+        "USER" | "PASS" => reply(peer, "462", ":You may not reregister"),
+        "PONG" | "CAP" => {}
+        command => reply(peer, "421", &format!("{} :Unknown command", command)),
+    }
+
+    Flow::Continue
+}
+
+/// Write an event as an IRC message for the client `nick`.
+pub(crate) fn render(lines: &mut Lines<Connection>, nick: &str, event: &Event) {
+    let (from, target, text, action) = match event {
+        Event::Message {
+            room,
+            from,
+            text,
+            action,
+        } => (from, format!("#{}", room), text, *action),
+        Event::Private { from, text, action } => (from, nick.to_string(), text, *action),
+        Event::Joined { room, name } => {
+            lines.buffer(format!("{} JOIN #{}\r\n", source(name), room).as_bytes());
+            return;
+        }
+        Event::Parted { room, name } => {
+            lines.buffer(format!("{} PART #{}\r\n", source(name), room).as_bytes());
+            return;
+        }
+        Event::Quit { name, departure } => {
+            let reason = match departure {
+                Departure::Quit => "Quit",
+                Departure::Timeout => "Ping timeout",
+            };
+            lines.buffer(format!("{} QUIT :{}\r\n", source(name), reason).as_bytes());
+            return;
+        }
+        Event::Renamed { old, new } => {
+            lines.buffer(format!("{} NICK :{}\r\n", source(old), new).as_bytes());
+            return;
+        }
+        Event::Notice { text } => {
+            let line = format!(":{} NOTICE {} :{}\r\n", SERVER_NAME, nick, text);
+            lines.buffer(line.as_bytes());
+            return;
+        }
+        Event::Disconnect { reason } => {
+            lines.buffer(format!("ERROR :{}\r\n", reason).as_bytes());
+            return;
+        }
+    };
+
+    let line = if action {
+        format!(
+            "{} PRIVMSG {} :\x01ACTION {}\x01\r\n",
+            source(from),
+            target,
+            text
+        )
+    } else {
+        format!("{} PRIVMSG {} :{}\r\n", source(from), target, text)
+    };
+    lines.buffer(line.as_bytes());
+}
+
+/// The prefix of messages sent on behalf of the client `nick`.
+fn source(nick: &str) -> String {
+    format!(":{}!{}@{}", nick, nick, SERVER_NAME)
+}
+
+/// Write a numeric reply.
+fn numeric(lines: &mut Lines<Connection>, code: &str, target: &str, params: &str) {
+    lines.buffer(format!(":{} {} {} {}\r\n", SERVER_NAME, code, target, params).as_bytes());
+}
+
+/// Write a numeric reply to a registered client.
+fn reply(peer: &mut Peer, code: &str, params: &str) {
+    numeric(&mut peer.lines, code, &peer.name, params);
+}
+
+/// Ping a client that has been silent for a while. It is expected to answer
+/// with a `PONG`.
+pub(crate) fn ping(lines: &mut Lines<Connection>) {
+    lines.buffer(format!("PING :{}\r\n", SERVER_NAME).as_bytes());
+}
+
+/// Answer a `PING`.
+fn pong(lines: &mut Lines<Connection>, message: &Message) {
+    let token = message.params.first().map(String::as_str).unwrap_or("");
+    lines.buffer(format!(":{} PONG {} :{}\r\n", SERVER_NAME, SERVER_NAME, token).as_bytes());
+}
+
+/// `NICK <nickname>`
+fn nick(peer: &mut Peer, state: &mut Shared, message: &Message) {
+    let nick = match message.params.first() {
+        Some(nick) => nick,
+        None => return reply(peer, "431", ":No nickname given"),
+    };
+
+    let nick = match validate_name(nick.as_bytes()) {
+        Ok(nick) => nick,
+        Err(_) => return reply(peer, "432", &format!("{} :Erroneous nickname", nick)),
+    };
+
+    if state.rename(peer.addr, &nick).is_err() {
+        return reply(
+            peer,
+            "433",
+            &format!("{} :Nickname is already in use", nick),
+        );
+    }
+
+    let line = format!("{} NICK :{}\r\n", source(&peer.name), nick);
+    peer.lines.buffer(line.as_bytes());
+    peer.renamed(nick);
+}
+
+/// `JOIN <channel>{,<channel>}`, or `JOIN 0` to leave every channel.
+fn join(peer: &mut Peer, state: &mut Shared, message: &Message) {
+    let channels = match message.params.first() {
+        Some(channels) => channels,
+        None => return reply(peer, "461", "JOIN :Not enough parameters"),
+    };
+
+    if channels == "0" {
+        for room in peer.rooms.clone() {
+            leave(peer, state, &room, "");
+        }
+        return;
+    }
+
+    for channel in channels.split(',') {
+        let room = match room_of(channel) {
+            Some(room) => room,
+            None => {
+                reply(peer, "403", &format!("{} :No such channel", channel));
+                continue;
+            }
+        };
+
+        if !peer.rooms.insert(room.to_string()) {
+            continue;
+        }
+        state.join(room, peer.addr);
+
+        let line = format!("{} JOIN #{}\r\n", source(&peer.name), room);
+        peer.lines.buffer(line.as_bytes());
+
+        let mut names: Vec<&str> = state.rooms[room]
+            .members
+            .iter()
+            .filter_map(|addr| state.peers.get(addr))
+            .map(|client| client.name.as_str())
+            .collect();
+        names.sort();
+        let names = names.join(" ");
+
+        reply(peer, "353", &format!("= #{} :{}", room, names));
+        reply(peer, "366", &format!("#{} :End of /NAMES list", room));
+
+        peer.replay(&state.history(room, HISTORY_REPLAY));
+    }
+}
+
+/// `PART <channel>{,<channel>} [<reason>]`
+fn part(peer: &mut Peer, state: &mut Shared, message: &Message) {
+    let channels = match message.params.first() {
+        Some(channels) => channels,
+        None => return reply(peer, "461", "PART :Not enough parameters"),
+    };
+    let reason = message.params.get(1).map(String::as_str).unwrap_or("");
+
+    for channel in channels.split(',') {
+        match room_of(channel) {
+            Some(room) if peer.rooms.contains(room) => leave(peer, state, room, reason),
+            _ => reply(
+                peer,
+                "442",
+                &format!("{} :You're not on that channel", channel),
+            ),
+        }
+    }
+}
+
+/// Leave a room the peer is a member of.
+fn leave(peer: &mut Peer, state: &mut Shared, room: &str, reason: &str) {
+    peer.rooms.remove(room);
+    state.leave(room, peer.addr);
+
+    let line = format!("{} PART #{} :{}\r\n", source(&peer.name), room, reason);
+    peer.lines.buffer(line.as_bytes());
+}
+
+/// `PRIVMSG <target>{,<target>} <text>`, also used for `NOTICE`.
+///
+/// Errors are not reported for `NOTICE`, as the RFC requires.
+fn privmsg(peer: &mut Peer, state: &mut Shared, message: &Message, notice: bool) {
+    let (targets, text) = match (message.params.first(), message.params.get(1)) {
+        (Some(targets), Some(text)) if !text.is_empty() => (targets, text),
+        (None, _) if !notice => {
+            return reply(
+                peer,
+                "411",
+                &format!(":No recipient given ({})", message.command),
+            )
+        }
+        (Some(_), _) if !notice => return reply(peer, "412", ":No text to send"),
+        _ => return,
+    };
+
+    if let Err(e) = state.check_muted(peer.addr) {
+        if !notice {
+            let line = format!(":{} NOTICE {} :{}\r\n", SERVER_NAME, peer.name, e);
+            peer.lines.buffer(line.as_bytes());
+        }
+        return;
+    }
+
+    // CTCP ACTION is what clients send for `/me`.
+    let (text, action) = match text
+        .strip_prefix("\x01ACTION ")
+        .and_then(|text| text.strip_suffix('\x01'))
+    {
+        Some(text) => (text, true),
+        None => (&text[..], false),
+    };
+
+    for target in targets.split(',') {
+        if let Some(room) = room_of(target) {
+            if !peer.rooms.contains(room) {
+                if !notice {
+                    reply(peer, "404", &format!("{} :Cannot send to channel", target));
+                }
+                continue;
+            }
+
+            let mut rooms = BTreeSet::new();
+            rooms.insert(room.to_string());
+            state.broadcast(peer.addr, &rooms, text, action);
+            continue;
+        }
+
+        // Clients of linked servers are addressed as `name@server`.
+        if target.contains('@') {
+            if state
+                .relay_private(peer.addr, target, text, action)
+                .is_err()
+                && !notice
+            {
+                reply(peer, "401", &format!("{} :No such nick/channel", target));
+            }
+            continue;
+        }
+
+        // Nicknames are matched exactly, unlike the prefixes `/msg` accepts.
+        match state.names.get(&target.to_lowercase()).copied() {
+            Some(addr) => {
+                state.private(addr, &peer.name, text, action);
+                state.touch(peer.addr);
+            }
+            None if !notice => {
+                reply(peer, "401", &format!("{} :No such nick/channel", target));
+            }
+            None => {}
+        }
+    }
+}
+
+/// The room behind a channel name, if it is a valid one.
+fn room_of(channel: &str) -> Option<&str> {
+    let room = channel.strip_prefix('#')?;
+    if room.is_empty() || validate_room(room).is_err() {
+        return None;
+    }
+    Some(room)
+}
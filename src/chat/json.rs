@@ -0,0 +1,352 @@
+//! The JSON lines protocol, for bots and other programs.
+//!
+//! A client of the telnet or WebSocket listeners switches to it by sending
+//! `PROTO json` as its first line. From then on every line, in both
+//! directions, is a JSON object whose `type` field tells what it is, for
+//! example:
+//!
+//! ```text
+//! > {"type": "login", "name": "alice", "password": "secret"}
+//! < {"type":"welcome","name":"alice"}
+//! > {"type": "message", "text": "hello"}
+//! < {"type":"message","room":"lobby","from":"bob","text":"hi","action":false,"history":false}
+//! > {"type": "command", "command": "jion", "args": "rust"}
+//! < {"type":"error","message":"unknown command /jion, try /help"}
+//! ```
+//!
+//! Clients send `login`, `message`, `action`, `private`, `command` and
+//! `pong` lines. The server sends `welcome`, `message`, `private`, `join`,
+//! `leave`, `quit`, `nick`, `notice`, `reply`, `error`, `disconnect` and
+//! `ping` lines. Command replies are the `reply` lines, the same text a
+//! telnet client gets.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+
+use super::auth::{Auth, AuthError};
+use super::commands::Flow;
+use super::connection::Connection;
+use super::event::{Departure, Event};
+use super::peer::{validate_name, Login, Peer};
+use crate::codec::Lines;
+
+/// A line sent by a client.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    /// Pick a name, logging in to its account if it has one, or log in to a
+    /// token account. Only valid during the handshake.
+    Login {
+        name: Option<String>,
+        password: Option<String>,
+        token: Option<String>,
+    },
+
+    /// A message to `room`, or to all the client's rooms.
+    Message { text: String, room: Option<String> },
+
+    /// An action, like `/me`.
+    Action { text: String, room: Option<String> },
+
+    /// A message to a single client, named exactly.
+    Private { to: String, text: String },
+
+    /// One of the `/` commands, without the slash.
+    Command {
+        command: String,
+        #[serde(default)]
+        args: String,
+    },
+
+    /// The answer to a `ping`.
+    Pong,
+}
+
+/// A line sent to a client.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Response<'a> {
+    /// The client's name was accepted.
+    Welcome {
+        name: &'a str,
+    },
+
+    /// A message to a room. `history` is set for the messages replayed when
+    /// the client enters a room.
+    Message {
+        room: &'a str,
+        from: &'a str,
+        text: &'a str,
+        action: bool,
+        history: bool,
+    },
+
+    Private {
+        from: &'a str,
+        text: &'a str,
+        action: bool,
+    },
+
+    Join {
+        room: &'a str,
+        name: &'a str,
+    },
+
+    Leave {
+        room: &'a str,
+        name: &'a str,
+    },
+
+    /// `reason` is `quit` or `timeout`.
+    Quit {
+        name: &'a str,
+        reason: &'a str,
+    },
+
+    Nick {
+        old: &'a str,
+        new: &'a str,
+    },
+
+    Notice {
+        text: &'a str,
+    },
+
+    /// A reply to a command.
+    Reply {
+        text: &'a str,
+    },
+
+    /// A request or command failed.
+    Error {
+        message: &'a str,
+    },
+
+    /// The last line before the server closes the connection.
+    Disconnect {
+        reason: &'a str,
+    },
+
+    /// The client has been silent for a while, and should answer with a
+    /// `pong`.
+    Ping,
+}
+
+/// Write a line to the client.
+pub(crate) fn send(lines: &mut Lines<Connection>, response: &Response) {
+    let mut line = serde_json::to_string(response).expect("responses always serialize");
+    line.push('\n');
+    lines.buffer(line.as_bytes());
+}
+
+/// Parse a line sent by the client.
+fn parse(line: &[u8]) -> Result<Request, String> {
+    serde_json::from_slice(line).map_err(|e| format!("invalid request: {}", e))
+}
+
+/// Handle a line sent by a client during the handshake.
+pub(crate) fn login(
+    lines: &mut Lines<Connection>,
+    auth: Option<&Auth>,
+    ip: IpAddr,
+    line: &[u8],
+) -> Login {
+    let error = |lines: &mut Lines<Connection>, message: &str| {
+        send(lines, &Response::Error { message });
+        Login::Continue
+    };
+
+    let (name, password, token) = match parse(line) {
+        Ok(Request::Login {
+            name,
+            password,
+            token,
+        }) => (name, password, token),
+        Ok(_) => return error(lines, "log in first"),
+        Err(e) => return error(lines, &e),
+    };
+
+    let result = match (name, token) {
+        (_, Some(token)) => match auth {
+            Some(auth) => auth
+                .verify_token(ip, &token)
+                .map(|account| (account.clone(), account)),
+            None => return error(lines, "this server has no accounts"),
+        },
+        (Some(name), None) => {
+            let name = match validate_name(name.as_bytes()) {
+                Ok(name) => name,
+                Err(e) => return error(lines, &e),
+            };
+
+            match (auth, password) {
+                (Some(auth), Some(password)) if auth.is_registered(&name) => {
+                    return Login::Verify { name, password };
+                }
+                (Some(auth), None) if auth.is_registered(&name) => {
+                    return error(lines, &format!("{} is registered, send its password", name));
+                }
+                _ => {
+                    return Login::Accepted {
+                        name,
+                        account: None,
+                    }
+                }
+            }
+        }
+        (None, None) => return error(lines, "login needs a name or a token"),
+    };
+
+    verified(lines, result)
+}
+
+/// Answer a client whose password or token has been checked.
+///
+/// `result` holds the name and the account the client logged in to.
+pub(crate) fn verified(
+    lines: &mut Lines<Connection>,
+    result: Result<(String, String), AuthError>,
+) -> Login {
+    match result {
+        Ok((name, account)) => Login::Accepted {
+            name,
+            account: Some(account),
+        },
+        Err(e @ AuthError::Denied) => {
+            send(
+                lines,
+                &Response::Error {
+                    message: &e.to_string(),
+                },
+            );
+            Login::Continue
+        }
+        Err(e @ AuthError::Blocked) => {
+            send(
+                lines,
+                &Response::Disconnect {
+                    reason: &e.to_string(),
+                },
+            );
+            Login::Refused
+        }
+    }
+}
+
+/// Handle a line sent by a logged in client.
+pub(crate) fn handle(peer: &mut Peer, line: &[u8]) -> Flow {
+    let result = match parse(line) {
+        Ok(Request::Message { text, room }) => say(peer, room, &text, false),
+        Ok(Request::Action { text, room }) => say(peer, room, &text, true),
+        Ok(Request::Private { to, text }) => private(peer, &to, &text),
+        Ok(Request::Command { command, args }) => {
+            let commands = peer.commands.clone();
+            commands.dispatch(peer, &format!("{} {}", command, args))
+        }
+        Ok(Request::Login { .. }) => Err("already logged in".to_string()),
+        Ok(Request::Pong) => Ok(Flow::Continue),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(flow) => flow,
+        Err(message) => {
+            send(&mut peer.lines, &Response::Error { message: &message });
+            Flow::Continue
+        }
+    }
+}
+
+/// Send a message to `room`, or to all the peer's rooms.
+fn say(peer: &mut Peer, room: Option<String>, text: &str, action: bool) -> Result<Flow, String> {
+    if text.is_empty() {
+        return Err("message can't be empty".to_string());
+    }
+
+    let state = peer.state.clone();
+    let mut state = state.lock().unwrap();
+
+    let room = match room {
+        Some(room) => room,
+        None => {
+            peer.broadcast(&mut state, text, action)?;
+            return Ok(Flow::Continue);
+        }
+    };
+
+    if !peer.rooms.contains(&room) {
+        return Err(format!("you are not in room {}", room));
+    }
+    state.check_muted(peer.addr)?;
+
+    let mut rooms = BTreeSet::new();
+    rooms.insert(room);
+    state.broadcast(peer.addr, &rooms, text, action);
+    Ok(Flow::Continue)
+}
+
+/// Send a private message. Unlike `/msg`, the recipient's name has to be
+/// given in full.
+fn private(peer: &mut Peer, to: &str, text: &str) -> Result<Flow, String> {
+    if text.is_empty() {
+        return Err("message can't be empty".to_string());
+    }
+
+    let state = peer.state.clone();
+    let mut state = state.lock().unwrap();
+    state.check_muted(peer.addr)?;
+
+    // Clients of linked servers are addressed as `name@server`.
+    if to.contains('@') {
+        state.relay_private(peer.addr, to, text, false)?;
+        return Ok(Flow::Continue);
+    }
+
+    let addr = *state
+        .names
+        .get(&to.to_lowercase())
+        .ok_or_else(|| format!("no client named {}", to))?;
+
+    state.private(addr, &peer.name, text, false);
+    state.touch(peer.addr);
+    Ok(Flow::Continue)
+}
+
+/// Write an event as a JSON line. `history` is set when the event is
+/// replayed from a room's history.
+pub(crate) fn render(lines: &mut Lines<Connection>, event: &Event, history: bool) {
+    let response = match event {
+        Event::Message {
+            room,
+            from,
+            text,
+            action,
+        } => Response::Message {
+            room,
+            from,
+            text,
+            action: *action,
+            history,
+        },
+        Event::Private { from, text, action } => Response::Private {
+            from,
+            text,
+            action: *action,
+        },
+        Event::Joined { room, name } => Response::Join { room, name },
+        Event::Parted { room, name } => Response::Leave { room, name },
+        Event::Quit { name, departure } => Response::Quit {
+            name,
+            reason: match departure {
+                Departure::Quit => "quit",
+                Departure::Timeout => "timeout",
+            },
+        },
+        Event::Renamed { old, new } => Response::Nick { old, new },
+        Event::Notice { text } => Response::Notice { text },
+        Event::Disconnect { reason } => Response::Disconnect { reason },
+    };
+    send(lines, &response);
+}
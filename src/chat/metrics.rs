@@ -0,0 +1,98 @@
+//! The metrics of the chat server, scraped by Prometheus from
+//! `--metrics-listen` and summed up by the admin `stats` command.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::metrics::{Counter, Gauge, Histogram, Registry};
+
+/// Upper bounds of the queue depth buckets, in messages.
+const QUEUE_DEPTH_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0];
+
+/// The server's metrics, updated without holding the state's lock.
+pub(crate) struct Metrics {
+    pub(crate) registry: Arc<Registry>,
+
+    pub(crate) started: Instant,
+
+    /// Connections accepted by the listeners, before the handshake.
+    pub(crate) accepted: Counter,
+
+    /// Connections turned away because the server was full or the address
+    /// banned.
+    pub(crate) rejected: Counter,
+
+    pub(crate) accept_errors: Counter,
+
+    /// Clients that got past the handshake.
+    pub(crate) clients: Counter,
+
+    /// Clients currently in the chat.
+    pub(crate) peers: Gauge,
+
+    /// Messages sent to rooms by local clients.
+    pub(crate) messages: Counter,
+
+    pub(crate) read: Counter,
+    pub(crate) written: Counter,
+
+    /// Messages dropped because a client's queue was full.
+    pub(crate) dropped: Counter,
+
+    /// Messages waiting in a client's queue each time it wakes up.
+    pub(crate) queue_depth: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Metrics {
+        let registry = Registry::new();
+
+        Metrics {
+            started: Instant::now(),
+            accepted: registry.counter(
+                "chat_connections_accepted_total",
+                "Connections accepted by the listeners.",
+                &[],
+            ),
+            rejected: registry.counter(
+                "chat_connections_rejected_total",
+                "Connections turned away because the server was full or the address banned.",
+                &[],
+            ),
+            accept_errors: registry.counter(
+                "chat_accept_errors_total",
+                "Errors accepting connections.",
+                &[],
+            ),
+            clients: registry.counter(
+                "chat_clients_total",
+                "Clients that completed the handshake.",
+                &[],
+            ),
+            peers: registry.gauge("chat_peers", "Clients currently in the chat.", &[]),
+            messages: registry.counter(
+                "chat_messages_total",
+                "Messages broadcast to rooms by local clients.",
+                &[],
+            ),
+            read: registry.counter("chat_read_bytes_total", "Bytes read from the clients.", &[]),
+            written: registry.counter(
+                "chat_written_bytes_total",
+                "Bytes written to the clients.",
+                &[],
+            ),
+            dropped: registry.counter(
+                "chat_dropped_messages_total",
+                "Messages dropped because a client's queue was full.",
+                &[],
+            ),
+            queue_depth: registry.histogram(
+                "chat_queue_depth",
+                "Messages waiting in a client's queue when it wakes up.",
+                &[],
+                QUEUE_DEPTH_BUCKETS,
+            ),
+            registry: Arc::new(registry),
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! A chat server for telnet clients. After a telnet client connects, the first
+//! line should contain the client's name. Names must be unique and may not
+//! contain spaces or control characters; the server answers a rejected name
+//! with an error line and waits for another one. After that, all lines sent by
+//! a client are broadcasted to all other clients in the same rooms.
+//!
+//! Every client starts in the `lobby` room. The last few messages of a room
+//! are replayed, marked with `[history]`, when a client enters it. Lines
+//! starting with `/` are commands rather than messages, for example:
+//!
+//! ```text
+//! /join <room>    join a room, creating it if it doesn't exist
+//! /leave [room]   leave a room (the room can be omitted if you are in one)
+//! /rooms          list all rooms and their member counts
+//! /nick <name>    change your name
+//! /who [room]     list the members of your rooms, with their idle times
+//! /history <n>    replay the last messages of your rooms
+//! /msg <name> <text>
+//!                 send a private message to a single client
+//! /me <action>    send an action message
+//! /kick <name> [reason]
+//!                 disconnect a client
+//! /ban <name|ip> [duration]
+//!                 disconnect and ban a client's address
+//! /unban <ip>     lift a ban
+//! /mute <name> [duration]
+//!                 stop a client from sending messages
+//! /unmute <name>  lift a mute
+//! /op <name>      make a client an operator
+//! /quit           leave the chat
+//! /help           list all commands
+//! ```
+//!
+//! The members of a room are told when someone enters or leaves it, changes
+//! name, or disconnects, including after a keepalive timeout. Which of these
+//! events are announced is configured with `announce`.
+//!
+//! Commands are looked up in a `Commands` registry, so new ones are added by
+//! registering a handler rather than by editing `Peer::poll`.
+//!
+//! Because the client is telnet, lines sent to clients are delimited by
+//! "\r\n". Lines received from clients may end with either "\r\n" or a bare
+//! "\n". A line longer than the configured maximum either disconnects the
+//! client or is cut at the maximum, with the rest of it discarded, depending on
+//! the configured long line policy.
+//!
+//! The server can keep an append-only transcript of joins, leaves and
+//! messages. The file is rotated once it grows past a configured size, and the
+//! history of the rooms is rebuilt from it on startup.
+//!
+//! Messages waiting for a client are kept in a bounded queue. When a client
+//! stops reading and its queue fills up, the server either drops the oldest
+//! message, drops the newest one or disconnects the client, depending on the
+//! configured overflow policy.
+//!
+//! Each client may send a burst of lines at once, then a few lines a second,
+//! commands included. Lines over the limit are discarded. A client that keeps
+//! flooding gets warned, then muted for a while, then disconnected.
+//!
+//! Clients that vanish without closing their connection can be told apart
+//! from quiet ones by enabling `keepalive`. A client silent for that long is
+//! pinged: IRC clients with `PING`, others with a line asking them to send
+//! `/pong`. A client still silent after `idle_timeout` is disconnected. With
+//! `handshake_timeout`, clients also have to send an acceptable name in
+//! time.
+//!
+//! IRC clients can join the same rooms on a separate set of addresses. They
+//! register with `NICK` and `USER` and see room `lobby` as channel `#lobby`;
+//! messages between telnet and IRC clients go both ways. Only the core of the
+//! protocol is supported: `JOIN`, `PART`, `PRIVMSG`, `NOTICE`, `NICK`,
+//! `PING`, `PONG` and `QUIT`.
+//!
+//! Browsers can connect over WebSocket on another set of addresses. After the
+//! HTTP upgrade, they speak the telnet protocol with one line per text
+//! message, starting with the client's name.
+//!
+//! Programs can send `PROTO json` as their first line on a telnet or WebSocket
+//! address to speak JSON lines instead: one JSON object per line, both ways,
+//! with typed messages, joins, leaves, command replies and errors. JSON
+//! clients share the rooms with everyone else. See the `json` module for the
+//! format.
+//!
+//! Each kind of client can also connect over TLS, on listeners of their own
+//! sharing a certificate and key loaded from PEM files. Setting `listen` to an
+//! empty list leaves the encrypted listeners only.
+//!
+//! Names can be protected by accounts, listed in a credentials file as
+//! printed by `line_chat_passwd`. A telnet client choosing the name of an
+//! account is asked for its password on the next line, and an IRC client has
+//! to send it with `PASS`. Bots log in to token accounts with a single
+//! `/token <token>` line. An address making too many failed attempts is
+//! refused for a while. Names without an account stay free for anyone.
+//!
+//! Only operators may run the moderation commands, `/kick`, `/ban`, `/mute`
+//! and `/op`. Clients logged in to one of the accounts listed as `operators`
+//! are operators from the start; `/op` makes another client one until it
+//! disconnects. Durations are written like `90s`, `10m`, `2h` or `7d`, and
+//! bans and mutes without one last until lifted. Bans apply to the client's
+//! address, are checked before a connection is accepted and are saved to the
+//! `bans` file, if set, so they survive restarts.
+//!
+//! Servers can be linked so that their clients share the same rooms. Each
+//! server has a `name`, accepts links on its own addresses and connects to
+//! the servers listed in `links`, reconnecting with exponential backoff when
+//! a link drops. Messages, private messages and presence events are relayed
+//! from server to server; each carries its origin and an id, so that it is
+//! delivered once even when the links form a loop. Clients of other servers
+//! appear as `name@server`, in rooms and in `/who`, and `/msg name@server`
+//! reaches them. Links are plaintext unless `federation.tls` is set, in
+//! which case they are encrypted in both directions: `cert` and `key` are
+//! presented on the link listeners, and the servers linked to are checked
+//! against the system's certificates and `ca`.
+//!
+//! A running server can be managed through the Unix socket set as
+//! `admin_socket`, with `chatctl`: it lists the connected clients with their
+//! traffic, kicks them, sends notices to everyone, reloads the settings that
+//! can change at runtime and shows counters since the start.
+//!
+//! The server's metrics, from accepted connections and active peers to bytes
+//! exchanged, queue depths and dropped messages, are served to Prometheus at
+//! `/metrics` on the addresses listed in `metrics_listen`.
+//!
+//! Events are logged to the standard error, each with the id, address and
+//! name of the connection it concerns. `log.level` sets the verbosity and
+//! `log.format = "json"` switches to JSON lines; left unset, they are read
+//! from `LOG_LEVEL` and `LOG_FORMAT`, see the `log` module.
+//!
+//! The server stops on SIGINT or SIGTERM. It stops accepting connections, sends
+//! every client a "server shutting down" line after the messages still queued
+//! for it and gives the clients a few seconds to receive them before exiting.
+//!
+//! Settings are read from an optional TOML file and can be overridden on the
+//! command line, see `line_chat --help`. For example:
+//!
+//! ```toml
+//! listen = ["0.0.0.0:6142", "[::]:6142"]
+//! irc_listen = ["0.0.0.0:6667"]
+//! websocket_listen = ["0.0.0.0:8080"]
+//! max_clients = 512
+//! max_line_length = 4096
+//! long_lines = "disconnect" # or "truncate"
+//! lines_per_tick = 10
+//! read_reserve = 1024
+//! write_buffer_limit = 65536
+//! announce = ["join", "leave", "nick", "timeout"]
+//! keepalive = 300 # seconds, 0 = never
+//! idle_timeout = 60
+//! handshake_timeout = 30 # seconds, 0 = never
+//! bans = "bans"
+//! admin_socket = "line_chat.sock"
+//! metrics_listen = ["127.0.0.1:9142"]
+//!
+//! [log]
+//! level = "info" # or "error", "warn", "debug", "trace", "off"
+//! format = "text" # or "json"
+//!
+//! [queue]
+//! capacity = 256
+//! overflow = "drop-oldest" # or "drop-newest", "disconnect"
+//!
+//! [flood]
+//! burst = 10
+//! rate = 2.0 # lines per second
+//! warnings = 2
+//! mute = 60 # seconds
+//!
+//! [transcript]
+//! path = "chat.log"
+//! max_size = 10485760
+//! keep = 5
+//!
+//! [tls]
+//! cert = "cert.pem"
+//! key = "key.pem"
+//! listen = ["0.0.0.0:6143"]
+//! irc_listen = ["0.0.0.0:6697"]
+//! websocket_listen = ["0.0.0.0:8443"]
+//!
+//! [auth]
+//! credentials = "credentials"
+//! max_failures = 5
+//! failure_window = 60 # seconds
+//! operators = ["alice"]
+//!
+//! [federation]
+//! name = "paris"
+//! listen = ["0.0.0.0:6150"]
+//! links = ["berlin.example.com:6150"]
+//! secret = "shared secret"
+//! max_backoff = 60 # seconds
+//!
+//! [federation.tls]
+//! cert = "link-cert.pem"
+//! key = "link-key.pem"
+//! ca = "link-ca.pem"
+//! ```
+
+//!
+//! `run` starts the server with a `Config`; the `line_chat` binary is a thin
+//! frontend that builds the `Config` from the command line.
+
+mod admin;
+mod auth;
+mod commands;
+mod config;
+mod connection;
+mod event;
+mod federation;
+mod flood;
+mod irc;
+mod json;
+mod metrics;
+mod moderation;
+mod peer;
+mod queue;
+mod server;
+mod state;
+mod transcript;
+
+pub use self::auth::{hash_password, hash_token, new_token, AuthConfig, PASSWORD_ROUNDS};
+pub use self::config::{Config, TlsConfig, USAGE};
+pub use self::event::Announcement;
+pub use self::federation::{FederationConfig, LinkTlsConfig};
+pub use self::flood::FloodConfig;
+pub use self::queue::{channel, Overflow, QueueConfig, Rx, Tx};
+pub use self::server::run;
+pub use self::transcript::{Record, RecordKind, Transcript, TranscriptConfig};
@@ -0,0 +1,373 @@
+//! Bans and mutes placed by operators.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a ban or a mute lasts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Restriction {
+    /// When the restriction ends. `None` until it is lifted.
+    pub(crate) until: Option<SystemTime>,
+}
+
+/// Addresses that may not connect, saved to a file so that they survive
+/// restarts.
+///
+/// The file has one `<ip> <expiry> <operator>` line per ban, where the expiry
+/// is in seconds since the epoch, or `never`. It is rewritten whenever the
+/// list changes. Expired bans are dropped the next time it is.
+#[derive(Default)]
+pub(crate) struct Bans {
+    /// The file the bans are saved to. Without one, bans last until the
+    /// server stops.
+    path: Option<PathBuf>,
+
+    bans: HashMap<IpAddr, Ban>,
+}
+
+/// An entry of the ban list.
+struct Ban {
+    restriction: Restriction,
+
+    /// Name of the operator who placed the ban.
+    by: String,
+}
+
+impl Restriction {
+    /// A restriction lasting `duration`, or until lifted. A duration too
+    /// long to be represented also lasts until lifted.
+    pub(crate) fn new(duration: Option<Duration>) -> Restriction {
+        Restriction {
+            until: duration.and_then(|duration| SystemTime::now().checked_add(duration)),
+        }
+    }
+
+    /// Whether the restriction has ended.
+    pub(crate) fn expired(&self) -> bool {
+        match self.until {
+            Some(until) => until <= SystemTime::now(),
+            None => false,
+        }
+    }
+
+    /// How long the restriction still lasts, as in "banned for 10m".
+    pub(crate) fn remaining(&self) -> String {
+        match self.until {
+            Some(until) => {
+                let left = until.duration_since(SystemTime::now()).unwrap_or_default();
+                format!("for {}", format_duration(left))
+            }
+            None => "until further notice".to_string(),
+        }
+    }
+}
+
+impl Bans {
+    /// Load the bans saved to `path`, if any. A missing file is an empty
+    /// ban list.
+    pub(crate) fn load(path: Option<&Path>) -> io::Result<Bans> {
+        let mut bans = Bans {
+            path: path.map(Path::to_path_buf),
+            bans: HashMap::new(),
+        };
+
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(bans),
+        };
+
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(bans),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failed to read {}: {}", path.display(), e),
+                ))
+            }
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} line {}: expected `<ip> <expiry> <operator>`",
+                        path.display(),
+                        i + 1
+                    ),
+                )
+            };
+
+            let mut fields = line.split_whitespace();
+            let (ip, until, by) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(ip), Some(until), Some(by)) => (ip, until, by),
+                _ => return Err(invalid()),
+            };
+
+            let ip = ip.parse().map_err(|_| invalid())?;
+            // An expiry too far away to be represented never comes.
+            let until = match until {
+                "never" => None,
+                secs => {
+                    let secs = secs.parse().map_err(|_| invalid())?;
+                    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+                }
+            };
+
+            let ban = Ban {
+                restriction: Restriction { until },
+                by: by.to_string(),
+            };
+            if !ban.restriction.expired() {
+                bans.bans.insert(ip, ban);
+            }
+        }
+
+        Ok(bans)
+    }
+
+    /// Number of addresses banned.
+    pub(crate) fn len(&self) -> usize {
+        self.bans.len()
+    }
+
+    /// The ban on `ip`, if there is one that hasn't expired.
+    pub(crate) fn check(&mut self, ip: IpAddr) -> Option<Restriction> {
+        let restriction = self.bans.get(&ip)?.restriction;
+        if restriction.expired() {
+            self.bans.remove(&ip);
+            return None;
+        }
+        Some(restriction)
+    }
+
+    /// Ban `ip`, replacing any previous ban on it.
+    ///
+    /// The ban is in place even if it couldn't be saved.
+    pub(crate) fn insert(
+        &mut self,
+        ip: IpAddr,
+        restriction: Restriction,
+        by: &str,
+    ) -> io::Result<()> {
+        let ban = Ban {
+            restriction,
+            by: by.to_string(),
+        };
+        self.bans.insert(ip, ban);
+        self.save()
+    }
+
+    /// Lift the ban on `ip`. Returns whether there was one.
+    pub(crate) fn remove(&mut self, ip: IpAddr) -> io::Result<bool> {
+        if self.bans.remove(&ip).is_none() {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Rewrite the ban file, if there is one.
+    ///
+    /// The list is written to a temporary file first, so a crash can't leave
+    /// a truncated list behind.
+    fn save(&mut self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        self.bans.retain(|_, ban| !ban.restriction.expired());
+
+        let mut text = String::new();
+        for (ip, ban) in &self.bans {
+            let until = match ban.restriction.until {
+                Some(until) => until
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+                    .to_string(),
+                None => "never".to_string(),
+            };
+            text.push_str(&format!("{} {} {}\n", ip, until, ban.by));
+        }
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Parse a duration such as `90s`, `10m`, `2h` or `7d`. A bare number is in
+/// seconds.
+pub(crate) fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration `{}`, try 30s, 10m, 2h or 7d", text);
+
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let secs = match unit {
+        "s" => number,
+        "m" => number.saturating_mul(60),
+        "h" => number.saturating_mul(60 * 60),
+        "d" => number.saturating_mul(24 * 60 * 60),
+        _ => return Err(invalid()),
+    };
+
+    if secs == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Format a duration in its largest whole unit, rounded to the nearest, as
+/// in `3h`.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs() + u64::from(duration.subsec_millis() >= 500);
+
+    let units = [(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m")];
+    for &(size, unit) in units.iter() {
+        if secs >= size {
+            return format!("{}{}", (secs + size / 2) / size, unit);
+        }
+    }
+    format!("{}s", secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(10 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Ok(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for text in [
+            "", "0", "0s", "0d", "s", "10w", "10 m", "1h30m", "-5m", "1.5h",
+        ]
+        .iter()
+        {
+            assert!(parse_duration(text).is_err(), "accepted `{}`", text);
+        }
+        // Too many digits for a u64.
+        assert!(parse_duration("99999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn saturates_long_durations() {
+        let max = Duration::from_secs(u64::MAX);
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX)), Ok(max));
+        assert_eq!(parse_duration(&format!("{}m", u64::MAX / 2)), Ok(max));
+
+        // Too long to be represented, so it lasts until lifted.
+        assert_eq!(Restriction::new(Some(max)).until, None);
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_millis(59_400)), "59s");
+        assert_eq!(format_duration(Duration::from_millis(59_600)), "1m");
+        assert_eq!(format_duration(Duration::from_secs(90)), "2m");
+        assert_eq!(format_duration(Duration::from_secs(3 * 60 * 60 - 1)), "3h");
+        assert_eq!(
+            format_duration(Duration::from_secs(10 * 24 * 60 * 60)),
+            "10d"
+        );
+    }
+
+    #[test]
+    fn restrictions_expire() {
+        let past = Restriction {
+            until: Some(SystemTime::now() - Duration::from_secs(1)),
+        };
+        assert!(past.expired());
+        assert_eq!(past.remaining(), "for 0s");
+
+        let future = Restriction::new(Some(Duration::from_secs(10 * 60)));
+        assert!(!future.expired());
+        assert_eq!(future.remaining(), "for 10m");
+
+        let forever = Restriction::new(None);
+        assert!(!forever.expired());
+        assert_eq!(forever.remaining(), "until further notice");
+    }
+
+    #[test]
+    fn drops_expired_bans() {
+        let ip = "192.0.2.1".parse().unwrap();
+        let mut bans = Bans::default();
+        let expired = Restriction {
+            until: Some(SystemTime::now() - Duration::from_secs(1)),
+        };
+
+        bans.insert(ip, expired, "op").unwrap();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans.check(ip), None);
+        assert_eq!(bans.len(), 0);
+    }
+
+    #[test]
+    fn saves_and_loads_bans() {
+        let dir = std::env::temp_dir().join(format!("bans-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bans");
+        let _ = fs::remove_file(&path);
+
+        let temporary = "192.0.2.1".parse().unwrap();
+        let permanent = "2001:db8::1".parse().unwrap();
+        let expired = "192.0.2.2".parse().unwrap();
+
+        let mut bans = Bans::load(Some(&path)).unwrap();
+        assert_eq!(bans.len(), 0);
+        let restriction = Restriction::new(Some(Duration::from_secs(60 * 60)));
+        bans.insert(temporary, restriction, "alice").unwrap();
+        bans.insert(permanent, Restriction::new(None), "bob")
+            .unwrap();
+        let mut text = fs::read_to_string(&path).unwrap();
+        text.push_str(&format!("# a comment\n\n{} 1 carol\n", expired));
+        fs::write(&path, text).unwrap();
+
+        let mut bans = Bans::load(Some(&path)).unwrap();
+        assert_eq!(bans.len(), 2);
+        assert!(bans.check(temporary).unwrap().until.is_some());
+        assert_eq!(bans.check(permanent), Some(Restriction { until: None }));
+        assert_eq!(bans.check(expired), None);
+
+        assert!(bans.remove(temporary).unwrap());
+        assert!(!bans.remove(temporary).unwrap());
+        let bans = Bans::load(Some(&path)).unwrap();
+        assert_eq!(bans.len(), 1);
+
+        fs::write(&path, "192.0.2.1 never\n").unwrap();
+        let err = Bans::load(Some(&path)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("line 1"));
+    }
+}
@@ -0,0 +1,1132 @@
+//! Connected clients.
+
+use tokio::io;
+use tokio::prelude::*;
+use tokio::timer::Delay;
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::auth::{Auth, AuthError};
+use super::commands::{Commands, Flow};
+use super::config::Config;
+use super::connection::Connection;
+use super::event::{Departure, Event};
+use super::flood::{Limiter, Verdict};
+use super::irc::{self, Registration, Step};
+use super::json::{self, Response};
+use super::metrics::Metrics;
+use super::moderation::Restriction;
+use super::queue::{channel, Rx};
+use super::server::SHUTDOWN_GRACE;
+use super::state::{Client, Shared, Traffic, HISTORY_REPLAY};
+use crate::codec::Lines;
+use crate::log::Context;
+use crate::{info, trace};
+
+/// Name of the room every client is placed in once its name is received.
+pub(crate) const DEFAULT_ROOM: &str = "lobby";
+
+/// Maximum length of a client name, in characters.
+pub(crate) const MAX_NAME: usize = 32;
+
+/// The protocol a client speaks, decided by the address it connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    /// Plain lines, with `/` commands. Meant for telnet or netcat.
+    Telnet,
+
+    /// IRC, see the `irc` module.
+    Irc,
+
+    /// JSON lines, see the `json` module. Chosen by sending `PROTO json` as
+    /// the first line to a telnet or WebSocket listener.
+    Json,
+}
+
+impl Protocol {
+    /// Name of the protocol, as shown to the administrator and in the logs.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Protocol::Telnet => "telnet",
+            Protocol::Irc => "irc",
+            Protocol::Json => "json",
+        }
+    }
+}
+
+/// The state for each connected client.
+pub(crate) struct Peer {
+    /// Name of the peer.
+    ///
+    /// When a client connects, the first line sent is treated as the client's
+    /// name (like alice or bob). The name is used to preface all messages that
+    /// arrive from the client so that we can simulate a real chat server:
+    ///
+    /// ```text
+    /// alice: Hello everyone.
+    /// bob: Welcome to telnet chat!
+    /// ```
+    ///
+    /// The name has been checked by `validate_name` and is unique among the
+    /// connected clients.
+    pub(crate) name: String,
+
+    /// The protocol spoken by the client.
+    pub(crate) protocol: Protocol,
+
+    /// The client connection wrapped with the `Lines` codec.
+    ///
+    /// This handles sending and receiving data on the socket. When using
+    /// `Lines`, we can work at the line level instead of having to manage the
+    /// raw byte operations.
+    pub(crate) lines: Lines<Connection>,
+
+    /// Handle to the shared chat state.
+    ///
+    /// This is used to broadcast messages read off the socket to all connected
+    /// peers.
+    pub(crate) state: Arc<Mutex<Shared>>,
+
+    /// The commands this peer can run.
+    ///
+    /// Lines starting with `/` are looked up in this registry instead of
+    /// being broadcasted.
+    pub(crate) commands: Arc<Commands>,
+
+    /// Server settings.
+    pub(crate) config: Arc<Config>,
+
+    /// Receive half of the message channel.
+    ///
+    /// This is used to receive messages from peers. When a message is received
+    /// off of this `Rx`, it will be written to the socket.
+    pub(crate) rx: Rx<Arc<Event>>,
+
+    /// Client socket address.
+    ///
+    /// The socket address is used as the key in the `peers` HashMap. The
+    /// address is saved so that the `Peer` drop implementation can clean up its
+    /// entry.
+    pub(crate) addr: SocketAddr,
+
+    /// Names of the rooms the peer is a member of.
+    ///
+    /// The peer's messages are only delivered to members of these rooms. A
+    /// `BTreeSet` keeps the rooms sorted so that listing and broadcasting
+    /// always visit them in the same order.
+    pub(crate) rooms: BTreeSet<String>,
+
+    /// Rate limiter of the lines read from the client.
+    pub(crate) limiter: Limiter,
+
+    /// Fires once the client has been silent for `keepalive` seconds, then
+    /// again `idle_timeout` seconds after it was pinged. `None` if idle
+    /// clients are kept.
+    pub(crate) idle: Option<Delay>,
+
+    /// Set when the client was pinged and hasn't sent anything since.
+    pub(crate) pinged: bool,
+
+    /// Why the client is leaving, announced to the clients sharing a room
+    /// with it once it has left.
+    pub(crate) departure: Departure,
+
+    /// Set once the connection is closing: after a disconnection notice, the
+    /// shutdown notice, the reply to `/quit` or the idle timeout notice has
+    /// been buffered. Nothing more is read and the peer completes as soon as
+    /// its write buffer is flushed, or when this deadline passes.
+    pub(crate) closing: Option<Delay>,
+
+    /// Bytes exchanged with the client, shared with its entry in `Shared`.
+    pub(crate) traffic: Arc<Traffic>,
+
+    pub(crate) metrics: Arc<Metrics>,
+
+    /// Fields of the connection's events in the logs.
+    pub(crate) log: Context,
+}
+
+/// Future that reads lines from a new client until it sends an acceptable
+/// name, resolving to the client's `Peer`.
+///
+/// Telnet clients send their name as the first line, IRC clients register
+/// with `NICK` and `USER`. A telnet client may instead start with
+/// `PROTO json` and log in with a JSON `login` line. A rejected name is
+/// answered with an error line and the client may try again. The client is
+/// only registered in `Shared` once its name has been accepted, so other
+/// peers never see a half-connected client. Resolves to `None` if the client
+/// disconnects first, or is disconnected after too many failed logins.
+///
+/// When authentication is enabled, the names of accounts require logging in.
+/// Telnet clients send the password, or token, on the line after the name,
+/// or log in to a token account in one line with `/token <token>`. IRC
+/// clients send it with `PASS` before registering.
+pub(crate) struct Handshake {
+    /// The client connection. `None` once the `Peer` has been created.
+    pub(crate) lines: Option<Lines<Connection>>,
+
+    pub(crate) protocol: Protocol,
+
+    /// What an IRC client has sent so far.
+    pub(crate) registration: Registration,
+
+    /// The name a telnet client sent, if it belongs to an account whose
+    /// password is expected on the next line.
+    pub(crate) pending: Option<String>,
+
+    /// Set until the first line has been read. Only the first line can
+    /// switch the client to another protocol.
+    pub(crate) first: bool,
+
+    /// The password being checked, if any. No line is read until it is done.
+    pub(crate) verifying: Option<Verification>,
+
+    /// Set once the login has been refused. The handshake resolves to `None`
+    /// once the refusal is written out, or when this deadline passes.
+    pub(crate) closing: Option<Delay>,
+
+    pub(crate) state: Arc<Mutex<Shared>>,
+
+    pub(crate) commands: Arc<Commands>,
+
+    pub(crate) config: Arc<Config>,
+
+    /// Fields of the connection's events in the logs.
+    pub(crate) log: Context,
+}
+
+impl Handshake {
+    /// Start the name handshake on a new connection.
+    pub(crate) fn new(
+        lines: Lines<Connection>,
+        protocol: Protocol,
+        state: Arc<Mutex<Shared>>,
+        commands: Arc<Commands>,
+        config: Arc<Config>,
+        log: Context,
+    ) -> Handshake {
+        Handshake {
+            lines: Some(lines),
+            protocol,
+            registration: Registration::default(),
+            pending: None,
+            first: true,
+            verifying: None,
+            closing: None,
+            state,
+            commands,
+            config,
+            log,
+        }
+    }
+}
+
+impl Future for Handshake {
+    type Item = Option<Peer>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Peer>, io::Error> {
+        // Like `Peer::poll`, read at most `lines_per_tick` lines before
+        // letting other tasks run, so a client sending many lines at once
+        // doesn't hold up the others.
+        let mut read = 0;
+
+        loop {
+            let lines = self
+                .lines
+                .as_mut()
+                .expect("polled Handshake after completion");
+
+            if let Some(ref mut deadline) = self.closing {
+                try_ready!(poll_closing(lines, deadline));
+                return Ok(Async::Ready(None));
+            }
+
+            // Write out the replies to rejected names. A client that keeps
+            // sending names without reading the replies is not read from
+            // until the buffer drains.
+            if lines.poll_flush()?.is_not_ready()
+                && lines.buffered_len() >= self.config.write_buffer_limit
+            {
+                return Ok(Async::NotReady);
+            }
+
+            // Get the client socket address
+            let addr = lines.get_ref().peer_addr()?;
+
+            // Checking a password is slow, so it happens without holding the
+            // lock on the shared state, on a thread that may block.
+            let auth = self.state.lock().unwrap().auth.clone();
+
+            let login = match self.verifying {
+                Some(ref mut verification) => {
+                    let result = try_ready!(verification.poll());
+                    self.verifying = None;
+                    match self.protocol {
+                        Protocol::Telnet => telnet_verified(lines, result),
+                        Protocol::Irc => self.registration.verified(lines, result),
+                        Protocol::Json => json::verified(lines, result),
+                    }
+                }
+                None => {
+                    if read == self.config.lines_per_tick {
+                        // More lines may be waiting, come back for them on
+                        // the next tick.
+                        task::current().notify();
+                        return Ok(Async::NotReady);
+                    }
+                    read += 1;
+
+                    let line = match try_ready!(lines.poll()) {
+                        Some(line) => line,
+                        // The remote client closed the connection without
+                        // sending an acceptable name.
+                        None => return Ok(Async::Ready(None)),
+                    };
+
+                    if std::mem::replace(&mut self.first, false)
+                        && self.protocol == Protocol::Telnet
+                    {
+                        if let Some(proto) = line.strip_prefix(b"PROTO ") {
+                            match proto {
+                                b"json" => self.protocol = Protocol::Json,
+                                b"text" => {}
+                                _ => {
+                                    lines.buffer(b"* error: unknown protocol, try json or text\r\n")
+                                }
+                            }
+                            continue;
+                        }
+                    }
+
+                    let ip = addr.ip();
+                    match self.protocol {
+                        Protocol::Telnet => {
+                            telnet_login(lines, &mut self.pending, auth.as_deref(), ip, &line)
+                        }
+                        Protocol::Irc => match self.registration.handle(lines, &line) {
+                            Step::Register(name) => self.registration.login(name, auth.as_deref()),
+                            Step::Continue => Login::Continue,
+                            Step::Quit => Login::Refused,
+                        },
+                        Protocol::Json => json::login(lines, auth.as_deref(), ip, &line),
+                    }
+                }
+            };
+
+            let (name, account) = match login {
+                Login::Accepted { name, account } => (name, account),
+                Login::Continue => continue,
+                Login::Verify { name, password } => {
+                    // `Verify` is only asked for when there are accounts.
+                    if let Some(auth) = auth {
+                        self.verifying = Some(Verification {
+                            auth,
+                            ip: addr.ip(),
+                            name,
+                            password,
+                        });
+                    }
+                    continue;
+                }
+                Login::Refused => {
+                    info!(context: self.log, "login refused");
+                    self.closing = Some(close_deadline());
+                    continue;
+                }
+            };
+
+            // Create a channel for this peer
+            let (tx, rx) = channel(self.state.lock().unwrap().queue);
+
+            // Add an entry for this client in the shared state map. This
+            // checks that the name is free with the state locked, so two
+            // clients can't claim the same name at once.
+            let client = Client {
+                name: name.clone(),
+                tx: tx.clone(),
+                operator: match (auth.as_deref(), &account) {
+                    (Some(auth), Some(account)) => auth.is_operator(account),
+                    _ => false,
+                },
+                account: account.clone(),
+                muted: None,
+                active: Instant::now(),
+                protocol: self.protocol,
+                traffic: Arc::default(),
+            };
+            if let Err(e) = self.state.lock().unwrap().add_peer(addr, client) {
+                match self.protocol {
+                    Protocol::Telnet => {
+                        lines.buffer(format!("* error: {}, try again\r\n", e).as_bytes())
+                    }
+                    Protocol::Irc => self.registration.taken(lines),
+                    Protocol::Json => json::send(lines, &Response::Error { message: &e }),
+                }
+                continue;
+            }
+
+            let mut log = self.log.clone();
+            log.set("name", &name);
+            if let Some(ref account) = account {
+                log.set("account", account);
+            }
+            info!(
+                context: log,
+                "joined the chat",
+                protocol = self.protocol.name()
+            );
+
+            let mut lines = self.lines.take().unwrap();
+            match self.protocol {
+                Protocol::Telnet => lines.buffer(format!("* welcome, {}\r\n", name).as_bytes()),
+                Protocol::Irc => irc::welcome(&mut lines, &name),
+                Protocol::Json => json::send(&mut lines, &Response::Welcome { name: &name }),
+            }
+
+            let peer = Peer::new(self, addr, name, lines, rx, log);
+            return Ok(Async::Ready(Some(peer)));
+        }
+    }
+}
+
+/// What to do after a line sent by a client during the handshake.
+pub(crate) enum Login {
+    /// Wait for more lines.
+    Continue,
+
+    /// Register the client under `name`, logged in to `account` if set.
+    Accepted {
+        name: String,
+        account: Option<String>,
+    },
+
+    /// Check `password`, the password of the account `name`, then answer
+    /// the client with the protocol's `verified`.
+    Verify { name: String, password: String },
+
+    /// Disconnect the client.
+    Refused,
+}
+
+/// Handle a line sent by a telnet client during the handshake.
+fn telnet_login(
+    lines: &mut Lines<Connection>,
+    pending: &mut Option<String>,
+    auth: Option<&Auth>,
+    ip: IpAddr,
+    line: &[u8],
+) -> Login {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => {
+            return match validate_name(line) {
+                Ok(name) => Login::Accepted {
+                    name,
+                    account: None,
+                },
+                Err(e) => {
+                    lines.buffer(format!("* error: {}, try again\r\n", e).as_bytes());
+                    Login::Continue
+                }
+            }
+        }
+    };
+
+    if let Some(name) = pending.take() {
+        // The line is the password of the name sent before.
+        return Login::Verify {
+            name,
+            password: String::from_utf8_lossy(line).into_owned(),
+        };
+    }
+
+    if let Some(token) = line.strip_prefix(b"/token ") {
+        let token = String::from_utf8_lossy(token);
+        let result = auth
+            .verify_token(ip, token.trim())
+            .map(|account| (account.clone(), account));
+        telnet_verified(lines, result)
+    } else {
+        match validate_name(line) {
+            Ok(ref name) if auth.is_registered(name) => {
+                lines.buffer(format!("* {} is registered, send its password\r\n", name).as_bytes());
+                *pending = Some(name.clone());
+                Login::Continue
+            }
+            Ok(name) => Login::Accepted {
+                name,
+                account: None,
+            },
+            Err(e) => {
+                lines.buffer(format!("* error: {}, try again\r\n", e).as_bytes());
+                Login::Continue
+            }
+        }
+    }
+}
+
+/// Answer a telnet client whose password or token has been checked.
+///
+/// `result` holds the name and the account the client logged in to.
+fn telnet_verified(
+    lines: &mut Lines<Connection>,
+    result: Result<(String, String), AuthError>,
+) -> Login {
+    match result {
+        Ok((name, account)) => Login::Accepted {
+            name,
+            account: Some(account),
+        },
+        Err(AuthError::Denied) => {
+            lines.buffer(format!("* error: {}, try again\r\n", AuthError::Denied).as_bytes());
+            Login::Continue
+        }
+        Err(AuthError::Blocked) => {
+            lines.buffer(format!("* error: {}\r\n", AuthError::Blocked).as_bytes());
+            Login::Refused
+        }
+    }
+}
+
+/// When a connection that starts closing now is closed, whether or not the
+/// client has read everything that was left to write to it.
+fn close_deadline() -> Delay {
+    Delay::new(Instant::now() + SHUTDOWN_GRACE)
+}
+
+/// Write out what is left for a closing connection.
+///
+/// Ready once the write buffer is flushed, or once `deadline` has passed: a
+/// client that doesn't read is dropped with its lines unsent.
+fn poll_closing(lines: &mut Lines<Connection>, deadline: &mut Delay) -> Poll<(), io::Error> {
+    if lines.poll_flush()?.is_ready() {
+        return Ok(Async::Ready(()));
+    }
+
+    match deadline.poll() {
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        // A failed timer can't tell the time, give up on the client too.
+        Ok(Async::Ready(())) | Err(_) => Ok(Async::Ready(())),
+    }
+}
+
+/// A password being checked, off the reactor.
+pub(crate) struct Verification {
+    auth: Arc<Auth>,
+    ip: IpAddr,
+    name: String,
+    password: String,
+}
+
+impl Future for Verification {
+    type Item = Result<(String, String), AuthError>;
+    type Error = io::Error;
+
+    /// Check the password on a thread that may block, resolving to the name
+    /// and the account the client logged in to.
+    ///
+    /// Deriving the hash of a password is slow on purpose, and would stall
+    /// every other connection if it ran on the reactor.
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        let Verification {
+            ref auth,
+            ip,
+            ref name,
+            ref password,
+        } = *self;
+        let verify = || {
+            auth.verify(ip, name, password)
+                .map(|account| (name.clone(), account))
+        };
+
+        match tokio_threadpool::blocking(verify) {
+            Ok(Async::Ready(result)) => Ok(Async::Ready(result)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // Not running on a thread pool, the password is checked in
+            // place.
+            Err(_) => Ok(Async::Ready(verify())),
+        }
+    }
+}
+
+/// Check that the line sent by a client can be used as its name.
+///
+/// Returns the name as a string.
+pub(crate) fn validate_name(line: &[u8]) -> Result<String, String> {
+    let name = std::str::from_utf8(line)
+        .map_err(|_| "name must be valid UTF-8".to_string())?
+        .trim();
+
+    if name.is_empty() {
+        return Err("name can't be empty".to_string());
+    }
+
+    if name.chars().count() > MAX_NAME {
+        return Err(format!("name can't be longer than {} characters", MAX_NAME));
+    }
+
+    if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("name can't contain spaces or control characters".to_string());
+    }
+
+    if name.starts_with('/') {
+        return Err("name can't start with /".to_string());
+    }
+
+    // Names double as IRC nicknames, which can't contain these.
+    if name.starts_with('#') || name.starts_with(':') {
+        return Err("name can't start with # or :".to_string());
+    }
+
+    if name.contains(['!', '@', ',']) {
+        return Err("name can't contain !, @ or ,".to_string());
+    }
+
+    Ok(name.to_string())
+}
+
+impl Peer {
+    /// Create a new instance of `Peer`.
+    ///
+    /// The client at `addr` must already be registered in the shared state
+    /// under `name` with the `tx` half of its message channel. The protocol,
+    /// the shared state, the commands and the settings are those of the
+    /// `handshake` the client went through.
+    ///
+    /// Telnet and JSON clients start in the default room. IRC clients start
+    /// in no room, as they expect to `JOIN` channels themselves.
+    pub(crate) fn new(
+        handshake: &Handshake,
+        addr: SocketAddr,
+        name: String,
+        lines: Lines<Connection>,
+        rx: Rx<Arc<Event>>,
+        log: Context,
+    ) -> Peer {
+        let protocol = handshake.protocol;
+        let config = handshake.config.clone();
+
+        let limiter = Limiter::new(config.flood);
+        let idle = match config.keepalive {
+            0 => None,
+            secs => Some(Delay::new(Instant::now() + Duration::from_secs(secs))),
+        };
+
+        let shared = handshake.state.clone();
+        let mut state = shared.lock().unwrap();
+        let traffic = match state.peers.get(&addr) {
+            Some(client) => client.traffic.clone(),
+            None => Arc::default(),
+        };
+
+        let mut peer = Peer {
+            name,
+            protocol,
+            lines,
+            state: shared.clone(),
+            commands: handshake.commands.clone(),
+            config,
+            rx,
+            addr,
+            rooms: BTreeSet::new(),
+            limiter,
+            idle,
+            pinged: false,
+            departure: Departure::Quit,
+            closing: None,
+            traffic,
+            metrics: state.metrics.clone(),
+            log,
+        };
+
+        if protocol != Protocol::Irc {
+            // Place the peer in the default room and catch it up on what was
+            // said before it arrived. Both happen under the same lock, so no
+            // message is missed or replayed twice.
+            state.join(DEFAULT_ROOM, addr);
+            peer.rooms.insert(DEFAULT_ROOM.to_string());
+            peer.replay(&state.history(DEFAULT_ROOM, HISTORY_REPLAY));
+        }
+
+        peer
+    }
+
+    /// Send a line to this peer only.
+    ///
+    /// The line is written straight to the write buffer, so it can't be
+    /// dropped by the queue's overflow policy. IRC clients get it as a
+    /// `NOTICE` from the server, JSON clients as a `reply`.
+    pub(crate) fn reply(&mut self, message: &str) {
+        let line = match self.protocol {
+            Protocol::Telnet => format!("* {}\r\n", message),
+            Protocol::Irc => format!(
+                ":{} NOTICE {} :{}\r\n",
+                irc::SERVER_NAME,
+                self.name,
+                message
+            ),
+            Protocol::Json => {
+                return json::send(&mut self.lines, &Response::Reply { text: message })
+            }
+        };
+        self.lines.buffer(line.as_bytes());
+    }
+
+    /// Take the name the client was just renamed to in the shared state.
+    pub(crate) fn renamed(&mut self, name: String) {
+        info!(context: self.log, "renamed", new_name = name);
+        self.log.set("name", &name);
+        self.name = name;
+    }
+
+    /// Write an event to the client, in the client's protocol.
+    pub(crate) fn send_event(&mut self, event: &Event) {
+        match self.protocol {
+            Protocol::Telnet => {
+                let line = match event {
+                    Event::Message {
+                        room,
+                        from,
+                        text,
+                        action: false,
+                    } => format!("[{}] {}: {}\r\n", room, from, text),
+                    Event::Message {
+                        room,
+                        from,
+                        text,
+                        action: true,
+                    } => format!("[{}] * {} {}\r\n", room, from, text),
+                    Event::Private {
+                        from,
+                        text,
+                        action: false,
+                    } => format!("[private] {}: {}\r\n", from, text),
+                    Event::Private {
+                        from,
+                        text,
+                        action: true,
+                    } => format!("[private] * {} {}\r\n", from, text),
+                    Event::Joined { room, name } => format!("[{}] * {} joined\r\n", room, name),
+                    Event::Parted { room, name } => format!("[{}] * {} left\r\n", room, name),
+                    Event::Quit {
+                        name,
+                        departure: Departure::Quit,
+                    } => format!("* {} left the chat\r\n", name),
+                    Event::Quit {
+                        name,
+                        departure: Departure::Timeout,
+                    } => format!("* {} timed out\r\n", name),
+                    Event::Renamed { old, new } => format!("* {} is now known as {}\r\n", old, new),
+                    Event::Notice { text } => format!("* {}\r\n", text),
+                    Event::Disconnect { reason } => format!("* {}\r\n", reason),
+                };
+                self.lines.buffer(line.as_bytes());
+            }
+            Protocol::Irc => irc::render(&mut self.lines, &self.name, event),
+            Protocol::Json => json::render(&mut self.lines, event, false),
+        }
+    }
+
+    /// Write lines of a room's history to the client.
+    ///
+    /// Telnet clients see each line marked with `[history]` so they can tell
+    /// it apart from live messages, and JSON clients with `history` set. IRC
+    /// has no such marker, the lines are sent as regular messages.
+    pub(crate) fn replay(&mut self, history: &[Arc<Event>]) {
+        for event in history {
+            match self.protocol {
+                Protocol::Telnet => {
+                    self.lines.buffer(b"[history] ");
+                    self.send_event(event);
+                }
+                Protocol::Irc => self.send_event(event),
+                Protocol::Json => json::render(&mut self.lines, event, true),
+            }
+        }
+    }
+
+    /// Send a message from this peer to the members of its rooms.
+    pub(crate) fn broadcast(
+        &self,
+        state: &mut Shared,
+        text: &str,
+        action: bool,
+    ) -> Result<(), String> {
+        if self.rooms.is_empty() {
+            // Nobody would receive the message, so tell the sender instead of
+            // silently dropping it.
+            return Err("you are not in any room, use /join <room>".to_string());
+        }
+
+        state.check_muted(self.addr)?;
+
+        state.broadcast(self.addr, &self.rooms, text, action);
+        Ok(())
+    }
+
+    /// Handle a line read from the client.
+    pub(crate) fn handle_line(&mut self, line: &[u8]) -> Flow {
+        match self.protocol {
+            Protocol::Telnet => {}
+            Protocol::Irc => return irc::handle(self, line),
+            Protocol::Json => return json::handle(self, line),
+        }
+
+        let result = if line.starts_with(b"//") {
+            // A double slash escapes a message that starts with `/`.
+            self.say(&line[1..])
+        } else if line.starts_with(b"/") {
+            let commands = self.commands.clone();
+            commands.dispatch(self, &String::from_utf8_lossy(&line[1..]))
+        } else {
+            self.say(line)
+        };
+
+        match result {
+            Ok(flow) => flow,
+            Err(e) => {
+                self.reply(&format!("error: {}", e));
+                Flow::Continue
+            }
+        }
+    }
+
+    /// Deal with a line the rate limiter didn't allow.
+    fn flooding(&mut self, verdict: Verdict) {
+        let mut state = self.state.lock().unwrap();
+
+        match verdict {
+            Verdict::Allow | Verdict::Drop => {}
+            Verdict::Warn => {
+                drop(state);
+                self.reply("warning: you are sending too fast, slow down");
+            }
+            Verdict::Mute => {
+                let mute = Restriction::new(Some(Duration::from_secs(self.config.flood.mute)));
+                if let Some(client) = state.peers.get_mut(&self.addr) {
+                    client.muted = Some(mute);
+                }
+                drop(state);
+
+                info!(context: self.log, "muted for flooding");
+                self.reply(&format!("you are muted {} for flooding", mute.remaining()));
+            }
+            Verdict::Disconnect => {
+                // The notice goes through the queue like a kick, the peer
+                // closes the connection once it is written.
+                info!(context: self.log, "disconnected for flooding");
+                state.disconnect(self.addr, "disconnected for flooding");
+            }
+        }
+    }
+
+    /// Check whether the client has been silent for too long.
+    ///
+    /// The first time the timer fires, the client is pinged. IRC clients
+    /// answer with `PONG` on their own, JSON clients get a `ping` line and
+    /// telnet clients are asked to send `/pong`; any line will do. Returns
+    /// `true` if the client didn't answer in time and has been sent a
+    /// disconnection notice.
+    fn poll_idle(&mut self) -> Result<bool, io::Error> {
+        let idle = match self.idle {
+            Some(ref mut idle) => idle,
+            None => return Ok(false),
+        };
+
+        if idle.poll().map_err(io::Error::other)?.is_not_ready() {
+            return Ok(false);
+        }
+
+        if self.pinged {
+            info!(context: self.log, "timed out");
+            self.departure = Departure::Timeout;
+            self.send_event(&Event::Disconnect {
+                reason: "disconnected after too long without activity".to_string(),
+            });
+            return Ok(true);
+        }
+
+        // Poll the timer again, so that the task is woken up at the new
+        // deadline.
+        idle.reset(Instant::now() + Duration::from_secs(self.config.idle_timeout));
+        idle.poll().map_err(io::Error::other)?;
+        self.pinged = true;
+
+        match self.protocol {
+            Protocol::Telnet => self.reply("ping, send /pong to stay connected"),
+            Protocol::Irc => irc::ping(&mut self.lines),
+            Protocol::Json => json::send(&mut self.lines, &Response::Ping),
+        }
+        Ok(false)
+    }
+
+    /// Restart the idle timer, after a line was received.
+    fn active(&mut self) -> Result<(), io::Error> {
+        if let Some(ref mut idle) = self.idle {
+            idle.reset(Instant::now() + Duration::from_secs(self.config.keepalive));
+            idle.poll().map_err(io::Error::other)?;
+            self.pinged = false;
+        }
+        Ok(())
+    }
+
+    /// Publish the number of bytes exchanged with the client and of
+    /// messages dropped from its queue so far, adding what changed since the
+    /// last time to the server's metrics.
+    fn count_traffic(&self) {
+        let traffic = &self.traffic;
+        let metrics = &self.metrics;
+
+        let read = self.lines.bytes_read();
+        metrics
+            .read
+            .add(read - traffic.read.swap(read, Ordering::Relaxed));
+
+        let written = self.lines.bytes_written();
+        metrics
+            .written
+            .add(written - traffic.written.swap(written, Ordering::Relaxed));
+
+        let dropped = self.rx.dropped() as u64;
+        metrics
+            .dropped
+            .add(dropped - traffic.dropped.swap(dropped, Ordering::Relaxed));
+    }
+
+    /// Broadcast a regular chat message.
+    pub(crate) fn say(&mut self, message: &[u8]) -> Result<Flow, String> {
+        let text = String::from_utf8_lossy(message);
+
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
+        self.broadcast(&mut state, &text, false)?;
+        Ok(Flow::Continue)
+    }
+}
+
+/// This is where a connected client is managed.
+///
+/// A `Peer` is also a future representing completely processing the client.
+///
+/// When a `Peer` is created, the first line (representing the client's name)
+/// has already been read. When the socket closes, the `Peer` future completes.
+///
+/// While processing, the peer future implementation will:
+///
+/// 1) Receive messages on its message channel and write them to the socket.
+/// 2) Receive messages from the socket and broadcast them to all peers, or run
+///    them as commands if they start with `/`.
+///
+impl Future for Peer {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        // A closing peer has nothing left to do but write.
+        if let Some(ref mut deadline) = self.closing {
+            return poll_closing(&mut self.lines, deadline);
+        }
+
+        // Tokio (and futures) use cooperative scheduling without any
+        // preemption. If a task never yields execution back to the executor,
+        // then other tasks may be starved.
+        //
+        // To deal with this, robust applications should not have any unbounded
+        // loops. In this example, we will read at most `lines_per_tick` lines
+        // from the queue on each tick.
+        //
+        // If the limit is hit, the current task is notified, informing the
+        // executor to schedule the task again asap.
+        let lines_per_tick = self.config.lines_per_tick;
+
+        // Set when lines are left on the queue because the write buffer is
+        // full.
+        let mut backlog = false;
+
+        self.metrics.queue_depth.observe(self.rx.len() as f64);
+
+        // Receive all messages from peers.
+        for i in 0..lines_per_tick {
+            // Stop staging lines once the write buffer is full. The rest wait
+            // on the bounded queue, where the overflow policy applies if the
+            // client doesn't catch up.
+            if self.lines.buffered_len() >= self.config.write_buffer_limit {
+                backlog = true;
+                break;
+            }
+
+            // Polling the queue fails if it overflowed and the overflow policy
+            // is to disconnect the peer.
+            match self.rx.poll()? {
+                Async::Ready(Some(event)) => {
+                    // Buffer the line. Once all lines are buffered, they will
+                    // be flushed to the socket (right below).
+                    self.send_event(&event);
+
+                    // Nothing comes after a disconnection notice, close the
+                    // connection once it is written.
+                    if let Event::Disconnect { .. } = *event {
+                        self.closing = Some(close_deadline());
+                        break;
+                    }
+
+                    // If this is the last iteration, the loop will break even
+                    // though there could still be lines to read. Because we did
+                    // not reach `Async::NotReady`, we have to notify ourselves
+                    // in order to tell the executor to schedule the task again.
+                    if i + 1 == lines_per_tick {
+                        task::current().notify();
+                    }
+                }
+                Async::Ready(None) => {
+                    // The server is shutting down and every message queued for
+                    // this peer has been buffered. Say goodbye; the connection
+                    // is closed once the buffer is flushed.
+                    if self.closing.is_none() {
+                        match self.protocol {
+                            Protocol::Telnet => self.lines.buffer(b"* server shutting down\r\n"),
+                            Protocol::Irc => self.lines.buffer(b"ERROR :Server shutting down\r\n"),
+                            Protocol::Json => json::send(
+                                &mut self.lines,
+                                &Response::Disconnect {
+                                    reason: "server shutting down",
+                                },
+                            ),
+                        }
+                        self.closing = Some(close_deadline());
+                    }
+                    break;
+                }
+                Async::NotReady => break,
+            }
+        }
+
+        // Flush the write buffer to the socket. If the whole buffer was written
+        // and lines are still waiting on the queue, nothing else will wake the
+        // task, so notify it to stage the next batch.
+        let flushed = self.lines.poll_flush()?.is_ready();
+
+        if flushed && backlog {
+            task::current().notify();
+        }
+
+        if let Some(ref mut deadline) = self.closing {
+            // Don't read from clients during shutdown, just finish writing.
+            return poll_closing(&mut self.lines, deadline);
+        }
+
+        // Read new lines from the socket. Command replies are written straight
+        // to the write buffer, so stop reading while it is full and can't be
+        // flushed.
+        //
+        // Reading is bounded by `lines_per_tick` as well, so that a client
+        // pasting a large file doesn't hold up other tasks. Each line also
+        // has to get past the peer's rate limiter.
+        let mut read = 0;
+        while self.lines.buffered_len() < self.config.write_buffer_limit
+            || self.lines.poll_flush()?.is_ready()
+        {
+            if read == lines_per_tick {
+                // More lines may be waiting, come back for them on the next
+                // tick.
+                task::current().notify();
+                break;
+            }
+
+            let line = match self.lines.poll()? {
+                Async::Ready(line) => line,
+                Async::NotReady => break,
+            };
+            read += 1;
+
+            if let Some(ref line) = line {
+                trace!(
+                    context: self.log,
+                    "received line",
+                    line = String::from_utf8_lossy(line)
+                );
+            }
+
+            if let Some(message) = line {
+                match self.limiter.check() {
+                    Verdict::Allow => {}
+                    Verdict::Disconnect => {
+                        // Stop reading, the disconnection notice is already
+                        // on its way.
+                        self.flooding(Verdict::Disconnect);
+                        break;
+                    }
+                    verdict => {
+                        self.flooding(verdict);
+                        continue;
+                    }
+                }
+
+                if let Flow::Quit = self.handle_line(&message) {
+                    // Write out the reply to `/quit` and whatever is still
+                    // queued for the client before closing the connection.
+                    while let Ok(Async::Ready(Some(event))) = self.rx.poll() {
+                        self.send_event(&event);
+                    }
+                    let deadline = self.closing.get_or_insert_with(close_deadline);
+                    return poll_closing(&mut self.lines, deadline);
+                }
+            } else {
+                // EOF was reached. The remote client has disconnected. There is
+                // nothing more to do.
+                return Ok(Async::Ready(()));
+            }
+        }
+
+        if read > 0 {
+            self.active()?;
+        }
+
+        if self.poll_idle()? {
+            // Write out the disconnection notice, like the reply to `/quit`.
+            let deadline = self.closing.get_or_insert_with(close_deadline);
+            return poll_closing(&mut self.lines, deadline);
+        }
+
+        // Flush the replies to the commands that were just handled, and the
+        // keepalive ping.
+        self.lines.poll_flush()?;
+        self.count_traffic();
+
+        // As always, it is important to not just return `NotReady` without
+        // ensuring an inner future also returned `NotReady`.
+        //
+        // We know we got a `NotReady` from either `self.rx` or `self.lines`, so
+        // the contract is respected.
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.count_traffic();
+        let mut state = self.state.lock().unwrap();
+
+        state.quit(self.addr, &self.rooms, self.departure);
+
+        if let Some(client) = state.remove_peer(self.addr) {
+            let reason = match self.departure {
+                Departure::Quit => "quit",
+                Departure::Timeout => "timeout",
+            };
+            info!(
+                context: self.log,
+                "left the chat",
+                reason = reason,
+                read = self.lines.bytes_read(),
+                written = self.lines.bytes_written(),
+                dropped = client.tx.dropped()
+            );
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Bounded per-peer message queues.
+
+use futures::task::AtomicTask;
+use serde::{Deserialize, Serialize};
+use tokio::io;
+use tokio::prelude::*;
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Transmit half of a peer's message channel.
+///
+/// Sending never blocks. If the peer's queue is full, the queue's `Overflow`
+/// policy decides what happens to the message.
+pub struct Tx<T>(Arc<Queue<T>>);
+
+/// Receive half of a peer's message channel.
+pub struct Rx<T>(Arc<Queue<T>>);
+
+/// Bounded queue of messages waiting to be written to a peer's socket.
+///
+/// A peer that doesn't read from its socket stops draining this queue. The
+/// queue is bounded so that a stalled client can't make the server buffer an
+/// unlimited amount of data on its behalf.
+struct Queue<T> {
+    lines: Mutex<QueueState<T>>,
+
+    /// Task of the `Peer` reading from the queue, notified on every send.
+    task: AtomicTask,
+
+    /// Settings the queue was created with.
+    config: QueueConfig,
+
+    /// Number of messages that were dropped because the queue was full.
+    dropped: AtomicUsize,
+}
+
+struct QueueState<T> {
+    lines: VecDeque<T>,
+
+    /// Set when the queue overflowed under the `Disconnect` policy.
+    closed: bool,
+
+    /// Set when the server is shutting down. The queue ends once the lines
+    /// already in it have been taken.
+    shutdown: bool,
+
+    /// Set once a last message was queued with `close`. Messages sent after
+    /// it are discarded.
+    finished: bool,
+}
+
+/// Size and overflow policy of the per-peer queues.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Maximum number of lines waiting for a peer.
+    pub capacity: usize,
+
+    #[serde(rename = "overflow")]
+    pub policy: Overflow,
+}
+
+/// What to do with a message sent to a peer whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Discard the oldest queued message to make room for the new one.
+    DropOldest,
+
+    /// Discard the new message.
+    DropNewest,
+
+    /// Disconnect the peer.
+    Disconnect,
+}
+
+/// Create a bounded message channel for a peer.
+pub fn channel<T>(config: QueueConfig) -> (Tx<T>, Rx<T>) {
+    let queue = Arc::new(Queue {
+        lines: Mutex::new(QueueState {
+            lines: VecDeque::new(),
+            closed: false,
+            shutdown: false,
+            finished: false,
+        }),
+        task: AtomicTask::new(),
+        config,
+        dropped: AtomicUsize::new(0),
+    });
+
+    (Tx(queue.clone()), Rx(queue))
+}
+
+impl<T> Clone for Tx<T> {
+    fn clone(&self) -> Tx<T> {
+        Tx(self.0.clone())
+    }
+}
+
+impl<T> Tx<T> {
+    /// Queue a message for the peer.
+    ///
+    /// If the queue is full, the message is handled according to the queue's
+    /// `Overflow` policy.
+    pub fn send(&self, line: T) {
+        let queue = &self.0;
+        let mut state = queue.lines.lock().unwrap();
+
+        if state.closed {
+            queue.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        if state.finished {
+            return;
+        }
+
+        if state.lines.len() >= queue.config.capacity {
+            queue.dropped.fetch_add(1, Ordering::Relaxed);
+
+            match queue.config.policy {
+                Overflow::DropOldest => {
+                    state.lines.pop_front();
+                }
+                Overflow::DropNewest => return,
+                Overflow::Disconnect => {
+                    state.lines.clear();
+                    state.closed = true;
+                    drop(state);
+
+                    // Wake the peer so it notices it has been disconnected.
+                    queue.task.notify();
+                    return;
+                }
+            }
+        }
+
+        state.lines.push_back(line);
+        drop(state);
+
+        queue.task.notify();
+    }
+
+    /// End the queue once the lines already in it have been taken.
+    pub fn shutdown(&self) {
+        self.0.lines.lock().unwrap().shutdown = true;
+        self.0.task.notify();
+    }
+
+    /// Replace the queued messages with a last one, then end the queue.
+    ///
+    /// The message skips the capacity check, so it is never dropped by the
+    /// overflow policy.
+    pub fn close(&self, line: T) {
+        let mut state = self.0.lines.lock().unwrap();
+        if state.closed || state.finished {
+            return;
+        }
+
+        state.lines.clear();
+        state.lines.push_back(line);
+        state.finished = true;
+        state.shutdown = true;
+        drop(state);
+
+        self.0.task.notify();
+    }
+
+    /// Number of messages waiting in the peer's queue.
+    pub fn len(&self) -> usize {
+        self.0.lines.lock().unwrap().lines.len()
+    }
+
+    /// Whether no message is waiting in the peer's queue.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of messages dropped because the peer's queue was full.
+    pub fn dropped(&self) -> usize {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Rx<T> {
+    /// Number of messages waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.0.lines.lock().unwrap().lines.len()
+    }
+
+    /// Whether no message is waiting in the queue.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of messages dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Stream for Rx<T> {
+    type Item = T;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, io::Error> {
+        let queue = &self.0;
+
+        // Register the task before looking at the queue, so a line sent right
+        // after the check still wakes us up.
+        queue.task.register();
+
+        let mut state = queue.lines.lock().unwrap();
+
+        if state.closed {
+            return Err(io::Error::other("peer is too slow, its queue overflowed"));
+        }
+
+        match state.lines.pop_front() {
+            Some(line) => Ok(Async::Ready(Some(line))),
+            None if state.shutdown => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        match s {
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "drop-newest" => Ok(Overflow::DropNewest),
+            "disconnect" => Ok(Overflow::Disconnect),
+            _ => Err(format!(
+                "unknown overflow policy `{}`, expected drop-oldest, drop-newest or disconnect",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> QueueConfig {
+        QueueConfig {
+            capacity: 256,
+            policy: Overflow::DropOldest,
+        }
+    }
+}
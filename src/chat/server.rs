@@ -0,0 +1,515 @@
+//! Accepting clients and shutting down.
+
+use futures::future::{self, Either};
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio::timer::{Delay, Interval};
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+use tokio_tls::TlsAcceptor;
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::admin;
+use super::auth::Auth;
+use super::commands::Commands;
+use super::config::Config;
+use super::connection::Connection;
+use super::federation::{self, Federation};
+use super::moderation::Bans;
+use super::peer::{Handshake, Protocol};
+use super::state::{Shared, HISTORY_SIZE};
+use super::transcript::Transcript;
+use crate::codec::{Lines, WebSocket};
+use crate::log::Context;
+use crate::metrics::{self, Counter};
+use crate::tls;
+use crate::{debug, error, info, warn};
+
+/// How long connected clients are given to receive their pending messages
+/// when the server shuts down, or when their connection is being closed.
+pub(crate) const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How long a listener waits after failing to accept a connection. Errors
+/// such as running out of file descriptors fail every attempt until some
+/// connections are closed.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Id of the next connection, telling apart the events of each connection in
+/// the logs.
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+/// What clients connecting to a listener speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Telnet,
+    Irc,
+
+    /// The telnet protocol, one line per WebSocket text message.
+    WebSocket,
+}
+
+impl Endpoint {
+    /// Name of the endpoint in the logs.
+    fn name(self) -> &'static str {
+        match self {
+            Endpoint::Telnet => "telnet",
+            Endpoint::Irc => "irc",
+            Endpoint::WebSocket => "websocket",
+        }
+    }
+
+    /// The protocol of the lines exchanged with the client.
+    fn protocol(self) -> Protocol {
+        match self {
+            Endpoint::Telnet | Endpoint::WebSocket => Protocol::Telnet,
+            Endpoint::Irc => Protocol::Irc,
+        }
+    }
+}
+
+/// Spawn a task to manage the socket.
+///
+/// This will read lines from the socket until the client sends an acceptable
+/// name, then add the client to the set of connected peers in the chat service.
+fn process(
+    socket: TcpStream,
+    endpoint: Endpoint,
+    tls: Option<TlsAcceptor>,
+    state: Arc<Mutex<Shared>>,
+    commands: Arc<Commands>,
+    config: Arc<Config>,
+    connections: Arc<AtomicUsize>,
+) {
+    let handshake_timeout = config.handshake_timeout;
+
+    // Every event of the connection carries these fields, and the client's
+    // name once it has one.
+    let mut log = Context::new()
+        .with("conn", NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed))
+        .with("endpoint", endpoint.name());
+    if let Ok(addr) = socket.peer_addr() {
+        log.set("addr", addr);
+    }
+    if tls.is_some() {
+        log.set("tls", true);
+    }
+    debug!(context: log, "connection accepted");
+
+    // The TLS handshake comes first, if the listener has one.
+    let connection = match tls {
+        Some(tls) => Either::A(
+            tls.accept(socket)
+                .map(Connection::Tls)
+                .map_err(io::Error::other),
+        ),
+        None => Either::B(future::ok(Connection::Tcp(socket))),
+    };
+
+    let handshake_log = log.clone();
+    let timeout_log = log.clone();
+    let connection = connection
+        // WebSocket clients then upgrade their connection over HTTP.
+        .and_then(move |connection| match endpoint {
+            Endpoint::WebSocket => Either::A(
+                WebSocket::accept(connection)
+                    .map(|connection| Connection::WebSocket(Box::new(connection))),
+            ),
+            Endpoint::Telnet | Endpoint::Irc => Either::B(future::ok(connection)),
+        })
+        .and_then(move |connection| {
+            // Wrap the connection with the `Lines` codec.
+            //
+            // By doing this, we can operate at the line level instead of doing
+            // raw byte manipulation.
+            let lines = Lines::new(connection)
+                .max_line_length(config.max_line_length)
+                .long_lines(config.long_lines)
+                .read_reserve(config.read_reserve);
+
+            // The first acceptable line is treated as the client's name. The
+            // client is not added to the set of connected peers until this
+            // line is received.
+            Handshake::new(
+                lines,
+                endpoint.protocol(),
+                state,
+                commands,
+                config,
+                handshake_log,
+            )
+        });
+
+    // Clients that take too long to get through all of the above are
+    // dropped. This also frees the connections of clients that opened a
+    // socket and never sent anything.
+    let connection = match handshake_timeout {
+        0 => Either::A(connection),
+        secs => Either::B(
+            connection
+                .timeout(Duration::from_secs(secs))
+                .or_else(move |e| {
+                    if e.is_elapsed() {
+                        info!(context: timeout_log, "handshake timed out");
+                        Ok(None)
+                    } else if e.is_inner() {
+                        Err(e.into_inner().unwrap())
+                    } else {
+                        // The timer failed.
+                        Err(io::Error::other(e))
+                    }
+                }),
+        ),
+    };
+
+    let connection = connection
+        .and_then(|peer| {
+            // If `peer` is `None`, then the client disconnected, or timed
+            // out, without sending an acceptable name.
+            //
+            // Since the connection is closed, there is no further work that we
+            // need to do. So, we just terminate processing by returning
+            // `future::ok()`.
+            //
+            // The problem is that only a single future type can be returned
+            // from a combinator closure, but we want to return both
+            // `future::ok()` and `Peer` (below).
+            //
+            // This is a common problem, so the `futures` crate solves this by
+            // providing the `Either` helper enum that allows creating a single
+            // return type that covers two concrete future types.
+            //
+            // The `Peer` is also a future that processes the connection, only
+            // completing when the socket closes.
+            match peer {
+                // Wrap `peer` with `Either::B` to make the return type fit.
+                Some(peer) => Either::B(peer),
+                None => Either::A(future::ok(())),
+            }
+        })
+        // Task futures have an error of type `()`, this ensures we handle the
+        // error. We do this by logging it.
+        .map_err(move |e| {
+            warn!(context: log, "connection error", error = e);
+        })
+        // The connection no longer counts towards `max_clients`.
+        .then(move |result| {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            result
+        });
+
+    // Spawn the task. Internally, this submits the task to a thread pool.
+    tokio::spawn(connection);
+}
+
+/// Why a client is turned away before the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rejection {
+    /// The server already has `max_clients` connections.
+    Full,
+
+    /// The client's address is banned.
+    Banned,
+}
+
+/// Turn away a client.
+///
+/// Clients of TLS listeners are disconnected without a notice, they couldn't
+/// read one before the TLS handshake.
+fn reject(socket: TcpStream, endpoint: Endpoint, tls: bool, rejection: Rejection) {
+    if tls {
+        return;
+    }
+
+    let notice = match (endpoint, rejection) {
+        (Endpoint::Telnet, Rejection::Full) => "* server is full, try again later\r\n",
+        (Endpoint::Telnet, Rejection::Banned) => "* you are banned from this server\r\n",
+        (Endpoint::Irc, Rejection::Full) => "ERROR :Server is full, try again later\r\n",
+        (Endpoint::Irc, Rejection::Banned) => "ERROR :You are banned from this server\r\n",
+        (Endpoint::WebSocket, Rejection::Full) => {
+            "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        }
+        (Endpoint::WebSocket, Rejection::Banned) => {
+            "HTTP/1.1 403 Forbidden\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        }
+    };
+    let rejection = io::write_all(socket, notice)
+        .map(|_| ())
+        .map_err(|e| debug!("rejection notice not sent", error = e));
+
+    tokio::spawn(rejection);
+}
+
+/// Run the chat server until it receives SIGINT or SIGTERM.
+///
+/// This blocks the calling thread. On a signal, the listeners are closed,
+/// every client is told that the server is shutting down and given
+/// `SHUTDOWN_GRACE` to receive its pending messages, and the transcript is
+/// closed.
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let config = Arc::new(config);
+
+    // Create the shared state. This is how all the peers communicate.
+    //
+    // The server task will hold a handle to this. For every new client, the
+    // `state` handle is cloned and passed into the task that processes the
+    // client connection.
+    //
+    // Each peer gets a bounded queue, sized by the settings.
+    let mut shared = Shared::new(config.queue);
+    shared.announce = config.announce.clone();
+
+    // The history of the rooms is rebuilt from the transcript before any
+    // client connects.
+    if let Some(config) = config.transcript.clone() {
+        let records = Transcript::recover(&config, HISTORY_SIZE)?;
+        info!(
+            "transcript recovered",
+            records = records.len(),
+            path = config.path.display()
+        );
+        shared.restore(records);
+        shared.transcript = Some(Transcript::open(config)?);
+    }
+
+    if let Some(ref config) = config.auth {
+        let auth = Auth::load(config)?;
+        info!(
+            "accounts loaded",
+            accounts = auth.len(),
+            path = config.credentials.display()
+        );
+        shared.auth = Some(Arc::new(auth));
+    }
+
+    shared.bans = Bans::load(config.bans.as_deref())?;
+    if let Some(ref path) = config.bans {
+        info!(
+            "bans loaded",
+            bans = shared.bans.len(),
+            path = path.display()
+        );
+    }
+
+    if let Some(ref federation) = config.federation {
+        shared.federation = Some(Federation::new(federation, config.max_line_length));
+    }
+
+    let metrics = shared.metrics.clone();
+    let state = Arc::new(Mutex::new(shared));
+
+    // The command registry is read-only once the server is running, so it is
+    // shared between peers without a lock.
+    let commands = Arc::new(Commands::builtin());
+
+    // Connections currently open on any of the listeners, checked against
+    // `max_clients`.
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    // Clients are told apart by the address they connect to.
+    let mut endpoints: Vec<(SocketAddr, Endpoint, Option<TlsAcceptor>)> = Vec::new();
+    let listen = |addrs: &[SocketAddr], endpoint, tls: &Option<TlsAcceptor>| {
+        addrs
+            .iter()
+            .map(move |addr| (*addr, endpoint, tls.clone()))
+            .collect::<Vec<_>>()
+    };
+
+    endpoints.extend(listen(&config.listen, Endpoint::Telnet, &None));
+    endpoints.extend(listen(&config.irc_listen, Endpoint::Irc, &None));
+    endpoints.extend(listen(&config.websocket_listen, Endpoint::WebSocket, &None));
+
+    // All the TLS listeners share the same certificate.
+    if let Some(ref config) = config.tls {
+        let acceptor = Some(tls::acceptor(&config.cert, &config.key)?);
+        endpoints.extend(listen(&config.listen, Endpoint::Telnet, &acceptor));
+        endpoints.extend(listen(&config.irc_listen, Endpoint::Irc, &acceptor));
+        endpoints.extend(listen(
+            &config.websocket_listen,
+            Endpoint::WebSocket,
+            &acceptor,
+        ));
+    }
+
+    let mut servers = Vec::new();
+    for (addr, endpoint, tls) in endpoints {
+        // Bind a TCP listener to the socket address.
+        //
+        // Note that this is the Tokio TcpListener, which is fully async.
+        let listener = TcpListener::bind(&addr)?;
+        let encrypted = tls.is_some();
+
+        // The server task asynchronously iterates over and processes each
+        // incoming connection.
+        let server_state = state.clone();
+        let commands = commands.clone();
+        let config = config.clone();
+        let connections = connections.clone();
+        let metrics = metrics.clone();
+        let errors = metrics.accept_errors.clone();
+        let server =
+            keep_accepting(listener.incoming(), addr.to_string(), errors).for_each(move |socket| {
+                metrics.accepted.inc();
+
+                // A client that is already gone has no address.
+                let ip = match socket.peer_addr() {
+                    Ok(addr) => addr.ip(),
+                    Err(e) => {
+                        debug!("connection dropped", listener = addr, error = e);
+                        return Ok(());
+                    }
+                };
+
+                // Banned clients are turned away before anything is read from
+                // them.
+                if server_state.lock().unwrap().bans.check(ip).is_some() {
+                    info!("connection rejected", addr = ip, reason = "banned");
+                    metrics.rejected.inc();
+                    reject(socket, endpoint, tls.is_some(), Rejection::Banned);
+                    return Ok(());
+                }
+
+                if connections.fetch_add(1, Ordering::SeqCst) >= config.max_clients {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    warn!("connection rejected", addr = ip, reason = "full");
+                    metrics.rejected.inc();
+                    reject(socket, endpoint, tls.is_some(), Rejection::Full);
+                    return Ok(());
+                }
+
+                // Spawn a task to process the connection
+                process(
+                    socket,
+                    endpoint,
+                    tls.clone(),
+                    server_state.clone(),
+                    commands.clone(),
+                    config.clone(),
+                    connections.clone(),
+                );
+                Ok(())
+            });
+
+        info!(
+            "server running",
+            addr = addr,
+            endpoint = endpoint.name(),
+            tls = encrypted
+        );
+        servers.push(server);
+    }
+
+    // The listeners run until the server is stopped.
+    let server = future::select_all(servers).map(|_| ()).map_err(|_| ());
+
+    // Start the Tokio runtime.
+    //
+    // The Tokio is a pre-configured "out of the box" runtime for building
+    // asynchronous applications. It includes both a reactor and a task
+    // scheduler. This means applications are multithreaded by default.
+    let mut runtime = Runtime::new()?;
+
+    // Links to other servers run on the same runtime, next to the clients.
+    if let Some(ref config) = config.federation {
+        federation::start(config, state.clone(), &mut runtime)?;
+    }
+
+    if let Some(ref path) = config.admin_socket {
+        admin::start(path, state.clone(), config.clone(), &mut runtime)?;
+    }
+
+    for addr in &config.metrics_listen {
+        runtime.spawn(metrics::serve(addr, metrics.registry.clone())?);
+        info!("serving metrics", url = format!("http://{}/metrics", addr));
+    }
+
+    // Run the server until it fails or the process receives SIGINT or
+    // SIGTERM. Either way, the listener is dropped when `block_on` returns,
+    // so no new connections are accepted after this.
+    let stopped = server.select2(shutdown_signal().map_err(|err| {
+        error!("signal error", error = err);
+    }));
+    if runtime.block_on(stopped).is_err() {
+        // The signal handler failed and has already reported why.
+        return Ok(());
+    }
+
+    info!("shutting down", peers = state.lock().unwrap().peers.len());
+
+    // Tell the connected clients, then give them `SHUTDOWN_GRACE` to receive
+    // what is still queued for them.
+    state.lock().unwrap().shutdown();
+
+    let drained =
+        wait_for_peers(state.clone()).select2(Delay::new(Instant::now() + SHUTDOWN_GRACE));
+    let _ = runtime.block_on(drained);
+
+    // Drop the peers that didn't make it in time, as well as clients still
+    // in the name handshake.
+    runtime.shutdown_now().wait().unwrap();
+
+    // Every peer has recorded its leave events by now, so the transcript can
+    // be closed.
+    let transcript = state.lock().unwrap().transcript.take();
+    if let Some(transcript) = transcript {
+        transcript.close();
+    }
+
+    if let Some(ref path) = config.admin_socket {
+        let _ = std::fs::remove_file(path);
+    }
+
+    Ok(())
+}
+
+/// The connections accepted by the listener `listener`, from `incoming`.
+///
+/// A failed accept doesn't end the stream, and with it the listener: it is
+/// logged and counted in `errors`, and the listener tries again after
+/// `ACCEPT_ERROR_DELAY`.
+pub(crate) fn keep_accepting<S>(
+    incoming: S,
+    listener: String,
+    errors: Counter,
+) -> impl Stream<Item = S::Item, Error = ()>
+where
+    S: Stream<Error = io::Error>,
+{
+    incoming
+        .then(move |result| match result {
+            Ok(socket) => Either::A(future::ok(Some(socket))),
+            Err(err) => {
+                errors.inc();
+                error!("accept error", listener = listener, error = err);
+                Either::B(Delay::new(Instant::now() + ACCEPT_ERROR_DELAY).then(|_| Ok(None)))
+            }
+        })
+        .filter_map(|socket| socket)
+}
+
+/// Resolve once the process receives SIGINT or SIGTERM.
+fn shutdown_signal() -> impl Future<Item = (), Error = io::Error> {
+    let interrupt = Signal::new(SIGINT).flatten_stream();
+    let terminate = Signal::new(SIGTERM).flatten_stream();
+
+    interrupt
+        .select(terminate)
+        .into_future()
+        .map(|_| ())
+        .map_err(|(err, _)| err)
+}
+
+/// Resolve once every peer has disconnected.
+fn wait_for_peers(state: Arc<Mutex<Shared>>) -> impl Future<Item = (), Error = ()> {
+    // Peers leave the shared state when they are dropped, which doesn't wake
+    // anyone up, so check every now and then.
+    Interval::new_interval(Duration::from_millis(50))
+        .map_err(|err| error!("timer error", error = err))
+        .take_while(move |_| Ok(!state.lock().unwrap().peers.is_empty()))
+        .for_each(|_| Ok(()))
+}
@@ -0,0 +1,734 @@
+//! State shared between all peers.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::auth::Auth;
+use super::event::{Announcement, Departure, Event};
+use super::federation::{Body, Federation};
+use super::metrics::Metrics;
+use super::moderation::{Bans, Restriction};
+use super::peer::Protocol;
+use super::queue::{QueueConfig, Tx};
+use super::transcript::{now_millis, Record, RecordKind, Transcript};
+
+/// Number of recent lines kept for each room.
+pub(crate) const HISTORY_SIZE: usize = 100;
+
+/// Number of lines of history replayed to a client when it enters a room.
+pub(crate) const HISTORY_REPLAY: usize = 10;
+
+/// Number of empty rooms kept for their history. Past it, the room that has
+/// been empty the longest is dropped.
+pub(crate) const MAX_IDLE_ROOMS: usize = 256;
+
+/// `text` without its control characters.
+///
+/// Every message goes through it on its way in, local or relayed: the
+/// protocols end lines with `\r\n`, and a message holding one would
+/// otherwise forge lines in the streams of the clients it reaches.
+pub(crate) fn strip_controls(text: &str) -> Cow<'_, str> {
+    if text.contains(char::is_control) {
+        Cow::Owned(text.chars().filter(|c| !c.is_control()).collect())
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Data that is shared between all peers in the chat server.
+///
+/// This is the set of `Tx` handles for all connected clients and the members
+/// of every room. Whenever a message is received from a client, it is
+/// broadcasted to the members of the client's rooms by looking their
+/// addresses up in `peers` and sending the message's `Event` on each `Tx`.
+pub(crate) struct Shared {
+    pub(crate) peers: HashMap<SocketAddr, Client>,
+
+    /// Settings used to create the queue of every new peer.
+    pub(crate) queue: QueueConfig,
+
+    /// Rooms keyed by room name.
+    ///
+    /// A room is created when the first peer joins it and removed once it has
+    /// neither members nor history, or once it is pushed out of `idle_rooms`.
+    pub(crate) rooms: HashMap<String, Room>,
+
+    /// Names of the rooms that have history but no members, the one that has
+    /// been empty the longest first. At most `MAX_IDLE_ROOMS` long.
+    pub(crate) idle_rooms: VecDeque<String>,
+
+    /// Index of connected clients by name.
+    ///
+    /// Names are unique regardless of case, so the keys are lowercased. A
+    /// `BTreeMap` lets `/msg` find names by prefix.
+    pub(crate) names: BTreeMap<String, SocketAddr>,
+
+    /// On-disk log of joins, leaves and messages, if enabled.
+    pub(crate) transcript: Option<Transcript>,
+
+    /// The accounts protecting names, if authentication is enabled.
+    ///
+    /// Checking a password is slow, so clients clone the `Arc` and release
+    /// the lock before doing it.
+    pub(crate) auth: Option<Arc<Auth>>,
+
+    /// Addresses banned by operators, checked before accepting a connection.
+    pub(crate) bans: Bans,
+
+    /// The presence events announced to the members of a room.
+    pub(crate) announce: Vec<Announcement>,
+
+    /// Links to other servers, if federation is enabled.
+    pub(crate) federation: Option<Federation>,
+
+    /// Peers update the metrics without taking the lock, through their own
+    /// clone of the `Arc`.
+    pub(crate) metrics: Arc<Metrics>,
+}
+
+/// Bytes exchanged with a client and messages dropped from its queue, kept up
+/// to date by its `Peer`.
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    pub(crate) read: AtomicU64,
+    pub(crate) written: AtomicU64,
+    pub(crate) dropped: AtomicU64,
+}
+
+/// A chat room.
+#[derive(Default)]
+pub(crate) struct Room {
+    /// Addresses of the peers in the room.
+    pub(crate) members: HashSet<SocketAddr>,
+
+    /// The last `HISTORY_SIZE` messages broadcasted to the room, oldest
+    /// first.
+    pub(crate) history: VecDeque<Arc<Event>>,
+}
+
+/// The entry of a connected client in `Shared::peers`.
+pub(crate) struct Client {
+    /// Name of the client, as shown by `/who`.
+    pub(crate) name: String,
+
+    /// Transmit half of the client's message channel.
+    pub(crate) tx: Tx<Arc<Event>>,
+
+    /// Name of the account the client logged in to, if any.
+    pub(crate) account: Option<String>,
+
+    /// Whether the client may run the moderation commands. Clients logged in
+    /// to an operator account start as operators, others can be made one
+    /// with `/op`.
+    pub(crate) operator: bool,
+
+    /// Set while the client is muted.
+    pub(crate) muted: Option<Restriction>,
+
+    /// When the client last sent a message, for the idle times shown by
+    /// `/who`.
+    pub(crate) active: Instant,
+
+    pub(crate) protocol: Protocol,
+
+    pub(crate) traffic: Arc<Traffic>,
+}
+
+impl Shared {
+    /// Create a new, empty, instance of `Shared`.
+    pub(crate) fn new(queue: QueueConfig) -> Self {
+        Shared {
+            peers: HashMap::new(),
+            queue,
+            rooms: HashMap::new(),
+            idle_rooms: VecDeque::new(),
+            names: BTreeMap::new(),
+            transcript: None,
+            auth: None,
+            bans: Bans::default(),
+            announce: Announcement::ALL.to_vec(),
+            federation: None,
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+
+    /// Write an event to the transcript, if enabled.
+    pub(crate) fn record(&self, kind: RecordKind, room: &str, addr: SocketAddr, text: &str) {
+        if let Some(client) = self.peers.get(&addr) {
+            self.record_as(kind, room, &client.name, text);
+        }
+    }
+
+    /// Write an event of the client called `name` to the transcript, if
+    /// enabled.
+    fn record_as(&self, kind: RecordKind, room: &str, name: &str, text: &str) {
+        if let Some(ref transcript) = self.transcript {
+            transcript.write(Record {
+                time: now_millis(),
+                kind,
+                room: room.to_string(),
+                name: name.to_string(),
+                text: text.to_string(),
+            });
+        }
+    }
+
+    /// Tell the linked servers, if any, what a local client did.
+    fn relay(&mut self, body: Body) {
+        if let Some(ref mut federation) = self.federation {
+            federation.relay(body);
+        }
+    }
+
+    /// Name of the client at `addr`, or an empty string if it is gone.
+    fn name_of(&self, addr: SocketAddr) -> String {
+        self.peers
+            .get(&addr)
+            .map(|client| client.name.clone())
+            .unwrap_or_default()
+    }
+
+    /// Tell every connected peer that the server is shutting down.
+    ///
+    /// Each peer writes out the messages already queued for it, followed by
+    /// a shutdown notice, then closes its connection.
+    pub(crate) fn shutdown(&self) {
+        for client in self.peers.values() {
+            client.tx.shutdown();
+        }
+    }
+
+    /// Fill the history of the rooms with messages recovered from the
+    /// transcript.
+    pub(crate) fn restore(&mut self, records: Vec<Record>) {
+        for record in records {
+            let action = match record.kind {
+                RecordKind::Message => false,
+                RecordKind::Action => true,
+                RecordKind::Join | RecordKind::Leave => continue,
+            };
+
+            let room = record.room;
+            let event = Event::Message {
+                room: room.clone(),
+                from: record.name,
+                text: record.text,
+                action,
+            };
+            self.rooms
+                .entry(room.clone())
+                .or_default()
+                .remember(Arc::new(event));
+
+            // Idle rooms are kept in the order they were last written to.
+            if let Some(i) = self.idle_rooms.iter().position(|r| *r == room) {
+                self.idle_rooms.remove(i);
+            }
+            self.keep_idle(room);
+        }
+    }
+
+    /// Keep the empty `room` for its history, dropping the room that has
+    /// been empty the longest if there are too many.
+    fn keep_idle(&mut self, room: String) {
+        self.idle_rooms.push_back(room);
+        if self.idle_rooms.len() > MAX_IDLE_ROOMS {
+            if let Some(oldest) = self.idle_rooms.pop_front() {
+                self.rooms.remove(&oldest);
+            }
+        }
+    }
+
+    /// Check that a client logged in to `account` may use `name`.
+    ///
+    /// The name of an account can only be used by clients logged in to it.
+    fn check_account(&self, name: &str, account: Option<&str>) -> Result<(), String> {
+        let auth = match self.auth {
+            Some(ref auth) => auth,
+            None => return Ok(()),
+        };
+
+        let owner = account.map(str::to_lowercase);
+        if auth.is_registered(name) && owner != Some(name.to_lowercase()) {
+            return Err(format!("{} is registered, log in to use it", name));
+        }
+        Ok(())
+    }
+
+    /// Register a connected client.
+    ///
+    /// Fails if another client already uses the same name, or if the name
+    /// belongs to an account the client isn't logged in to.
+    pub(crate) fn add_peer(&mut self, addr: SocketAddr, client: Client) -> Result<(), String> {
+        self.check_account(&client.name, client.account.as_deref())?;
+
+        let key = client.name.to_lowercase();
+
+        if self.names.contains_key(&key) {
+            return Err(format!("{} is already taken", client.name));
+        }
+
+        self.names.insert(key, addr);
+        self.peers.insert(addr, client);
+        self.metrics.clients.inc();
+        self.metrics.peers.inc();
+        Ok(())
+    }
+
+    /// Remove a client, returning its entry.
+    pub(crate) fn remove_peer(&mut self, addr: SocketAddr) -> Option<Client> {
+        let client = self.peers.remove(&addr)?;
+        self.names.remove(&client.name.to_lowercase());
+        self.metrics.peers.dec();
+        Some(client)
+    }
+
+    /// Change the name of a connected client.
+    ///
+    /// Fails if another client already uses the new name, or if the name
+    /// belongs to an account the client isn't logged in to. A client may
+    /// change the case of its own name.
+    pub(crate) fn rename(&mut self, addr: SocketAddr, name: &str) -> Result<(), String> {
+        let account = self
+            .peers
+            .get(&addr)
+            .and_then(|client| client.account.clone());
+        self.check_account(name, account.as_deref())?;
+
+        let key = name.to_lowercase();
+
+        match self.names.get(&key) {
+            Some(owner) if *owner != addr => {
+                return Err(format!("{} is already taken", name));
+            }
+            _ => {}
+        }
+
+        let client = match self.peers.get_mut(&addr) {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+
+        let old = std::mem::replace(&mut client.name, name.to_string());
+        self.names.remove(&old.to_lowercase());
+        self.names.insert(key, addr);
+
+        if old == name {
+            return Ok(());
+        }
+
+        if self.announce.contains(&Announcement::Nick) {
+            let event = Arc::new(Event::Renamed {
+                old: old.clone(),
+                new: name.to_string(),
+            });
+            self.notify(self.neighbours(addr), event);
+        }
+        self.relay(Body::Nick {
+            old,
+            new: name.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Record that the client at `addr` sent a message.
+    pub(crate) fn touch(&mut self, addr: SocketAddr) {
+        if let Some(client) = self.peers.get_mut(&addr) {
+            client.active = Instant::now();
+        }
+    }
+
+    /// Whether the client at `addr` is an operator.
+    pub(crate) fn is_operator(&self, addr: SocketAddr) -> bool {
+        self.peers.get(&addr).is_some_and(|client| client.operator)
+    }
+
+    /// Check that the client at `addr` isn't muted, lifting its mute if it
+    /// has expired.
+    pub(crate) fn check_muted(&mut self, addr: SocketAddr) -> Result<(), String> {
+        let client = match self.peers.get_mut(&addr) {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+
+        match client.muted {
+            Some(mute) if mute.expired() => {
+                client.muted = None;
+                Ok(())
+            }
+            Some(mute) => Err(format!("you are muted {}", mute.remaining())),
+            None => Ok(()),
+        }
+    }
+
+    /// Disconnect the client at `addr`, telling it why.
+    ///
+    /// Messages still queued for the client are discarded.
+    pub(crate) fn disconnect(&self, addr: SocketAddr, reason: &str) {
+        if let Some(client) = self.peers.get(&addr) {
+            client.tx.close(Arc::new(Event::Disconnect {
+                reason: reason.to_string(),
+            }));
+        }
+    }
+
+    /// Find the client addressed as `name`.
+    ///
+    /// Names are matched regardless of case. An exact match wins, otherwise
+    /// `name` may be an unambiguous prefix of a connected client's name, so
+    /// `/msg al` reaches `alice`.
+    pub(crate) fn find(&self, name: &str) -> Result<SocketAddr, String> {
+        let key = name.to_lowercase();
+
+        if let Some(addr) = self.names.get(&key) {
+            return Ok(*addr);
+        }
+
+        let matches: Vec<SocketAddr> = self
+            .names
+            .range(key.clone()..)
+            .take_while(|(candidate, _)| candidate.starts_with(&key))
+            .map(|(_, addr)| *addr)
+            .collect();
+
+        match matches.len() {
+            0 => Err(format!("no one is called {}", name)),
+            1 => Ok(matches[0]),
+            _ => {
+                let candidates: Vec<&str> = matches
+                    .iter()
+                    .filter_map(|addr| self.peers.get(addr))
+                    .map(|client| client.name.as_str())
+                    .collect();
+                Err(format!(
+                    "{} is ambiguous, it matches {}",
+                    name,
+                    candidates.join(", ")
+                ))
+            }
+        }
+    }
+
+    /// Send a private message from the client at `from` to `to`, a client of
+    /// another server written `name@server`. Returns the recipient's name as
+    /// it is known.
+    pub(crate) fn relay_private(
+        &mut self,
+        from: SocketAddr,
+        to: &str,
+        text: &str,
+        action: bool,
+    ) -> Result<String, String> {
+        let text = &*strip_controls(text);
+        let name = self.name_of(from);
+        let to = match self.federation {
+            Some(ref mut federation) => federation.private(&name, to, text, action)?,
+            None => return Err(format!("no one is called {}", to)),
+        };
+        self.touch(from);
+        Ok(to)
+    }
+
+    /// Send a private message from `from` to the local client at `to`.
+    pub(crate) fn private(&self, to: SocketAddr, from: &str, text: &str, action: bool) {
+        if let Some(client) = self.peers.get(&to) {
+            client.tx.send(Arc::new(Event::Private {
+                from: from.to_string(),
+                text: strip_controls(text).into_owned(),
+                action,
+            }));
+        }
+    }
+
+    /// Addresses of the clients sharing at least one room with `addr`.
+    fn neighbours(&self, addr: SocketAddr) -> HashSet<SocketAddr> {
+        self.rooms
+            .values()
+            .filter(|room| room.members.contains(&addr))
+            .flat_map(|room| room.members.iter().cloned())
+            .filter(|member| *member != addr)
+            .collect()
+    }
+
+    /// Send an event to each of `addrs`.
+    fn notify<I>(&self, addrs: I, event: Arc<Event>)
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        for addr in addrs {
+            if let Some(client) = self.peers.get(&addr) {
+                client.tx.send(event.clone());
+            }
+        }
+    }
+
+    /// Send an event about a client of another server to the local members
+    /// of `rooms`, once each, if `announcement` is enabled.
+    pub(crate) fn notify_rooms(
+        &self,
+        rooms: &BTreeSet<String>,
+        announcement: Announcement,
+        event: Event,
+    ) {
+        if !self.announce.contains(&announcement) {
+            return;
+        }
+
+        let members: HashSet<SocketAddr> = rooms
+            .iter()
+            .filter_map(|room| self.rooms.get(room))
+            .flat_map(|room| room.members.iter().cloned())
+            .collect();
+        self.notify(members, Arc::new(event));
+    }
+
+    /// Tell the other members of `room` that the client at `addr` entered or
+    /// left it.
+    fn announce_member(&self, room: &str, addr: SocketAddr, joined: bool) {
+        let announcement = if joined {
+            Announcement::Join
+        } else {
+            Announcement::Leave
+        };
+        if !self.announce.contains(&announcement) {
+            return;
+        }
+
+        let (name, members) = match (self.peers.get(&addr), self.rooms.get(room)) {
+            (Some(client), Some(room)) => (client.name.clone(), &room.members),
+            _ => return,
+        };
+
+        let room = room.to_string();
+        let event = Arc::new(if joined {
+            Event::Joined { room, name }
+        } else {
+            Event::Parted { room, name }
+        });
+        self.notify(members.iter().cloned().filter(|a| *a != addr), event);
+    }
+
+    /// Add `addr` to the members of `room`, creating the room if needed.
+    pub(crate) fn join(&mut self, room: &str, addr: SocketAddr) {
+        let members = &mut self.rooms.entry(room.to_string()).or_default().members;
+        let was_empty = members.is_empty();
+        let joined = members.insert(addr);
+
+        if was_empty {
+            if let Some(i) = self.idle_rooms.iter().position(|r| r == room) {
+                self.idle_rooms.remove(i);
+            }
+        }
+
+        if joined {
+            self.record(RecordKind::Join, room, addr, "");
+            self.announce_member(room, addr, true);
+            self.relay(Body::Join {
+                room: room.to_string(),
+                name: self.name_of(addr),
+            });
+        }
+    }
+
+    /// Remove `addr` from the members of `room`, telling the other members.
+    pub(crate) fn leave(&mut self, room: &str, addr: SocketAddr) {
+        if self
+            .rooms
+            .get(room)
+            .is_some_and(|room| room.members.contains(&addr))
+        {
+            self.announce_member(room, addr, false);
+            self.relay(Body::Leave {
+                room: room.to_string(),
+                name: self.name_of(addr),
+            });
+        }
+        self.remove_member(room, addr);
+    }
+
+    /// Remove a disconnected client from all of its `rooms`.
+    ///
+    /// Clients sharing a room with it are told once, however many rooms they
+    /// share.
+    pub(crate) fn quit(
+        &mut self,
+        addr: SocketAddr,
+        rooms: &BTreeSet<String>,
+        departure: Departure,
+    ) {
+        let announcement = match departure {
+            Departure::Quit => Announcement::Leave,
+            Departure::Timeout => Announcement::Timeout,
+        };
+
+        if self.announce.contains(&announcement) {
+            if let Some(client) = self.peers.get(&addr) {
+                let event = Arc::new(Event::Quit {
+                    name: client.name.clone(),
+                    departure,
+                });
+                self.notify(self.neighbours(addr), event);
+            }
+        }
+
+        self.relay(Body::Quit {
+            name: self.name_of(addr),
+            timeout: departure == Departure::Timeout,
+        });
+
+        for room in rooms {
+            self.remove_member(room, addr);
+        }
+    }
+
+    /// Remove `addr` from the members of `room`. A room left empty is
+    /// dropped, or kept as an idle room if it has history.
+    fn remove_member(&mut self, room: &str, addr: SocketAddr) {
+        let (left, empty, history) = match self.rooms.get_mut(room) {
+            Some(room) => (
+                room.members.remove(&addr),
+                room.members.is_empty(),
+                !room.history.is_empty(),
+            ),
+            None => (false, false, false),
+        };
+
+        if left {
+            self.record(RecordKind::Leave, room, addr, "");
+        }
+
+        if left && empty {
+            if history {
+                self.keep_idle(room.to_string());
+            } else {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    /// The last `n` lines of the history of `room`, oldest first.
+    pub(crate) fn history(&self, room: &str, n: usize) -> Vec<Arc<Event>> {
+        match self.rooms.get(room) {
+            Some(room) => {
+                let skip = room.history.len().saturating_sub(n);
+                room.history.iter().skip(skip).cloned().collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Send a message from `from` to the members of `rooms`, except `from`
+    /// itself.
+    ///
+    /// A peer that shares several rooms with the sender only receives the
+    /// message once, for the first room they have in common. The message is
+    /// added to the history of every room.
+    pub(crate) fn broadcast(
+        &mut self,
+        from: SocketAddr,
+        rooms: &BTreeSet<String>,
+        text: &str,
+        action: bool,
+    ) {
+        let text = &*strip_controls(text);
+        let name = match self.peers.get_mut(&from) {
+            Some(client) => {
+                client.active = Instant::now();
+                client.name.clone()
+            }
+            None => return,
+        };
+        let kind = if action {
+            RecordKind::Action
+        } else {
+            RecordKind::Message
+        };
+        let mut delivered = HashSet::new();
+        self.metrics.messages.inc();
+
+        for room in rooms {
+            self.record(kind, room, from, text);
+            self.relay(Body::Message {
+                room: room.clone(),
+                from: name.clone(),
+                text: text.to_string(),
+                action,
+            });
+
+            // The event is shared by every recipient through an `Arc`, so it
+            // isn't copied for each of them.
+            let event = Arc::new(Event::Message {
+                room: room.clone(),
+                from: name.clone(),
+                text: text.to_string(),
+                action,
+            });
+
+            let room = match self.rooms.get_mut(room) {
+                Some(room) => room,
+                None => continue,
+            };
+
+            room.remember(event.clone());
+
+            for addr in &room.members {
+                // Don't send the message to ourselves or to a peer that
+                // already got it through another room.
+                if *addr == from || !delivered.insert(*addr) {
+                    continue;
+                }
+
+                if let Some(client) = self.peers.get(addr) {
+                    client.tx.send(event.clone());
+                }
+            }
+        }
+    }
+
+    /// Deliver a message from `from`, a client of another server, to the
+    /// local members of `room`, and to its history. Messages to rooms that
+    /// don't exist here are dropped.
+    pub(crate) fn deliver(&mut self, room: &str, from: &str, text: &str, action: bool) {
+        if !self.rooms.contains_key(room) {
+            return;
+        }
+        let text = &*strip_controls(text);
+
+        let kind = if action {
+            RecordKind::Action
+        } else {
+            RecordKind::Message
+        };
+        self.record_as(kind, room, from, text);
+
+        let event = Arc::new(Event::Message {
+            room: room.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+            action,
+        });
+
+        let room = self.rooms.get_mut(room).unwrap();
+        room.remember(event.clone());
+        for addr in &room.members {
+            if let Some(client) = self.peers.get(addr) {
+                client.tx.send(event.clone());
+            }
+        }
+    }
+}
+
+impl Room {
+    /// Add a message to the history, forgetting the oldest one once the
+    /// history holds `HISTORY_SIZE` messages.
+    fn remember(&mut self, message: Arc<Event>) {
+        self.history.push_back(message);
+        if self.history.len() > HISTORY_SIZE {
+            self.history.pop_front();
+        }
+    }
+}
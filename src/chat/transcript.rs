@@ -0,0 +1,360 @@
+//! Append-only, rotated transcript of the chat.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error, warn};
+
+/// Size at which the transcript is rotated, unless configured otherwise.
+pub const DEFAULT_TRANSCRIPT_MAX: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated transcript files kept, unless configured otherwise.
+pub const DEFAULT_TRANSCRIPT_KEEP: usize = 5;
+
+/// Handle to the append-only transcript of the chat.
+///
+/// Records are handed to a background thread that does the file I/O, so
+/// writing the transcript never blocks `Peer::poll`.
+pub struct Transcript {
+    tx: mpsc::Sender<Record>,
+
+    /// The writer thread, joined by `close`.
+    thread: thread::JoinHandle<()>,
+}
+
+/// Location and rotation settings of the transcript.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TranscriptConfig {
+    /// File the transcript is appended to.
+    pub path: PathBuf,
+
+    /// Size in bytes at which the file is rotated. `0` disables rotation.
+    #[serde(default = "default_transcript_max")]
+    pub max_size: u64,
+
+    /// Number of rotated files kept next to `path`, as `path.1` (the most
+    /// recent) to `path.<keep>`.
+    #[serde(default = "default_transcript_keep")]
+    pub keep: usize,
+}
+
+/// A line of the transcript.
+///
+/// Records are stored as tab separated fields:
+///
+/// ```text
+/// <milliseconds since the epoch>\t<kind>\t<room>\t<name>\t<text>
+/// ```
+///
+/// Backslashes, tabs and line breaks in the fields are escaped.
+#[derive(Debug)]
+pub struct Record {
+    pub time: u64,
+    pub kind: RecordKind,
+    pub room: String,
+    pub name: String,
+
+    /// The text of the message or action, without the sender's name. Empty
+    /// for joins and leaves.
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Join,
+    Leave,
+    Message,
+
+    /// A message sent with `/me`.
+    Action,
+}
+
+/// The background thread's side of the transcript.
+struct TranscriptWriter {
+    config: TranscriptConfig,
+    file: BufWriter<File>,
+
+    /// Size of the current file, used to decide when to rotate it.
+    size: u64,
+}
+
+impl Transcript {
+    /// Open the transcript for appending and start its writer thread.
+    pub fn open(config: TranscriptConfig) -> io::Result<Transcript> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+
+        let writer = TranscriptWriter {
+            config,
+            file: BufWriter::new(file),
+            size,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || writer.run(rx));
+
+        Ok(Transcript { tx, thread })
+    }
+
+    /// Stop the writer thread once it has written every queued record.
+    pub fn close(self) {
+        let Transcript { tx, thread } = self;
+
+        // The writer stops when the channel is closed.
+        drop(tx);
+
+        if thread.join().is_err() {
+            error!("transcript writer panicked");
+        }
+    }
+
+    /// Queue a record for writing.
+    pub fn write(&self, record: Record) {
+        // The send only fails if the writer thread is gone, in which case it
+        // has already reported why.
+        let _ = self.tx.send(record);
+    }
+
+    /// Read back the last `per_room` messages and actions of every room,
+    /// oldest first.
+    ///
+    /// The rotated files are read before the current one, keeping only the
+    /// latest records of each room as they go, so the whole transcript is
+    /// never held in memory. Lines that can't be parsed are skipped.
+    pub fn recover(config: &TranscriptConfig, per_room: usize) -> io::Result<Vec<Record>> {
+        let mut paths: Vec<PathBuf> = (1..=config.keep)
+            .rev()
+            .map(|n| rotated_path(&config.path, n))
+            .collect();
+        paths.push(config.path.clone());
+
+        let mut rooms: HashMap<String, VecDeque<Record>> = HashMap::new();
+        let mut skipped = 0;
+
+        for path in paths {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            for line in BufReader::new(file).lines() {
+                let record = match Record::parse(&line?) {
+                    Some(record) => record,
+                    None => {
+                        skipped += 1;
+                        continue;
+                    }
+                };
+                match record.kind {
+                    RecordKind::Message | RecordKind::Action => {}
+                    RecordKind::Join | RecordKind::Leave => continue,
+                }
+
+                let records = rooms.entry(record.room.clone()).or_default();
+                records.push_back(record);
+                if records.len() > per_room {
+                    records.pop_front();
+                }
+            }
+        }
+
+        if skipped > 0 {
+            warn!("skipped malformed transcript lines", lines = skipped);
+        }
+
+        // The sort is stable, so records written in the same millisecond
+        // stay in file order.
+        let mut records: Vec<Record> = rooms.into_values().flatten().collect();
+        records.sort_by_key(|record| record.time);
+        Ok(records)
+    }
+}
+
+impl TranscriptWriter {
+    /// Write records until every `Transcript` handle has been dropped.
+    fn run(mut self, rx: mpsc::Receiver<Record>) {
+        // Block for the next record, then write everything that is already
+        // queued before flushing, so a busy chat doesn't flush on every line.
+        while let Ok(record) = rx.recv() {
+            let mut result = self.write(&record);
+
+            while result.is_ok() {
+                match rx.try_recv() {
+                    Ok(record) => result = self.write(&record),
+                    Err(_) => break,
+                }
+            }
+
+            if let Err(e) = result.and_then(|_| self.file.flush()) {
+                error!("transcript error", error = e);
+            }
+        }
+
+        if let Err(e) = self.file.flush() {
+            error!("transcript error", error = e);
+        }
+    }
+
+    /// Append a record, rotating the file first if it would grow too large.
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let line = record.to_line();
+
+        if self.config.max_size > 0
+            && self.size > 0
+            && self.size + line.len() as u64 > self.config.max_size
+        {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Move `path` to `path.1`, shifting older files up and deleting the one
+    /// that falls off the end, then start a new, empty, file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let path = &self.config.path;
+
+        if self.config.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for n in (1..self.config.keep).rev() {
+                let from = rotated_path(path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(path, n + 1))?;
+                }
+            }
+            fs::rename(path, rotated_path(path, 1))?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Record {
+    /// Format the record as a transcript line, including the line break.
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            self.time,
+            self.kind.as_str(),
+            escape(&self.room),
+            escape(&self.name),
+            escape(&self.text)
+        )
+    }
+
+    /// Parse a line written by `to_line`.
+    fn parse(line: &str) -> Option<Record> {
+        let mut fields = line.splitn(5, '\t');
+
+        let time = fields.next()?.parse().ok()?;
+        let kind = match fields.next()? {
+            "join" => RecordKind::Join,
+            "leave" => RecordKind::Leave,
+            "message" => RecordKind::Message,
+            "action" => RecordKind::Action,
+            _ => return None,
+        };
+
+        Some(Record {
+            time,
+            kind,
+            room: unescape(fields.next()?),
+            name: unescape(fields.next()?),
+            text: unescape(fields.next()?),
+        })
+    }
+}
+
+impl RecordKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RecordKind::Join => "join",
+            RecordKind::Leave => "leave",
+            RecordKind::Message => "message",
+            RecordKind::Action => "action",
+        }
+    }
+}
+
+/// Path of the `n`th rotated transcript file.
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+/// Escape a transcript field so it can't break the line format.
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Reverse `escape`.
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+/// Current time as milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+fn default_transcript_max() -> u64 {
+    DEFAULT_TRANSCRIPT_MAX
+}
+
+fn default_transcript_keep() -> usize {
+    DEFAULT_TRANSCRIPT_KEEP
+}
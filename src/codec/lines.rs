@@ -0,0 +1,381 @@
+//! Line framing for chat-style protocols.
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::io;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+use std::str::FromStr;
+
+/// Longest line accepted by default, not counting the line ending.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 8 * 1024;
+
+/// Space reserved in the read buffer before each read by default.
+pub const DEFAULT_READ_RESERVE: usize = 1024;
+
+/// What to do with a line that is longer than the maximum line length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LongLines {
+    /// Disconnect the client.
+    Disconnect,
+
+    /// Keep the start of the line, up to the maximum length, and discard the
+    /// rest of it.
+    Truncate,
+}
+
+/// Line based codec
+///
+/// This decorates a socket and presents a line based read / write interface.
+///
+/// As a user of `Lines`, we can focus on working at the line level. So, we send
+/// and receive values that represent entire lines. The `Lines` codec will
+/// handle the encoding and decoding as well as reading from and writing to the
+/// socket.
+///
+/// Lines received may end with either "\r\n" or a bare "\n". Lines are
+/// written exactly as buffered, so callers add the "\r\n" themselves.
+#[derive(Debug)]
+pub struct Lines<S = TcpStream> {
+    /// The socket, usually a `TcpStream`.
+    socket: S,
+
+    /// Space reserved in `rd` before each read.
+    read_reserve: usize,
+
+    /// Maximum length of a line, not counting the line ending.
+    max_line_length: usize,
+
+    /// What to do with lines longer than `max_line_length`.
+    long_lines: LongLines,
+
+    /// Buffer used when reading from the socket. Data is not returned from this
+    /// buffer until an entire line has been read.
+    ///
+    /// The buffer holds at most one line's worth of data plus one read, since
+    /// no more is read while it is longer than `max_line_length`.
+    rd: BytesMut,
+
+    /// Number of bytes at the start of `rd` already searched for a line
+    /// ending, so that they aren't searched again on the next poll.
+    scanned: usize,
+
+    /// Set while the rest of a truncated line is being discarded.
+    discarding: bool,
+
+    /// Buffer used to stage data before writing it to the socket.
+    wr: BytesMut,
+
+    /// Number of bytes read from the socket so far.
+    bytes_read: u64,
+
+    /// Number of bytes written to the socket so far.
+    bytes_written: u64,
+}
+
+impl FromStr for LongLines {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LongLines, String> {
+        match s {
+            "disconnect" => Ok(LongLines::Disconnect),
+            "truncate" => Ok(LongLines::Truncate),
+            _ => Err(format!(
+                "unknown long line policy `{}`, expected disconnect or truncate",
+                s
+            )),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Lines<S> {
+    /// Create a new `Lines` codec backed by the socket
+    ///
+    /// Lines longer than `DEFAULT_MAX_LINE_LENGTH` are an error until another
+    /// limit is set with `max_line_length`.
+    pub fn new(socket: S) -> Self {
+        Lines {
+            socket,
+            read_reserve: DEFAULT_READ_RESERVE,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            long_lines: LongLines::Disconnect,
+            rd: BytesMut::new(),
+            scanned: 0,
+            discarding: false,
+            wr: BytesMut::new(),
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Set the longest line accepted, not counting the line ending.
+    pub fn max_line_length(mut self, max_line_length: usize) -> Self {
+        self.max_line_length = max_line_length;
+        self
+    }
+
+    /// Set what happens to lines longer than the maximum line length.
+    pub fn long_lines(mut self, long_lines: LongLines) -> Self {
+        self.long_lines = long_lines;
+        self
+    }
+
+    /// Set the space reserved in the read buffer before each read.
+    pub fn read_reserve(mut self, read_reserve: usize) -> Self {
+        self.read_reserve = read_reserve;
+        self
+    }
+
+    /// Get a reference to the underlying socket.
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Number of bytes read from the socket so far, including lines not yet
+    /// returned.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Number of bytes written to the socket so far, not counting those
+    /// still buffered.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Number of bytes buffered and not yet written to the socket.
+    pub fn buffered_len(&self) -> usize {
+        self.wr.len()
+    }
+
+    /// Buffer a line.
+    ///
+    /// This writes the line to an internal buffer. Calls to `poll_flush` will
+    /// attempt to flush this buffer to the socket.
+    ///
+    /// The buffer itself is not limited. Callers should check `buffered_len`
+    /// and stop producing data while it is large.
+    pub fn buffer(&mut self, line: &[u8]) {
+        // Ensure the buffer has capacity.
+        self.wr.reserve(line.len());
+
+        // Push the line onto the end of the write buffer.
+        //
+        // The `put` function is from the `BufMut` trait.
+        self.wr.put(line);
+    }
+
+    /// Flush the write buffer to the socket
+    pub fn poll_flush(&mut self) -> Poll<(), io::Error> {
+        // As long as there is buffered data to write, try to write it.
+        while !self.wr.is_empty() {
+            // Try to write some bytes to the socket
+            let n = try_ready!(self.socket.poll_write(&self.wr));
+
+            // As long as the wr is not empty, a successful write should
+            // never write 0 bytes.
+            assert!(n > 0);
+            self.bytes_written += n as u64;
+
+            // This discards the first `n` bytes of the buffer.
+            let _ = self.wr.split_to(n);
+        }
+
+        // Sockets that buffer writes themselves, like `WebSocket`, only send
+        // everything once flushed.
+        self.socket.poll_flush()
+    }
+
+    /// Read data from the socket.
+    ///
+    /// This only returns `Ready` when the socket has closed. Reading also
+    /// stops, without registering interest in the socket, once the read buffer
+    /// holds more than a line's worth of data; `poll` consumes some of it
+    /// before reading again.
+    fn fill_read_buf(&mut self) -> Poll<(), io::Error> {
+        while self.rd.len() <= self.max_line_length + 1 {
+            // Ensure the read buffer has capacity.
+            //
+            // This might result in an internal allocation.
+            self.rd.reserve(self.read_reserve);
+
+            // Read data into the buffer.
+            let n = try_ready!(AsyncRead::read_buf(&mut self.socket, &mut self.rd));
+
+            if n == 0 {
+                return Ok(Async::Ready(()));
+            }
+            self.bytes_read += n as u64;
+        }
+
+        Ok(Async::NotReady)
+    }
+
+    /// Apply the long line policy to `line`, which is longer than
+    /// `max_line_length`.
+    fn too_long(&self, mut line: BytesMut) -> Result<BytesMut, io::Error> {
+        match self.long_lines {
+            LongLines::Disconnect => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"))
+            }
+            LongLines::Truncate => {
+                line.truncate(self.max_line_length);
+                Ok(line)
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Stream for Lines<S> {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            // First, read any new data that might have been received off the
+            // socket
+            let sock_closed = self.fill_read_buf()?.is_ready();
+
+            // Now, try finding lines. Both "\r\n" and a bare "\n" end a line,
+            // so it is enough to look for the "\n", starting where the previous
+            // search stopped.
+            let pos = self.rd[self.scanned..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map(|i| self.scanned + i);
+
+            if let Some(pos) = pos {
+                // Remove the line from the read buffer and set it to `line`.
+                let mut line = self.rd.split_to(pos + 1);
+                self.scanned = 0;
+
+                // Drop the trailing \n, and the \r before it if there is one
+                line.split_off(pos);
+                if line.ends_with(b"\r") {
+                    line.split_off(pos - 1);
+                }
+
+                // This is the end of a line that was already returned
+                // truncated.
+                if self.discarding {
+                    self.discarding = false;
+                    continue;
+                }
+
+                if line.len() > self.max_line_length {
+                    line = self.too_long(line)?;
+                }
+
+                // Return the line
+                return Ok(Async::Ready(Some(line)));
+            }
+
+            // Leave room for the "\r" of a line that is exactly
+            // `max_line_length` long.
+            if self.rd.len() > self.max_line_length + 1 {
+                let line = self.rd.take();
+                self.scanned = 0;
+
+                if self.discarding {
+                    continue;
+                }
+
+                let line = self.too_long(line)?;
+                self.discarding = true;
+                return Ok(Async::Ready(Some(line)));
+            }
+
+            self.scanned = self.rd.len();
+
+            return if sock_closed {
+                Ok(Async::Ready(None))
+            } else {
+                Ok(Async::NotReady)
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// The lines read from `input`, up to the end or the first error.
+    fn read_all(lines: Lines<Cursor<Vec<u8>>>) -> (Vec<String>, Option<io::Error>) {
+        let mut read = Vec::new();
+        for line in lines.wait() {
+            match line {
+                Ok(line) => read.push(String::from_utf8(line.to_vec()).unwrap()),
+                Err(e) => return (read, Some(e)),
+            }
+        }
+        (read, None)
+    }
+
+    fn lines(input: &[u8]) -> Lines<Cursor<Vec<u8>>> {
+        Lines::new(Cursor::new(input.to_vec()))
+    }
+
+    #[test]
+    fn splits_lines() {
+        let (read, error) = read_all(lines(b"hello\r\nworld\n\r\nbare\rreturn\n"));
+        assert_eq!(read, ["hello", "world", "", "bare\rreturn"]);
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn drops_an_unfinished_last_line() {
+        let (read, error) = read_all(lines(b"complete\nunfinished"));
+        assert_eq!(read, ["complete"]);
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn rejects_long_lines() {
+        let (read, error) = read_all(lines(b"short\ntoo long\nnever read\n").max_line_length(5));
+        assert_eq!(read, ["short"]);
+        assert_eq!(error.unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // Without a line ending in sight.
+        let (read, error) = read_all(lines(&[b'x'; 100]).max_line_length(10));
+        assert!(read.is_empty());
+        assert_eq!(error.unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn accepts_lines_of_the_maximum_length() {
+        let (read, error) = read_all(lines(b"12345\r\n12345\n").max_line_length(5));
+        assert_eq!(read, ["12345", "12345"]);
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn truncates_long_lines() {
+        let input = b"short\nmuch too long\nnext\n";
+        let (read, error) = read_all(
+            lines(input)
+                .max_line_length(5)
+                .long_lines(LongLines::Truncate)
+                .read_reserve(4),
+        );
+        assert_eq!(read, ["short", "much ", "next"]);
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn writes_buffered_lines() {
+        let mut lines = lines(b"");
+        lines.buffer(b"one\r\n");
+        lines.buffer(b"two\r\n");
+        assert_eq!(lines.buffered_len(), 10);
+
+        assert!(lines.poll_flush().unwrap().is_ready());
+        assert_eq!(lines.buffered_len(), 0);
+        assert_eq!(lines.bytes_written(), 10);
+        assert_eq!(lines.get_ref().get_ref(), b"one\r\ntwo\r\n");
+    }
+}
@@ -0,0 +1,17 @@
+//! Codecs that split a byte stream into lines.
+//!
+//! `Lines` wraps a socket directly and is driven by hand from a `Future`,
+//! while `StructLinesCodec` plugs into `tokio::codec::Framed` and parses every
+//! line into a typed value. `WebSocket` carries lines over a WebSocket
+//! connection, one text message per line, so that `Lines` can wrap it too.
+
+mod lines;
+mod struct_lines;
+mod websocket;
+
+pub use self::lines::{Lines, LongLines, DEFAULT_MAX_LINE_LENGTH, DEFAULT_READ_RESERVE};
+pub use self::struct_lines::{
+    escape, unescape, Fields, FromLine, ParseError, ParseErrorKind, StructLinesCodec, ToLine,
+    DEFAULT_MAX_LENGTH,
+};
+pub use self::websocket::{Accept, WebSocket};
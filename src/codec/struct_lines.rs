@@ -0,0 +1,542 @@
+//! A codec that turns each line of a stream into a typed struct and back.
+//!
+//! `StructLinesCodec` splits the input on a configurable delimiter and parses
+//! every line with `FromLine`. Outgoing values are written with `ToLine`,
+//! followed by the delimiter. A line that doesn't parse is reported as a
+//! `ParseError` carrying its line number, and decoding carries on with the
+//! next line. Only I/O errors and lines longer than the configured maximum end
+//! the stream.
+
+use bytes::{BufMut, BytesMut};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use tokio::codec::{Decoder, Encoder};
+use tokio::io;
+
+/// Longest line accepted by default, not counting the delimiter.
+pub const DEFAULT_MAX_LENGTH: usize = 8 * 1024;
+
+/// Types that can be parsed from a single line.
+pub trait FromLine: Sized {
+    fn from_line(line: &str) -> Result<Self, ParseErrorKind>;
+}
+
+/// Types that can be written as a single line.
+///
+/// Values are escaped with `escape` where needed, so that they stay a single
+/// field. Should the line still contain the delimiter of the codec, the codec
+/// writes it as a `\xHH` escape, which `unescape` reverses.
+pub trait ToLine {
+    fn to_line(&self, line: &mut String);
+}
+
+/// A line that could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Number of the line, starting at 1.
+    pub line: usize,
+
+    pub kind: ParseErrorKind,
+}
+
+/// The reason a line could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The line is not valid UTF-8.
+    InvalidUtf8,
+
+    /// The line is empty.
+    Empty,
+
+    /// The line starts with a tag that isn't known.
+    UnknownTag(String),
+
+    /// A field is not of the form `key=value`.
+    MalformedField(String),
+
+    /// A value contains a `\` that doesn't start a known escape.
+    InvalidEscape(String),
+
+    /// The line lacks something the tag requires.
+    Missing(&'static str),
+}
+
+/// Splits a stream of bytes into lines and parses them into `D`, and writes
+/// values of `E` as lines.
+#[derive(Debug)]
+pub struct StructLinesCodec<D, E> {
+    /// Byte that ends a line. When this is `\n`, a `\r` before it is dropped
+    /// as well, so that telnet clients work.
+    delimiter: u8,
+
+    /// Longest line accepted, not counting the delimiter.
+    max_length: usize,
+
+    /// Number of lines decoded so far.
+    line: usize,
+
+    /// Number of bytes at the start of the buffer already searched for the
+    /// delimiter.
+    scanned: usize,
+
+    _types: PhantomData<fn(E) -> D>,
+}
+
+impl<D, E> StructLinesCodec<D, E> {
+    /// Create a codec for lines ending with `\n` or `\r\n`.
+    pub fn new() -> StructLinesCodec<D, E> {
+        StructLinesCodec::with_delimiter(b'\n')
+    }
+
+    /// Create a codec for lines ending with `delimiter`.
+    ///
+    /// Panics unless `delimiter` is an ASCII control character, or ASCII
+    /// punctuation other than `\` and `=`. Letters, digits and spaces make
+    /// up the tags, keys and escapes of a line, and `\` and `=` its escapes
+    /// and fields, so a delimiter written as `\xHH` in their place would
+    /// change what the line means.
+    pub fn with_delimiter(delimiter: u8) -> StructLinesCodec<D, E> {
+        assert!(
+            (delimiter.is_ascii_control() || delimiter.is_ascii_punctuation())
+                && delimiter != b'\\'
+                && delimiter != b'=',
+            "invalid delimiter {:?}",
+            char::from(delimiter)
+        );
+        StructLinesCodec {
+            delimiter,
+            max_length: DEFAULT_MAX_LENGTH,
+            line: 0,
+            scanned: 0,
+            _types: PhantomData,
+        }
+    }
+
+    /// Set the longest line accepted, not counting the delimiter.
+    pub fn max_length(mut self, max_length: usize) -> StructLinesCodec<D, E> {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Number of bytes a line may exceed `max_length` by before its `\r` is
+    /// dropped, since the `\r` before a `\n` isn't counted towards the length.
+    fn slack(&self) -> usize {
+        if self.delimiter == b'\n' {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Fail if `line`, without its delimiter, is longer than `max_length`.
+    fn check_length(&self, line: &[u8]) -> Result<(), io::Error> {
+        let cr = self.delimiter == b'\n' && line.ends_with(b"\r");
+        if line.len() - usize::from(cr) > self.max_length {
+            return Err(line_too_long());
+        }
+        Ok(())
+    }
+
+    /// Parse one line, without its delimiter.
+    fn parse(&mut self, mut line: BytesMut) -> Result<D, ParseError>
+    where
+        D: FromLine,
+    {
+        self.line += 1;
+
+        if self.delimiter == b'\n' && line.ends_with(b"\r") {
+            let len = line.len();
+            line.truncate(len - 1);
+        }
+
+        let result = match std::str::from_utf8(&line) {
+            Ok(line) => D::from_line(line),
+            Err(_) => Err(ParseErrorKind::InvalidUtf8),
+        };
+
+        result.map_err(|kind| ParseError {
+            line: self.line,
+            kind,
+        })
+    }
+}
+
+impl<D, E> Default for StructLinesCodec<D, E> {
+    fn default() -> StructLinesCodec<D, E> {
+        StructLinesCodec::new()
+    }
+}
+
+impl<D: FromLine, E> Decoder for StructLinesCodec<D, E> {
+    /// Lines that fail to parse are yielded as errors, so that one bad line
+    /// doesn't end the stream.
+    type Item = Result<D, ParseError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        // Resume the search where the previous call stopped.
+        let delimiter = self.delimiter;
+        let pos = buf[self.scanned..]
+            .iter()
+            .position(|&b| b == delimiter)
+            .map(|i| self.scanned + i);
+
+        match pos {
+            Some(pos) => {
+                self.scanned = 0;
+
+                let mut line = buf.split_to(pos + 1);
+                line.truncate(pos);
+                self.check_length(&line)?;
+
+                Ok(Some(self.parse(line)))
+            }
+            None if buf.len() > self.max_length + self.slack() => Err(line_too_long()),
+            None => {
+                self.scanned = buf.len();
+                Ok(None)
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        match self.decode(buf)? {
+            Some(item) => Ok(Some(item)),
+            // The last line doesn't need a delimiter.
+            None if !buf.is_empty() => {
+                self.scanned = 0;
+                let line = buf.take();
+                self.check_length(&line)?;
+                Ok(Some(self.parse(line)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<D, E: ToLine> Encoder for StructLinesCodec<D, E> {
+    type Item = E;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: E, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let mut line = String::new();
+        item.to_line(&mut line);
+
+        // A delimiter left in the line would split it in two.
+        let delimiter = char::from(self.delimiter);
+        if line.contains(delimiter) {
+            line = line.replace(delimiter, &format!("\\x{:02x}", self.delimiter));
+        }
+
+        dst.reserve(line.len() + 2);
+        dst.put(line.as_bytes());
+        if self.delimiter == b'\n' {
+            dst.put(&b"\r\n"[..]);
+        } else {
+            dst.put(self.delimiter);
+        }
+
+        Ok(())
+    }
+}
+
+fn line_too_long() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "line too long")
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl Error for ParseError {}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::InvalidUtf8 => write!(f, "not valid UTF-8"),
+            ParseErrorKind::Empty => write!(f, "empty line"),
+            ParseErrorKind::UnknownTag(tag) => write!(f, "unknown tag `{}`", tag),
+            ParseErrorKind::MalformedField(field) => {
+                write!(f, "expected `key=value`, found `{}`", field)
+            }
+            ParseErrorKind::InvalidEscape(value) => write!(f, "invalid escape in `{}`", value),
+            ParseErrorKind::Missing(what) => write!(f, "missing {}", what),
+        }
+    }
+}
+
+/// `key=value` fields, in key order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fields(pub BTreeMap<String, String>);
+
+impl Fields {
+    /// Parse whitespace separated `key=value` fields.
+    pub fn parse(s: &str) -> Result<Fields, ParseErrorKind> {
+        let mut fields = BTreeMap::new();
+
+        for field in s.split_whitespace() {
+            let mut parts = field.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = match parts.next() {
+                Some(value) if !key.is_empty() => value,
+                _ => return Err(ParseErrorKind::MalformedField(field.to_string())),
+            };
+
+            let value =
+                unescape(value).ok_or_else(|| ParseErrorKind::InvalidEscape(value.to_string()))?;
+            fields.insert(key.to_string(), value);
+        }
+
+        Ok(Fields(fields))
+    }
+
+    pub fn write(&self, line: &mut String) {
+        for (key, value) in &self.0 {
+            line.push(' ');
+            line.push_str(key);
+            line.push('=');
+            line.push_str(&escape(value));
+        }
+    }
+}
+
+/// Escape whitespace and `\` in a value, so that it stays a single field of
+/// a single line.
+///
+/// `unescape` also understands `\xHH`, for an ASCII character written as
+/// two hexadecimal digits, which the codec uses for delimiters.
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ' ' => escaped.push_str("\\s"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Reverse `escape`. Returns `None` for an unknown escape.
+pub fn unescape(value: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            's' => unescaped.push(' '),
+            't' => unescaped.push('\t'),
+            'r' => unescaped.push('\r'),
+            'n' => unescaped.push('\n'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return None;
+                }
+                let byte = u8::from_str_radix(&hex, 16).ok().filter(u8::is_ascii)?;
+                unescaped.push(char::from(byte));
+            }
+            _ => return None,
+        }
+    }
+
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A command of a small key-value protocol.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Command {
+        Set(Fields),
+        Quit,
+    }
+
+    impl FromLine for Command {
+        fn from_line(line: &str) -> Result<Command, ParseErrorKind> {
+            let mut parts = line.splitn(2, ' ');
+            match parts.next().unwrap_or("") {
+                "" => Err(ParseErrorKind::Empty),
+                "SET" => Ok(Command::Set(Fields::parse(parts.next().unwrap_or(""))?)),
+                "QUIT" => Ok(Command::Quit),
+                tag => Err(ParseErrorKind::UnknownTag(tag.to_string())),
+            }
+        }
+    }
+
+    impl ToLine for Command {
+        fn to_line(&self, line: &mut String) {
+            match self {
+                Command::Set(fields) => {
+                    line.push_str("SET");
+                    fields.write(line);
+                }
+                Command::Quit => line.push_str("QUIT"),
+            }
+        }
+    }
+
+    fn set(fields: &[(&str, &str)]) -> Command {
+        let fields = fields
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Command::Set(Fields(fields))
+    }
+
+    /// Decode every line of `input`, as if the stream ended after it.
+    fn decode_all(
+        codec: &mut StructLinesCodec<Command, Command>,
+        input: &[u8],
+    ) -> Vec<Result<Command, ParseError>> {
+        let mut buf = BytesMut::from(input);
+        let mut decoded = Vec::new();
+        while let Some(item) = codec.decode_eof(&mut buf).unwrap() {
+            decoded.push(item);
+        }
+        decoded
+    }
+
+    #[test]
+    fn round_trip() {
+        let commands = vec![
+            set(&[("name", "alice"), ("greeting", "hello, world")]),
+            set(&[("text", "tab\there\r\nnew line"), ("path", "C:\\chat")]),
+            set(&[("empty", "")]),
+            Command::Quit,
+        ];
+
+        let mut codec = StructLinesCodec::new();
+        let mut buf = BytesMut::new();
+        for command in commands.clone() {
+            codec.encode(command, &mut buf).unwrap();
+        }
+        assert_eq!(buf.iter().filter(|&&b| b == b'\n').count(), commands.len());
+
+        let decoded: Vec<Command> = decode_all(&mut codec, &buf)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(decoded, commands);
+    }
+
+    #[test]
+    fn reports_parse_errors_with_line_numbers() {
+        let mut codec = StructLinesCodec::new();
+        let decoded = decode_all(&mut codec, b"SET a=1\r\nJUMP\n\nSET a=\\q\nQUIT");
+
+        assert_eq!(decoded.len(), 5);
+        assert_eq!(decoded[0], Ok(set(&[("a", "1")])));
+        assert_eq!(
+            decoded[1],
+            Err(ParseError {
+                line: 2,
+                kind: ParseErrorKind::UnknownTag("JUMP".to_string()),
+            })
+        );
+        assert_eq!(decoded[2].as_ref().unwrap_err().kind, ParseErrorKind::Empty);
+        assert_eq!(
+            decoded[3].as_ref().unwrap_err().kind,
+            ParseErrorKind::InvalidEscape("\\q".to_string())
+        );
+        // The last line needs no delimiter.
+        assert_eq!(decoded[4], Ok(Command::Quit));
+    }
+
+    #[test]
+    fn rejects_long_lines() {
+        let mut codec = StructLinesCodec::<Command, Command>::new().max_length(8);
+        let mut buf = BytesMut::from(&b"SET a=12\r\nSET a=123\n"[..]);
+
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Ok(set(&[("a", "12")])))
+        );
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn escapes_the_delimiter() {
+        let mut codec = StructLinesCodec::with_delimiter(b';');
+        let command = set(&[("text", "one; two")]);
+
+        let mut buf = BytesMut::new();
+        codec.encode(command.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[..], &b"SET text=one\\x3b\\stwo;"[..]);
+        assert_eq!(decode_all(&mut codec, &buf), vec![Ok(command)]);
+    }
+
+    #[test]
+    fn round_trips_with_every_kind_of_delimiter() {
+        // Control characters, including the default line ending, and
+        // punctuation.
+        for &delimiter in b"\n\t\0\x1e;|,:" {
+            let mut codec = StructLinesCodec::with_delimiter(delimiter);
+            let value = format!("a{}b c\\s\\x3b=d\t\r\n", char::from(delimiter));
+            let commands = vec![set(&[("text", &value), ("x", "7")]), Command::Quit];
+
+            let mut buf = BytesMut::new();
+            for command in commands.clone() {
+                codec.encode(command, &mut buf).unwrap();
+            }
+            let decoded: Vec<_> = decode_all(&mut codec, &buf);
+            assert_eq!(
+                decoded,
+                commands.into_iter().map(Ok).collect::<Vec<_>>(),
+                "delimiter {:?}",
+                char::from(delimiter)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_delimiters_used_within_lines() {
+        for &delimiter in b"\\= sxtrn0aE\xff" {
+            let result = std::panic::catch_unwind(|| {
+                StructLinesCodec::<Command, Command>::with_delimiter(delimiter)
+            });
+            assert!(result.is_err(), "accepted {:?}", char::from(delimiter));
+        }
+    }
+
+    #[test]
+    fn unescapes_hexadecimal_escapes() {
+        assert_eq!(unescape("a\\x3bb").as_deref(), Some("a;b"));
+        assert_eq!(unescape("\\\\x3b").as_deref(), Some("\\x3b"));
+        assert_eq!(unescape("\\x3"), None);
+        assert_eq!(unescape("\\x+3"), None);
+        assert_eq!(unescape("\\xff"), None);
+    }
+
+    #[test]
+    fn decodes_lines_split_across_reads() {
+        let mut codec = StructLinesCodec::<Command, Command>::new();
+        let mut buf = BytesMut::from(&b"SET a"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"=1\nQU");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Ok(set(&[("a", "1")])))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+}
@@ -0,0 +1,740 @@
+//! WebSocket connections, as specified by RFC 6455.
+
+use bytes::{BufMut, BytesMut};
+use futures::{Async, Future, Poll};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use std::io::{Read, Write};
+
+/// Maximum size of the HTTP request that opens a WebSocket connection.
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Status codes sent in close frames.
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+
+/// A WebSocket connection, seen as a byte stream of lines.
+///
+/// Every text message received from the client is read as one line, ending
+/// with `\n`, and every line written is sent to the client as one text
+/// message, without its line ending. This lets a `WebSocket` be wrapped by
+/// `Lines` like a plain socket.
+///
+/// Pings are answered and a close frame is answered before the connection
+/// reads as closed. Binary messages aren't supported, the connection is
+/// closed when the client sends one, or a text message that isn't valid
+/// UTF-8.
+///
+/// Frames written are buffered and only written out as the socket allows,
+/// so the connection must be flushed for them to be sent.
+pub struct WebSocket<S = TcpStream> {
+    socket: S,
+
+    /// Data read off the socket that hasn't been decoded yet.
+    rd: BytesMut,
+
+    /// The data frame whose payload is being read, if any.
+    frame: Option<Frame>,
+
+    /// Set when the current message is complete but its `\n` hasn't been
+    /// read yet.
+    newline: bool,
+
+    /// Set while a message is split in several frames, between its first
+    /// frame and its last.
+    fragmented: bool,
+
+    /// The bytes of a character cut short at the end of the payload read so
+    /// far, completed by the next bytes of the message.
+    incomplete: Vec<u8>,
+
+    /// Bytes written that don't make a whole line yet.
+    line: BytesMut,
+
+    /// Encoded frames waiting to be written to the socket.
+    wr: BytesMut,
+
+    /// Set once a close frame has been sent. Nothing else may be sent after
+    /// it.
+    closing: bool,
+}
+
+/// Header of a data frame.
+struct Frame {
+    /// Bytes of the payload left to read.
+    remaining: u64,
+
+    /// The key the client masked the payload with.
+    mask: [u8; 4],
+
+    /// Number of payload bytes read so far, which selects the byte of the
+    /// mask to apply next.
+    offset: usize,
+
+    /// Set for the last frame of a message.
+    fin: bool,
+}
+
+/// Future that answers the HTTP request opening a WebSocket connection,
+/// resolving to the connection.
+///
+/// A request that isn't a valid WebSocket upgrade is answered with
+/// `400 Bad Request`, and the future fails.
+pub struct Accept<S = TcpStream> {
+    socket: Option<S>,
+
+    /// The request, as read so far.
+    rd: BytesMut,
+
+    /// The response, once the request is complete.
+    wr: BytesMut,
+
+    /// Why the request was rejected, reported once the response is written.
+    rejected: Option<String>,
+}
+
+impl<S: AsyncRead + AsyncWrite> WebSocket<S> {
+    /// Wait for the client on `socket` to open a WebSocket connection.
+    pub fn accept(socket: S) -> Accept<S> {
+        Accept {
+            socket: Some(socket),
+            rd: BytesMut::new(),
+            wr: BytesMut::new(),
+            rejected: None,
+        }
+    }
+
+    /// Get a reference to the underlying socket.
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Write out as much of the buffered frames as the socket accepts.
+    ///
+    /// Returns `Ok` if the socket isn't ready for more, leaving the rest in
+    /// the buffer.
+    fn write_frames(&mut self) -> io::Result<()> {
+        while !self.wr.is_empty() {
+            match self.socket.write(&self.wr) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    let _ = self.wr.split_to(n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a close frame, unless one has been sent already.
+    fn close(&mut self, code: u16) {
+        if !self.closing {
+            let mut payload = [0; 2];
+            payload[0] = (code >> 8) as u8;
+            payload[1] = code as u8;
+            encode_frame(&mut self.wr, OPCODE_CLOSE, &payload);
+            self.closing = true;
+        }
+    }
+
+    /// Close the connection because the client broke the protocol.
+    fn fail(&mut self, code: u16, reason: &str) -> io::Error {
+        self.close(code);
+        // This is the last chance to send the close frame.
+        let _ = self.write_frames();
+        io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+    }
+
+    /// Decode the frame header at the start of `rd`, handling control frames
+    /// entirely.
+    ///
+    /// Returns `false` if `rd` doesn't hold a complete header yet, or a
+    /// complete control frame, and `true` once the frame is handled. A data
+    /// frame is stored in `frame` for its payload to be read.
+    fn decode_header(&mut self) -> io::Result<bool> {
+        if self.rd.len() < 2 {
+            return Ok(false);
+        }
+
+        let fin = self.rd[0] & 0x80 != 0;
+        let opcode = self.rd[0] & 0x0f;
+        let masked = self.rd[1] & 0x80 != 0;
+
+        if self.rd[0] & 0x70 != 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "reserved bits set"));
+        }
+        if !masked {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unmasked frame"));
+        }
+
+        let (len, offset) = match self.rd[1] & 0x7f {
+            126 if self.rd.len() >= 4 => (u64::from(self.rd[2]) << 8 | u64::from(self.rd[3]), 4),
+            127 if self.rd.len() >= 10 => {
+                let len = self.rd[2..10]
+                    .iter()
+                    .fold(0, |len, byte| len << 8 | u64::from(*byte));
+                (len, 10)
+            }
+            126 | 127 => return Ok(false),
+            len => (u64::from(len), 2),
+        };
+
+        if self.rd.len() < offset + 4 {
+            return Ok(false);
+        }
+        let mut mask = [0; 4];
+        mask.copy_from_slice(&self.rd[offset..offset + 4]);
+        let offset = offset + 4;
+
+        match opcode {
+            OPCODE_TEXT | OPCODE_CONTINUATION => {
+                if (opcode == OPCODE_TEXT) == self.fragmented {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unexpected continuation"));
+                }
+
+                let _ = self.rd.split_to(offset);
+                self.fragmented = !fin;
+                self.frame = Some(Frame {
+                    remaining: len,
+                    mask,
+                    offset: 0,
+                    fin,
+                });
+                Ok(true)
+            }
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                if !fin || len > 125 {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "invalid control frame"));
+                }
+
+                // Control frames are small, they are only handled once they
+                // have been read entirely.
+                let len = len as usize;
+                if self.rd.len() < offset + len {
+                    return Ok(false);
+                }
+                let _ = self.rd.split_to(offset);
+                let mut payload = self.rd.split_to(len);
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+
+                match opcode {
+                    OPCODE_PING if !self.closing => {
+                        encode_frame(&mut self.wr, OPCODE_PONG, &payload);
+                    }
+                    OPCODE_CLOSE => {
+                        // The client's status code isn't echoed: some codes
+                        // are only meant to be reported locally, never sent.
+                        let code = match payload.len() {
+                            0 => CLOSE_NORMAL,
+                            1 => CLOSE_PROTOCOL_ERROR,
+                            _ if std::str::from_utf8(&payload[2..]).is_err() => CLOSE_INVALID_DATA,
+                            _ if valid_close_code(
+                                u16::from(payload[0]) << 8 | u16::from(payload[1]),
+                            ) =>
+                            {
+                                CLOSE_NORMAL
+                            }
+                            _ => CLOSE_PROTOCOL_ERROR,
+                        };
+                        self.close(code);
+                    }
+                    _ => {}
+                }
+                Ok(true)
+            }
+            OPCODE_BINARY => Err(self.fail(CLOSE_UNSUPPORTED, "binary messages not supported")),
+            _ => Err(self.fail(CLOSE_PROTOCOL_ERROR, "unknown opcode")),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Read for WebSocket<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            // Pongs and the reply to a close frame go out as soon as possible.
+            self.write_frames()?;
+
+            if self.newline {
+                self.newline = false;
+                buf[0] = b'\n';
+                return Ok(1);
+            }
+
+            // Once a close frame has been exchanged, the connection is done.
+            if self.closing {
+                return Ok(0);
+            }
+
+            if let Some(ref mut frame) = self.frame {
+                if frame.remaining == 0 {
+                    self.newline = frame.fin;
+                    self.frame = None;
+                    if self.newline && !self.incomplete.is_empty() {
+                        return Err(self.fail(CLOSE_INVALID_DATA, "text must be valid UTF-8"));
+                    }
+                    continue;
+                }
+
+                if !self.rd.is_empty() {
+                    let n = buf
+                        .len()
+                        .min(self.rd.len())
+                        .min(frame.remaining.min(usize::MAX as u64) as usize);
+
+                    let payload = self.rd.split_to(n);
+                    for (i, byte) in payload.iter().enumerate() {
+                        buf[i] = byte ^ frame.mask[(frame.offset + i) % 4];
+                    }
+                    frame.offset += n;
+                    frame.remaining -= n as u64;
+
+                    if !check_utf8(&mut self.incomplete, &buf[..n]) {
+                        return Err(self.fail(CLOSE_INVALID_DATA, "text must be valid UTF-8"));
+                    }
+                    return Ok(n);
+                }
+            } else if self.decode_header()? {
+                continue;
+            }
+
+            // More data is needed. This fails with `WouldBlock` if none is
+            // available.
+            self.rd.reserve(4096);
+            match AsyncRead::read_buf(&mut self.socket, &mut self.rd)? {
+                Async::Ready(0) => return Ok(0),
+                Async::Ready(_) => {}
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for WebSocket<S> {}
+
+impl<S: AsyncRead + AsyncWrite> Write for WebSocket<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closing {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        // Frames are only buffered once the previous ones have been written,
+        // so the buffer never holds more than one write's worth of data.
+        self.write_frames()?;
+        if !self.wr.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        self.line.extend_from_slice(buf);
+        while let Some(i) = self.line.iter().position(|b| *b == b'\n') {
+            let mut text = self.line.split_to(i + 1);
+            text.truncate(i);
+            if text.last() == Some(&b'\r') {
+                text.truncate(i - 1);
+            }
+            encode_frame(&mut self.wr, OPCODE_TEXT, &text);
+        }
+
+        self.write_frames()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_frames()?;
+        if !self.wr.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.socket.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for WebSocket<S> {
+    /// Send a close frame, then shut the socket down once it is written.
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.close(CLOSE_NORMAL);
+        try_ready!(self.poll_flush());
+        self.socket.shutdown()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for Accept<S> {
+    type Item = WebSocket<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<WebSocket<S>, io::Error> {
+        let socket = self
+            .socket
+            .as_mut()
+            .expect("polled Accept after completion");
+
+        // Read the request, up to the empty line ending its headers.
+        while self.wr.is_empty() {
+            if let Some(end) = find(&self.rd, b"\r\n\r\n") {
+                let request = self.rd.split_to(end + 4);
+                match respond(&request) {
+                    Ok(response) => self.wr.extend_from_slice(response.as_bytes()),
+                    Err(reason) => {
+                        self.wr.extend_from_slice(
+                            b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\
+                              Content-Length: 0\r\n\r\n",
+                        );
+                        self.rejected = Some(reason);
+                    }
+                }
+                break;
+            }
+
+            if self.rd.len() > MAX_REQUEST_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "WebSocket request too long",
+                ));
+            }
+
+            self.rd.reserve(1024);
+            if try_ready!(AsyncRead::read_buf(socket, &mut self.rd)) == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        while !self.wr.is_empty() {
+            let n = try_ready!(socket.poll_write(&self.wr));
+            let _ = self.wr.split_to(n);
+        }
+
+        if let Some(reason) = self.rejected.take() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+        }
+
+        // Frames the client sent right after the request are kept.
+        Ok(Async::Ready(WebSocket {
+            socket: self.socket.take().unwrap(),
+            rd: self.rd.take(),
+            frame: None,
+            newline: false,
+            fragmented: false,
+            incomplete: Vec::new(),
+            line: BytesMut::new(),
+            wr: BytesMut::new(),
+            closing: false,
+        }))
+    }
+}
+
+/// Check the HTTP request opening a WebSocket connection, returning the
+/// response accepting it.
+fn respond(request: &[u8]) -> Result<String, String> {
+    let request =
+        std::str::from_utf8(request).map_err(|_| "request must be valid UTF-8".to_string())?;
+    let mut lines = request.split("\r\n");
+
+    let request_line = lines.next().unwrap_or("");
+    if !request_line.starts_with("GET ") {
+        return Err(format!("unexpected request `{}`", request_line));
+    }
+
+    let mut upgrade = false;
+    let mut connection = false;
+    let mut version = false;
+    let mut key = None;
+
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+
+        match &name[..] {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "connection" => {
+                connection = value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+            }
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value),
+            _ => {}
+        }
+    }
+
+    if !upgrade || !connection {
+        return Err("not a WebSocket upgrade".to_string());
+    }
+    if !version {
+        return Err("unsupported WebSocket version".to_string());
+    }
+    let key = key.ok_or_else(|| "missing Sec-WebSocket-Key".to_string())?;
+
+    let mut digest = sha1::Sha1::new();
+    digest.update(key.as_bytes());
+    digest.update(ACCEPT_GUID.as_bytes());
+    let accept = base64::encode(&digest.digest().bytes());
+
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+         Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    ))
+}
+
+/// Check that `bytes`, following the `incomplete` end of the text read so
+/// far, are valid UTF-8. The bytes of a character cut short at the end are
+/// left in `incomplete`.
+fn check_utf8(incomplete: &mut Vec<u8>, bytes: &[u8]) -> bool {
+    let joined;
+    let text = if incomplete.is_empty() {
+        bytes
+    } else {
+        joined = [&incomplete[..], bytes].concat();
+        &joined[..]
+    };
+
+    match std::str::from_utf8(text) {
+        Ok(_) => {
+            incomplete.clear();
+            true
+        }
+        // Nothing is wrong yet if the last character is merely unfinished.
+        Err(e) if e.error_len().is_none() => {
+            *incomplete = text[e.valid_up_to()..].to_vec();
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Whether a client may send `code` in a close frame. The others are
+/// reserved, or only reported locally.
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+/// Append an unmasked frame to `dst`, as sent by a server.
+fn encode_frame(dst: &mut BytesMut, opcode: u8, payload: &[u8]) {
+    dst.reserve(payload.len() + 10);
+    dst.put_u8(0x80 | opcode);
+
+    if payload.len() < 126 {
+        dst.put_u8(payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        dst.put_u8(126);
+        dst.put_u16_be(payload.len() as u16);
+    } else {
+        dst.put_u8(127);
+        dst.put_u64_be(payload.len() as u64);
+    }
+
+    dst.extend_from_slice(payload);
+}
+
+/// Position of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream as StdTcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+    use tokio::prelude::{Future, Stream};
+    use tokio::runtime::current_thread;
+
+    /// Serve one WebSocket connection that echoes every text message.
+    fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let server = listener
+                .incoming()
+                .take(1)
+                .for_each(|socket| {
+                    WebSocket::accept(socket).and_then(|websocket| {
+                        let (reader, writer) = websocket.split();
+                        io::copy(reader, writer).then(|_| Ok(()))
+                    })
+                })
+                .map_err(|_| ());
+            current_thread::run(server);
+        });
+        addr
+    }
+
+    /// A client, written against the RFC rather than the server.
+    struct Client(StdTcpStream);
+
+    impl Client {
+        fn connect(addr: SocketAddr) -> Client {
+            let mut socket = StdTcpStream::connect(addr).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            socket
+                .write_all(
+                    b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                      Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                      Sec-WebSocket-Version: 13\r\n\r\n",
+                )
+                .unwrap();
+
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                socket.read_exact(&mut byte).unwrap();
+                response.push(byte[0]);
+            }
+            let response = String::from_utf8(response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+            // The accept key of the RFC's example.
+            assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+            Client(socket)
+        }
+
+        /// Send a frame, masked as clients must.
+        fn send(&mut self, fin: bool, opcode: u8, payload: &[u8]) {
+            let mask = [0x37, 0xfa, 0x21, 0x3d];
+            let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+            if payload.len() < 126 {
+                frame.push(0x80 | payload.len() as u8);
+            } else {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            }
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            self.0.write_all(&frame).unwrap();
+        }
+
+        /// Receive a frame, returning its opcode and payload.
+        fn receive(&mut self) -> (u8, Vec<u8>) {
+            let mut header = [0; 2];
+            self.0.read_exact(&mut header).unwrap();
+            assert_eq!(header[0] & 0x80, 0x80, "fragmented frame");
+            assert_eq!(header[1] & 0x80, 0, "masked frame");
+
+            let len = match header[1] & 0x7f {
+                126 => {
+                    let mut len = [0; 2];
+                    self.0.read_exact(&mut len).unwrap();
+                    usize::from(u16::from_be_bytes(len))
+                }
+                127 => panic!("unexpectedly long frame"),
+                len => usize::from(len),
+            };
+            let mut payload = vec![0; len];
+            self.0.read_exact(&mut payload).unwrap();
+            (header[0] & 0x0f, payload)
+        }
+
+        /// Receive a close frame, returning its status code.
+        fn receive_close(&mut self) -> u16 {
+            let (opcode, payload) = self.receive();
+            assert_eq!(opcode, OPCODE_CLOSE);
+            u16::from(payload[0]) << 8 | u16::from(payload[1])
+        }
+    }
+
+    #[test]
+    fn echoes_text_messages() {
+        let mut client = Client::connect(echo_server());
+
+        client.send(true, OPCODE_TEXT, b"hello");
+        assert_eq!(client.receive(), (OPCODE_TEXT, b"hello".to_vec()));
+
+        // A message in several frames, with a character split between them.
+        client.send(false, OPCODE_TEXT, b"caf\xc3");
+        client.send(true, OPCODE_CONTINUATION, b"\xa9");
+        assert_eq!(client.receive(), (OPCODE_TEXT, "café".as_bytes().to_vec()));
+
+        // A payload length on 16 bits.
+        let long = vec![b'x'; 300];
+        client.send(true, OPCODE_TEXT, &long);
+        assert_eq!(client.receive(), (OPCODE_TEXT, long));
+    }
+
+    #[test]
+    fn answers_pings() {
+        let mut client = Client::connect(echo_server());
+
+        client.send(true, OPCODE_PING, b"are you there");
+        assert_eq!(client.receive(), (OPCODE_PONG, b"are you there".to_vec()));
+
+        // A ping between the frames of a message.
+        client.send(false, OPCODE_TEXT, b"still ");
+        client.send(true, OPCODE_PING, b"");
+        client.send(true, OPCODE_CONTINUATION, b"here");
+        assert_eq!(client.receive(), (OPCODE_PONG, Vec::new()));
+        assert_eq!(client.receive(), (OPCODE_TEXT, b"still here".to_vec()));
+    }
+
+    #[test]
+    fn answers_close_frames() {
+        let mut client = Client::connect(echo_server());
+        client.send(true, OPCODE_CLOSE, &1001u16.to_be_bytes());
+        assert_eq!(client.receive_close(), CLOSE_NORMAL);
+
+        // 1005 means that no code was sent, it can't be sent itself.
+        let mut client = Client::connect(echo_server());
+        client.send(true, OPCODE_CLOSE, &1005u16.to_be_bytes());
+        assert_eq!(client.receive_close(), CLOSE_PROTOCOL_ERROR);
+
+        let mut client = Client::connect(echo_server());
+        client.send(true, OPCODE_CLOSE, b"");
+        assert_eq!(client.receive_close(), CLOSE_NORMAL);
+    }
+
+    #[test]
+    fn rejects_invalid_text() {
+        let mut client = Client::connect(echo_server());
+        client.send(true, OPCODE_TEXT, b"caf\xe9");
+        assert_eq!(client.receive_close(), CLOSE_INVALID_DATA);
+
+        // A message can't end in the middle of a character.
+        let mut client = Client::connect(echo_server());
+        client.send(true, OPCODE_TEXT, b"caf\xc3");
+        assert_eq!(client.receive_close(), CLOSE_INVALID_DATA);
+    }
+
+    #[test]
+    fn rejects_protocol_errors() {
+        let mut client = Client::connect(echo_server());
+        client.send(true, OPCODE_CONTINUATION, b"no message started");
+        assert_eq!(client.receive_close(), CLOSE_PROTOCOL_ERROR);
+
+        let mut client = Client::connect(echo_server());
+        client.send(true, OPCODE_BINARY, b"\x00\x01");
+        assert_eq!(client.receive_close(), CLOSE_UNSUPPORTED);
+
+        // Clients must mask their frames.
+        let mut client = Client::connect(echo_server());
+        client.0.write_all(b"\x81\x02hi").unwrap();
+        assert_eq!(client.receive_close(), CLOSE_PROTOCOL_ERROR);
+    }
+}
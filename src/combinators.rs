@@ -0,0 +1,72 @@
+//! Small futures that print what other futures and streams produce.
+
+use futures::{Async, Future, Poll, Stream};
+use std::fmt;
+
+/// Future that prints the value of the future it wraps.
+///
+/// This is the explicit version of `future.map(|value| println!("{}", value))`.
+pub struct Display<T>(pub T);
+
+impl<T> Future for Display<T>
+where
+    T: Future,
+    T::Item: fmt::Display,
+{
+    type Item = ();
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // let value = match self.0.poll() {
+        //     Ok(Async::Ready(value)) => value,
+        //     Ok(Async::NotReady) => return Ok(Async::NotReady),
+        //     Err(err) => return Err(err),
+        // };
+        //
+        // Same as
+        //
+        let value = try_ready!(self.0.poll());
+
+        println!("{}", value);
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Future that prints the first 10 values of the stream it wraps.
+///
+/// Completes early if the stream ends before producing 10 values.
+pub struct Display10<T> {
+    stream: T,
+    curr: usize,
+}
+
+impl<T> Display10<T> {
+    pub fn new(stream: T) -> Display10<T> {
+        Display10 { stream, curr: 0 }
+    }
+}
+
+impl<T> Future for Display10<T>
+where
+    T: Stream,
+    T::Item: fmt::Display,
+{
+    type Item = ();
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while self.curr < 10 {
+            let value = match try_ready!(self.stream.poll()) {
+                Some(value) => value,
+                // There were less than 10 values to display, terminate the
+                // future.
+                None => break,
+            };
+
+            println!("value #{} = {}", self.curr, value);
+            self.curr += 1;
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
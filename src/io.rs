@@ -0,0 +1,215 @@
+//! I/O building blocks: reading exact amounts, counting bytes and a
+//! simulated ping / pong transport.
+
+use futures::sync::mpsc;
+use futures::{future, stream, Future, Stream};
+use rand::Rng;
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::prelude::{Async, AsyncRead, Poll};
+use tokio::timer::{Delay, Interval};
+
+use crate::info;
+
+/// Future that fills a buffer from an `AsyncRead`, created by `read_exact`.
+///
+/// Resolves to the reader and the buffer once the buffer is full, and fails
+/// with `UnexpectedEof` if the reader ends first.
+//
+// In the common case, this is set to Some(Reading),
+// but we'll set it to None when we return Async::Ready
+// so that we can return the reader and the buffer.
+pub struct ReadExact<R, T>(Option<Reading<R, T>>);
+
+struct Reading<R, T> {
+    // This is the stream we're reading from.
+    reader: R,
+    // This is the buffer we're reading into.
+    buffer: T,
+    // And this is how far into the buffer we've written.
+    pos: usize,
+}
+
+/// Read exactly enough bytes from `reader` to fill `buffer`.
+//
+// We want to be able to construct a ReadExact over anything
+// that implements AsyncRead, and any buffer that can be
+// thought of as a &mut [u8].
+pub fn read_exact<R, T>(reader: R, buffer: T) -> ReadExact<R, T>
+where
+    R: AsyncRead,
+    T: AsMut<[u8]>,
+{
+    ReadExact(Some(Reading {
+        reader,
+        buffer,
+        // Initially, we've read no bytes into buffer.
+        pos: 0,
+    }))
+}
+
+impl<R, T> Future for ReadExact<R, T>
+where
+    R: AsyncRead,
+    T: AsMut<[u8]>,
+{
+    // When we've filled up the buffer, we want to return both the buffer
+    // with the data that we read and the reader itself.
+    type Item = (R, T);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.0 {
+            Some(Reading {
+                ref mut reader,
+                ref mut buffer,
+                ref mut pos,
+            }) => {
+                let buffer = buffer.as_mut();
+                // Check that we haven't finished
+                while *pos < buffer.len() {
+                    // Try to read data into the remainder of the buffer.
+                    // Just like read in std::io::Read, poll_read *can* read
+                    // fewer bytes than the length of the buffer it is given,
+                    // and we need to handle that by looking at its return
+                    // value, which is the number of bytes actually read.
+                    //
+                    // Notice that we are using try_ready! here, so if poll_read
+                    // returns NotReady (or an error), we will do the same!
+                    // We uphold the contract that we have arranged to be
+                    // notified later because poll_read follows that same
+                    // contract, and _it_ returned NotReady.
+                    let n = try_ready!(reader.poll_read(&mut buffer[*pos..]));
+                    *pos += n;
+
+                    // If no bytes were read, but there was no error, this
+                    // generally implies that the reader will provide no more
+                    // data (for example, because the TCP connection was closed
+                    // by the other side).
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "early eof"));
+                    }
+                }
+            }
+            None => panic!("poll on ReadExact after it's done"),
+        }
+
+        // We need to return the reader and the buffer, which we can only
+        // do by moving them out of self. We do this by taking our state
+        // and leaving `None`. This _should_ be fine, because poll()
+        // requires callers to not call poll() again after Ready has been
+        // returned, so we should only ever see Some(Reading) when poll()
+        // is called.
+        let reading = self.0.take().expect("must have seen Some above");
+        Ok(Async::Ready((reading.reader, reading.buffer)))
+    }
+}
+
+/// Sum the byte counts received on `rx` and print the total every `tick`.
+///
+/// The sum is reset to zero after each print. The future completes once every
+/// sender of `rx` has been dropped.
+pub fn report_bytes_read(
+    rx: mpsc::Receiver<usize>,
+    tick: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    // The stream of received `usize` values will be merged with an interval
+    // stream. The value types of each stream must match. This enum is used to
+    // track the various values.
+    #[derive(Eq, PartialEq, Debug)]
+    enum Item {
+        Value(usize),
+        Tick,
+        Done,
+    }
+
+    // Interval at which the current sum is written to STDOUT.
+    let interval = Interval::new_interval(tick)
+        .map(|_| Item::Tick)
+        .map_err(|_| ());
+
+    // Turn the stream into a sequence of:
+    // Item(num), Item(num), ... Done
+    let items = rx
+        .map(Item::Value)
+        // Merge in the stream of intervals
+        .select(interval)
+        // Terminate the stream once `Done` is received. This is necessary
+        // because `Interval` is an infinite stream and `select` will keep
+        // selecting on it.
+        .chain(stream::once(Ok(Item::Done)))
+        .take_while(|item| future::ok(*item != Item::Done));
+
+    // With the stream of `Item` values, start our logic.
+    //
+    // Using `fold` allows the state to be maintained across iterations.
+    // In this case, the state is the number of read bytes between tick.
+    items
+        .fold(0, |sum, item| {
+            match item {
+                // Sum the number of bytes with the state.
+                Item::Value(v) => future::ok(sum + v),
+                Item::Tick => {
+                    info!("bytes read", bytes = sum);
+                    future::ok(0)
+                }
+                _ => unreachable!(),
+            }
+        })
+        .map(|_| ())
+}
+
+/// Shortest delay of a pong from `Transport::default()`.
+const TRANSPORT_MIN_DELAY: Duration = Duration::from_secs(0);
+
+/// Longest delay of a pong from `Transport::default()`.
+const TRANSPORT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// A simulated transport for a ping / pong protocol.
+///
+/// Every pong arrives after a random delay between `min_delay` and
+/// `max_delay`.
+pub struct Transport {
+    min_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Transport {
+    pub fn new(min_delay: Duration, max_delay: Duration) -> Transport {
+        Transport {
+            min_delay,
+            max_delay,
+        }
+    }
+
+    /// Send a ping on behalf of the task `id`.
+    pub fn send_ping(&self, id: usize) {
+        info!("ping sent", task = id);
+    }
+
+    /// Wait for the pong answering the ping of the task `id`.
+    pub fn recv_pong(&self, id: usize) -> impl Future<Item = (), Error = io::Error> {
+        let min = self.min_delay.as_millis() as u64;
+        let max = self.max_delay.as_millis() as u64;
+        let wait_millis = if min < max {
+            rand::thread_rng().gen_range(min, max)
+        } else {
+            min
+        };
+        let delay = Duration::from_millis(wait_millis);
+
+        info!(
+            "waiting for pong",
+            task = id,
+            delay = format!("{:?}", delay)
+        );
+
+        Delay::new(Instant::now() + delay).map_err(io::Error::other)
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Transport {
+        Transport::new(TRANSPORT_MIN_DELAY, TRANSPORT_MAX_DELAY)
+    }
+}
@@ -0,0 +1,27 @@
+//! Building blocks shared by the examples in `src/bin`.
+//!
+//! - `codec`: line framing, by hand with `Lines` or through
+//!   `tokio::codec::Framed` with `StructLinesCodec`.
+//! - `combinators`: futures that print what other futures and streams
+//!   produce.
+//! - `io`: reading exact amounts, counting bytes and a simulated ping / pong
+//!   transport.
+//! - `log`: leveled, structured logging to the standard error, as text or
+//!   JSON lines.
+//! - `metrics`: counters, gauges and histograms served to Prometheus over
+//!   HTTP.
+//! - `tls`: TLS acceptors and connectors loaded from PEM files.
+//! - `chat`: the line based chat server run by the `line_chat` binary.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate futures;
+
+pub mod chat;
+pub mod codec;
+pub mod combinators;
+pub mod io;
+pub mod log;
+pub mod metrics;
+pub mod tls;
@@ -0,0 +1,354 @@
+//! Leveled, structured logging.
+//!
+//! An event has a level, a message and fields, written to the standard error
+//! as one line:
+//!
+//! ```text
+//! 2019-06-01T12:00:00.000Z  INFO joined the chat conn=3 addr=127.0.0.1:50632 name=alice
+//! ```
+//!
+//! or, with `LOG_FORMAT=json`, as one JSON object per line:
+//!
+//! ```text
+//! {"time":"2019-06-01T12:00:00.000Z","level":"info","message":"joined the chat","conn":"3",...}
+//! ```
+//!
+//! `LOG_LEVEL` selects the most verbose level written: `error`, `warn`,
+//! `info` (the default), `debug`, `trace` or `off`. A program can override
+//! both variables with a `LogConfig`, passed to `init` before anything is
+//! logged.
+//!
+//! Events are logged with the `error!`, `warn!`, `info!`, `debug!` and
+//! `trace!` macros. The message is followed by the fields, and optionally
+//! preceded by a `Context` holding fields shared by a series of events, such
+//! as those of a connection:
+//!
+//! ```ignore
+//! info!("server running", addr = addr);
+//! warn!(context: peer.log, "timed out", idle = secs);
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use std::env;
+use std::fmt::{self, Display, Write as _};
+use std::io::{self, Write as _};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How important an event is, from the most to the least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// How events are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Readable text, with the fields as `key=value`.
+    Text,
+
+    /// One JSON object per line.
+    Json,
+}
+
+/// Logging settings. Those left unset are read from the environment.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Most verbose level written, as accepted by `parse_level`. Defaults to
+    /// `LOG_LEVEL`, then `info`.
+    pub level: Option<String>,
+
+    /// Defaults to `LOG_FORMAT`, then `text`.
+    pub format: Option<Format>,
+}
+
+/// Settings fixed the first time an event is logged, or by `init`.
+struct Settings {
+    /// Most verbose level written, `None` if logging is off.
+    level: Option<Level>,
+
+    format: Format,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(|| Settings::new(&LogConfig::default()))
+}
+
+/// Log with the settings of `config`.
+///
+/// Only the first call has an effect, and only if nothing was logged
+/// before it.
+pub fn init(config: &LogConfig) {
+    let _ = SETTINGS.set(Settings::new(config));
+}
+
+/// Parse a level as written in the settings: `error`, `warn`, `info`,
+/// `debug`, `trace` or `off`, in any case. `off` gives `None`.
+pub fn parse_level(s: &str) -> Result<Option<Level>, String> {
+    match &s.to_lowercase()[..] {
+        "off" => Ok(None),
+        level => level.parse().map(Some),
+    }
+}
+
+impl Settings {
+    /// The settings of `config`, falling back to the environment. Invalid
+    /// levels fall back to `info`.
+    fn new(config: &LogConfig) -> Settings {
+        let level = match config.level {
+            Some(ref level) => Ok(level.clone()),
+            None => env::var("LOG_LEVEL"),
+        };
+        let level = match level {
+            Ok(ref level) => parse_level(level).unwrap_or(Some(Level::Info)),
+            Err(_) => Some(Level::Info),
+        };
+
+        let format = config
+            .format
+            .unwrap_or_else(|| match env::var("LOG_FORMAT") {
+                Ok(ref format) => format.parse().unwrap_or(Format::Text),
+                Err(_) => Format::Text,
+            });
+
+        Settings { level, format }
+    }
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+impl std::str::FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Level, String> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level `{}`", s)),
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format `{}`", s)),
+        }
+    }
+}
+
+/// Whether events of `level` are written.
+pub fn enabled(level: Level) -> bool {
+    settings().level.is_some_and(|max| level <= max)
+}
+
+/// Fields added to every event logged with it.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// Add a field.
+    pub fn with<T: Display>(mut self, key: &'static str, value: T) -> Context {
+        self.set(key, value);
+        self
+    }
+
+    /// Set a field, replacing its value if it is already there.
+    pub fn set<T: Display>(&mut self, key: &'static str, value: T) {
+        let value = value.to_string();
+        match self.fields.iter_mut().find(|(k, _)| *k == key) {
+            Some(field) => field.1 = value,
+            None => self.fields.push((key, value)),
+        }
+    }
+}
+
+/// Write an event. Use the macros instead, which skip formatting the fields
+/// of events that aren't written.
+#[doc(hidden)]
+pub fn write(
+    level: Level,
+    context: Option<&Context>,
+    message: &str,
+    fields: &[(&str, &dyn Display)],
+) {
+    let context = context.map_or(&[][..], |context| &context.fields[..]);
+    let fields = context
+        .iter()
+        .map(|(key, value)| (*key, value as &dyn Display))
+        .chain(fields.iter().copied());
+
+    let mut line = String::new();
+    match settings().format {
+        Format::Text => {
+            let _ = write!(
+                line,
+                "{} {:>5} {}",
+                Timestamp(SystemTime::now()),
+                level.as_str().to_uppercase(),
+                message
+            );
+            for (key, value) in fields {
+                let value = value.to_string();
+                if value.is_empty()
+                    || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=')
+                {
+                    let _ = write!(line, " {}={:?}", key, value);
+                } else {
+                    let _ = write!(line, " {}={}", key, value);
+                }
+            }
+        }
+        Format::Json => {
+            // The object is written by hand to keep the time, level and
+            // message first.
+            let _ = write!(
+                line,
+                "{{\"time\":\"{}\",\"level\":\"{}\",\"message\":{}",
+                Timestamp(SystemTime::now()),
+                level.as_str(),
+                json_string(message)
+            );
+            for (key, value) in fields {
+                let _ = write!(
+                    line,
+                    ",{}:{}",
+                    json_string(key),
+                    json_string(&value.to_string())
+                );
+            }
+            line.push('}');
+        }
+    }
+    line.push('\n');
+
+    // A single write keeps the lines of concurrent events apart.
+    let _ = io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    serde_json::to_string(s).expect("strings serialize")
+}
+
+/// A point in time, shown in UTC in the RFC 3339 format.
+struct Timestamp(SystemTime);
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+        // Convert the number of days to a date, from Howard Hinnant's
+        // `civil_from_days`.
+        let z = days as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            since_epoch.subsec_millis()
+        )
+    }
+}
+
+/// Log an event at a given level. The level macros are shorter.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, context: $context:expr, $message:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write(
+                $level,
+                Some(&$context),
+                &$message,
+                &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),*],
+            );
+        }
+    };
+    ($level:expr, $message:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write(
+                $level,
+                None,
+                &$message,
+                &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),*],
+            );
+        }
+    };
+}
+
+/// Log an error: something failed and was given up on.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+/// Log a warning: something unexpected that the program copes with.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+/// Log a notable event in the life of the program.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+/// Log an event useful to find out what the program is doing.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+/// Log an event too frequent to be useful but when tracking a problem down.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
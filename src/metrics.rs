@@ -0,0 +1,375 @@
+//! Counters, gauges and histograms, exposed over HTTP in the Prometheus text
+//! format.
+//!
+//! Metrics are created through a `Registry`, which hands out cheap, cloneable
+//! handles updated with atomic operations. `serve` answers `GET /metrics`
+//! with every metric of the registry, for Prometheus to scrape.
+
+use bytes::BytesMut;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{debug, error};
+
+/// Maximum size of a scrape request.
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+/// The metrics of a program.
+#[derive(Default)]
+pub struct Registry {
+    families: Mutex<Vec<Family>>,
+}
+
+/// The metrics sharing a name, told apart by their labels.
+struct Family {
+    name: String,
+    help: String,
+    kind: &'static str,
+    series: Vec<(String, Metric)>,
+}
+
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+/// A value that only goes up.
+#[derive(Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+/// A value that goes up and down.
+#[derive(Clone, Default)]
+pub struct Gauge(Arc<AtomicI64>);
+
+/// Observations counted in buckets.
+#[derive(Clone)]
+pub struct Histogram(Arc<Buckets>);
+
+struct Buckets {
+    /// Upper bounds of the buckets, in increasing order. The `+Inf` bucket is
+    /// implied.
+    bounds: Vec<f64>,
+
+    /// Number of observations that fell in each bucket, not cumulative.
+    counts: Vec<AtomicU64>,
+
+    count: AtomicU64,
+
+    /// Sum of the observations, as the bits of an `f64`.
+    sum: AtomicU64,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Create a counter. `labels` tell it apart from the other counters
+    /// named `name`.
+    pub fn counter(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Counter {
+        let counter = Counter::default();
+        self.register(name, help, labels, Metric::Counter(counter.clone()));
+        counter
+    }
+
+    /// Create a gauge, starting at 0.
+    pub fn gauge(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Gauge {
+        let gauge = Gauge::default();
+        self.register(name, help, labels, Metric::Gauge(gauge.clone()));
+        gauge
+    }
+
+    /// Create a histogram with buckets up to each of `bounds`, which must be
+    /// in increasing order.
+    pub fn histogram(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        bounds: &[f64],
+    ) -> Histogram {
+        let histogram = Histogram(Arc::new(Buckets {
+            bounds: bounds.to_vec(),
+            counts: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }));
+        self.register(name, help, labels, Metric::Histogram(histogram.clone()));
+        histogram
+    }
+
+    /// Add a metric to its family, creating the family if needed.
+    ///
+    /// Panics if the family holds metrics of another kind.
+    fn register(&self, name: &str, help: &str, labels: &[(&str, &str)], metric: Metric) {
+        let kind = match metric {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        };
+
+        let mut labels_text = String::new();
+        for (i, (label, value)) in labels.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            let _ = write!(labels_text, "{}{}=\"{}\"", separator, label, escape(value));
+        }
+
+        let mut families = self.families.lock().unwrap();
+        let family = match families.iter().position(|family| family.name == name) {
+            Some(i) => &mut families[i],
+            None => {
+                families.push(Family {
+                    name: name.to_string(),
+                    help: help.to_string(),
+                    kind,
+                    series: Vec::new(),
+                });
+                families.last_mut().unwrap()
+            }
+        };
+
+        assert_eq!(family.kind, kind, "metric {} registered twice", name);
+        family.series.push((labels_text, metric));
+    }
+
+    /// Every metric, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        for family in self.families.lock().unwrap().iter() {
+            let _ = writeln!(text, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(text, "# TYPE {} {}", family.name, family.kind);
+
+            for (labels, metric) in &family.series {
+                match metric {
+                    Metric::Counter(counter) => {
+                        sample(&mut text, &family.name, labels, "", counter.get())
+                    }
+                    Metric::Gauge(gauge) => {
+                        sample(&mut text, &family.name, labels, "", gauge.get())
+                    }
+                    Metric::Histogram(histogram) => {
+                        histogram.render(&mut text, &family.name, labels)
+                    }
+                }
+            }
+        }
+
+        text
+    }
+}
+
+/// Write one sample. `extra` is an additional label, such as a bucket's
+/// `le`.
+fn sample<T: std::fmt::Display>(
+    text: &mut String,
+    name: &str,
+    labels: &str,
+    extra: &str,
+    value: T,
+) {
+    let separator = if labels.is_empty() || extra.is_empty() {
+        ""
+    } else {
+        ","
+    };
+    if labels.is_empty() && extra.is_empty() {
+        let _ = writeln!(text, "{} {}", name, value);
+    } else {
+        let _ = writeln!(
+            text,
+            "{}{{{}{}{}}} {}",
+            name, labels, separator, extra, value
+        );
+    }
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, n: i64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Histogram {
+    /// Count an observation in its bucket.
+    pub fn observe(&self, value: f64) {
+        let buckets = &self.0;
+
+        if let Some(i) = buckets.bounds.iter().position(|bound| value <= *bound) {
+            buckets.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        buckets.count.fetch_add(1, Ordering::Relaxed);
+
+        let _ = buckets
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + value).to_bits())
+            });
+    }
+
+    /// Write the buckets, sum and count, as Prometheus expects them:
+    /// cumulative, ending with the `+Inf` bucket.
+    fn render(&self, text: &mut String, name: &str, labels: &str) {
+        let buckets = &self.0;
+        let bucket = format!("{}_bucket", name);
+
+        let mut cumulative = 0;
+        for (bound, count) in buckets.bounds.iter().zip(&buckets.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = format!("le=\"{}\"", bound);
+            sample(text, &bucket, labels, &le, cumulative);
+        }
+
+        let count = buckets.count.load(Ordering::Relaxed);
+        sample(text, &bucket, labels, "le=\"+Inf\"", count);
+
+        let sum = f64::from_bits(buckets.sum.load(Ordering::Relaxed));
+        sample(text, &format!("{}_sum", name), labels, "", sum);
+        sample(text, &format!("{}_count", name), labels, "", count);
+    }
+}
+
+/// Serve the metrics of `registry` over HTTP on `addr`.
+///
+/// `GET /metrics` is answered with the metrics, other requests with an
+/// error. The listener is bound right away; the returned future accepts
+/// scrapes until it fails.
+pub fn serve(
+    addr: &SocketAddr,
+    registry: Arc<Registry>,
+) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let listener = TcpListener::bind(addr)?;
+
+    Ok(listener
+        .incoming()
+        .for_each(move |socket| {
+            let scrape = Scrape::new(socket, registry.clone())
+                .map_err(|e| debug!("metrics connection error", error = e));
+            tokio::spawn(scrape);
+            Ok(())
+        })
+        .map_err(|e| error!("metrics accept error", error = e)))
+}
+
+/// Future that answers a single HTTP request for the metrics, then closes
+/// the connection.
+pub struct Scrape<S = TcpStream> {
+    socket: S,
+    registry: Arc<Registry>,
+
+    /// The request, as read so far.
+    rd: BytesMut,
+
+    /// The response, once the request is complete.
+    wr: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite> Scrape<S> {
+    pub fn new(socket: S, registry: Arc<Registry>) -> Scrape<S> {
+        Scrape {
+            socket,
+            registry,
+            rd: BytesMut::new(),
+            wr: BytesMut::new(),
+        }
+    }
+
+    /// The response to a request.
+    fn respond(&self, request: &[u8]) -> String {
+        let request = String::from_utf8_lossy(request);
+        let mut words = request.split_whitespace();
+
+        let (status, body) = match (words.next(), words.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.registry.render()),
+            (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+        };
+
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for Scrape<S> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        // Read the request, up to the empty line ending its headers.
+        while self.wr.is_empty() {
+            if let Some(end) = self.rd.windows(4).position(|w| w == b"\r\n\r\n") {
+                let response = self.respond(&self.rd[..end]);
+                self.wr.extend_from_slice(response.as_bytes());
+                break;
+            }
+
+            if self.rd.len() > MAX_REQUEST_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "metrics request too long",
+                ));
+            }
+
+            self.rd.reserve(1024);
+            if try_ready!(AsyncRead::read_buf(&mut self.socket, &mut self.rd)) == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        while !self.wr.is_empty() {
+            let n = try_ready!(self.socket.poll_write(&self.wr));
+            let _ = self.wr.split_to(n);
+        }
+        self.socket.shutdown()
+    }
+}
@@ -0,0 +1,164 @@
+//! TLS for the servers and clients, built from PEM files.
+
+use native_tls::{Certificate, Identity};
+use tokio::io;
+use tokio_tls::{TlsAcceptor, TlsConnector};
+
+use std::fs;
+use std::path::Path;
+
+/// Build an acceptor for incoming connections.
+///
+/// `cert` holds the certificate chain, starting with the server's own
+/// certificate, and `key` its private key in PKCS #8. Both are PEM encoded.
+pub fn acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?).map_err(|e| {
+        io::Error::other(format!(
+            "invalid certificate {} or key {}: {}",
+            cert.display(),
+            key.display(),
+            e
+        ))
+    })?;
+
+    let acceptor = native_tls::TlsAcceptor::new(identity).map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+/// Build an acceptor from the command-line arguments of a server, without
+/// the program name.
+///
+/// The arguments must be empty, for a plaintext server, or
+/// `--tls <cert> <key>`.
+pub fn acceptor_from_args<I>(args: I) -> Result<Option<TlsAcceptor>, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let args: Vec<String> = args.into_iter().collect();
+
+    match &args[..] {
+        [] => Ok(None),
+        [flag, cert, key] if flag == "--tls" => acceptor(Path::new(cert), Path::new(key))
+            .map(Some)
+            .map_err(|e| e.to_string()),
+        _ => Err("expected no arguments or `--tls <cert> <key>`".to_string()),
+    }
+}
+
+/// Build a connector for outgoing connections.
+///
+/// Servers are checked against the system's trusted certificates, as well as
+/// the PEM encoded certificate in `ca` if given, which lets clients trust a
+/// self-signed server.
+pub fn connector(ca: Option<&Path>) -> io::Result<TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+
+    if let Some(ca) = ca {
+        let cert = Certificate::from_pem(&read(ca)?).map_err(|e| {
+            io::Error::other(format!("invalid certificate {}: {}", ca.display(), e))
+        })?;
+        builder.add_root_certificate(cert);
+    }
+
+    let connector = builder.build().map_err(io::Error::other)?;
+    Ok(TlsConnector::from(connector))
+}
+
+/// Read a file, naming it in the error.
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to read {}: {}", path.display(), e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::prelude::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    use std::path::PathBuf;
+
+    /// Write a self-signed certificate for `localhost` and its key to a new
+    /// directory, returning their paths.
+    fn certificate(test: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("tls-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        fs::write(&cert, certified.cert.pem()).unwrap();
+        fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        (cert, key)
+    }
+
+    /// Accept one connection with `acceptor` and echo what the client sends,
+    /// while a client connects with `connector`, sends `message` and reads
+    /// the echo until the server closes the connection.
+    fn echo(acceptor: TlsAcceptor, connector: TlsConnector, message: &[u8]) -> io::Result<Vec<u8>> {
+        let mut runtime = Runtime::new()?;
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())?;
+        let addr = listener.local_addr()?;
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(socket, _)| acceptor.accept(socket.unwrap()).map_err(io::Error::other))
+            .and_then(|socket| {
+                let (reader, writer) = socket.split();
+                io::copy(reader, writer)
+            })
+            .and_then(|(_, _, writer)| io::shutdown(writer));
+        runtime.spawn(server.then(|_| Ok(())));
+
+        let message = message.to_vec();
+        let client = TcpStream::connect(&addr)
+            .and_then(move |socket| {
+                connector
+                    .connect("localhost", socket)
+                    .map_err(io::Error::other)
+            })
+            .and_then(move |socket| io::write_all(socket, message))
+            .and_then(|(socket, _)| io::shutdown(socket))
+            .and_then(|socket| io::read_to_end(socket, Vec::new()))
+            .map(|(_, echoed)| echoed);
+        runtime.block_on(client)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (cert, key) = certificate("round-trip");
+        let acceptor = acceptor(&cert, &key).unwrap();
+        let connector = connector(Some(&cert)).unwrap();
+
+        let echoed = echo(acceptor, connector, b"hello over TLS\n").unwrap();
+        assert_eq!(echoed, b"hello over TLS\n");
+    }
+
+    #[test]
+    fn untrusted_certificate() {
+        let (cert, key) = certificate("untrusted");
+        let acceptor = acceptor(&cert, &key).unwrap();
+        let connector = connector(None).unwrap();
+
+        assert!(echo(acceptor, connector, b"hello\n").is_err());
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = std::env::temp_dir().join("tls-missing");
+        let err = match acceptor(&dir.join("cert.pem"), &dir.join("key.pem")) {
+            Ok(_) => panic!("accepted missing files"),
+            Err(e) => e,
+        };
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("cert.pem"));
+    }
+}
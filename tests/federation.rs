@@ -0,0 +1,163 @@
+//! Two `line_chat` servers linked over TLS share their rooms.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a server, or a line, before failing.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running server, killed when dropped.
+struct Server(Child);
+
+impl Server {
+    fn start(args: &[&str]) -> Server {
+        let child = Command::new(env!("CARGO_BIN_EXE_line_chat"))
+            .args(args)
+            .env("LOG_LEVEL", "off")
+            .stdin(Stdio::null())
+            .spawn()
+            .expect("failed to start line_chat");
+        Server(child)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A telnet client.
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    /// Connect to `port`, retrying until the server is up, and log in as
+    /// `name`.
+    fn connect(port: u16, name: &str) -> Client {
+        let deadline = Instant::now() + TIMEOUT;
+        let socket = loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(socket) => break socket,
+                Err(e) if Instant::now() > deadline => panic!("failed to connect: {}", e),
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let mut client = Client {
+            reader: BufReader::new(socket.try_clone().unwrap()),
+            writer: socket,
+        };
+        client.send(name);
+        client.expect(&format!("welcome, {}", name));
+        client
+    }
+
+    fn send(&mut self, line: &str) {
+        self.writer
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .unwrap();
+    }
+
+    /// Read lines until one contains `text`, and return it.
+    fn expect(&mut self, text: &str) -> String {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut line = String::new();
+        while Instant::now() < deadline {
+            match self.reader.read_line(&mut line) {
+                Ok(0) => panic!("connection closed waiting for {:?}", text),
+                Ok(_) if line.contains(text) => return line,
+                Ok(_) => line.clear(),
+                // Timed out: a partial line stays in `line`.
+                Err(_) => {}
+            }
+        }
+        panic!("timed out waiting for {:?}", text);
+    }
+}
+
+/// A port nothing listens on yet.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Write a self-signed certificate for `localhost` and its key to a new
+/// directory, returning their paths.
+fn certificate(test: &str) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("line_chat-{}-{}", test, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    (cert, key)
+}
+
+#[test]
+fn relays_messages_and_rosters_over_tls() {
+    let (cert, key) = certificate("federation");
+    let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+    let (paris, berlin, link) = (free_port(), free_port(), free_port());
+
+    let _paris = Server::start(&[
+        "--listen",
+        &format!("127.0.0.1:{}", paris),
+        "--server-name",
+        "paris",
+        "--link-listen",
+        &format!("127.0.0.1:{}", link),
+        "--link-cert",
+        cert,
+        "--link-key",
+        key,
+    ]);
+    let mut alice = Client::connect(paris, "alice");
+
+    // Berlin links to Paris once Alice is there, and learns of her from the
+    // roster Paris sends when the link comes up.
+    let _berlin = Server::start(&[
+        "--listen",
+        &format!("127.0.0.1:{}", berlin),
+        "--server-name",
+        "berlin",
+        "--link",
+        &format!("localhost:{}", link),
+        "--link-ca",
+        cert,
+    ]);
+    let mut bob = Client::connect(berlin, "bob");
+
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        bob.send("/who");
+        let who = bob.expect("* lobby:");
+        if who.contains("alice@paris") {
+            break;
+        }
+        assert!(Instant::now() < deadline, "alice never showed up: {}", who);
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    alice.send("hello from paris");
+    bob.expect("[lobby] alice@paris: hello from paris");
+
+    bob.send("hello from berlin");
+    alice.expect("[lobby] bob@berlin: hello from berlin");
+}